@@ -0,0 +1,65 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, LineWriter, Write},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Appends a line for each served request to a file, for `--log-file`. Lines are flushed as they're written, so the
+/// file can be followed while the server runs.
+pub struct AccessLog {
+    writer: Mutex<LineWriter<File>>,
+}
+
+impl AccessLog {
+    /// Opens the file at the given path for appending, creating it if it doesn't exist.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog {
+            writer: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    /// Writes a line for a request from `remote_address` to the socket at `local_address`. For Unix domain sockets these
+    /// are the socket's path and "unix".
+    pub fn record(
+        &self,
+        protocol: &str,
+        local_address: impl fmt::Display,
+        remote_address: impl fmt::Display,
+        counter: u64,
+    ) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        writeln!(
+            self.writer.lock().unwrap(),
+            "{timestamp:.3} {protocol} {local_address} {remote_address} {counter}"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_a_line_per_request() {
+        let path = std::env::temp_dir().join(format!("whereyoufrom-accesslog-test-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for counter in 1..=2 {
+            let access_log = AccessLog::open(path.to_str().unwrap()).unwrap();
+            let remote_address: std::net::SocketAddr = "192.0.2.1:5678".parse().unwrap();
+            access_log.record("udp", "0.0.0.0:6969", remote_address, counter).unwrap();
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, counter) in lines.iter().zip(1..) {
+            let (timestamp, rest) = line.split_once(' ').unwrap();
+            assert!(timestamp.parse::<f64>().is_ok());
+            assert_eq!(rest, format!("udp 0.0.0.0:6969 192.0.2.1:5678 {counter}"));
+        }
+    }
+}
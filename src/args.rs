@@ -1,10 +1,26 @@
 use std::{
-    env, fmt,
+    collections::HashMap,
+    env, fmt, fs,
     io::ErrorKind,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    cidr::IpNetwork,
+    http,
+    template::{Placeholder, ResponseTemplate},
+    utils::{self, JSON_RESPONSE_FIELDS},
 };
 
 pub const DEFAULT_PORT: u16 = 6969;
+pub const DEFAULT_MAX_ADDRESSES: usize = 1024;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_FIRST_SEEN_TTL: Duration = Duration::from_secs(3600);
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 65536;
 
 pub fn get_version_string() -> String {
     format!(
@@ -20,14 +36,178 @@ pub fn get_help_string() -> &'static str {
         "Options:\n",
         "  -h, --help                      Display this help menu and exit\n",
         "  -V, --version                   Display the version number and exit\n",
+        "      --capabilities              Print the capabilities supported by this build and exit\n",
+        "      --resolve-only              Print the socket addresses a given address resolves to and exit\n",
         "  -v, --verbose                   Display additional information while running\n",
         "  -s, --silent                    Do not print to stdout\n",
+        "      --workers                   Run the servers on this many threads instead of a single one\n",
+        "      --once                      Serve a single TCP connection or UDP packet, then exit\n",
         "  -t, --listen-tcp                Specify a TCP socket address to listen for incoming clients\n",
         "  -u, --listen-udp                Specify a UDP socket address to listen for incoming clients\n",
+        "      --tcp-file                  Read TCP socket addresses to listen on from a file, one per line\n",
+        "      --udp-file                  Read UDP socket addresses to listen on from a file, one per line\n",
+        "      --fd-tcp                    Serve an already bound TCP listener inherited as this file descriptor (Unix only)\n",
+        "      --fd-udp                    Serve an already bound UDP socket inherited as this file descriptor (Unix only)\n",
+        "      --default-port              The port to use for addresses that don't specify one (default 6969)\n",
+        "      --default-scope             The interface for link-local IPv6 addresses that don't specify a scope\n",
+        "      --max-addresses             Refuse to start if more than this many sockets would be bound (default 1024)\n",
+        "      --warn-on-private           Log a warning when serving a client with a private or link-local address\n",
+        "      --allow                     Only serve clients with an IP in this CIDR range, may be given multiple times\n",
+        "      --deny                      Don't serve clients with an IP in this CIDR range, may be given multiple times\n",
+        "      --udp-response-port-offset  Send UDP responses to the client's port plus this offset (default 0)\n",
+        "      --format                    Respond with either text, json, ip-bytes, ip-port-bytes or a template (default text)\n",
+        "      --json                      Respond with a JSON object, the same as --format json\n",
+        "      --format-tcp                Override the response format for TCP\n",
+        "      --format-udp                Override the response format for UDP\n",
+        "      --json-extra                Add the fields of this JSON object to json format responses\n",
+        "      --json-numeric-port         Whether json format responses have the port as a number or a string (default true)\n",
+        "      --uptime-format             Show {uptime} as either seconds or a human-readable duration (default seconds)\n",
+        "      --track-per-ip              Count the requests from each client IP, shown in text responses and as {ip_count}\n",
+        "      --first-seen-message        Add this note to text responses for the first request seen from a client IP\n",
+        "      --first-seen-ttl            Greet a client IP as first seen again after this many seconds (default 3600)\n",
+        "      --hash-ip                   Show clients a salted hash of their IP address instead of the address itself\n",
+        "      --ip-salt                   The salt for --hash-ip, to get the same hashes across runs (default random)\n",
+        "      --checksum                  Append a checksum of the response to it, either crc32 or sha256\n",
+        "      --empty-udp                 Whether to respond to or drop empty UDP packets (default respond)\n",
+        "      --echo                      Append the payload of each UDP packet to its response\n",
+        "      --echo-only                 Respond to each UDP packet with just its own payload\n",
+        "      --udp-require-size          Only respond to UDP packets of exactly this many bytes, dropping others\n",
+        "      --udp-broadcast             Enable broadcast on IPv4 UDP sockets, to serve broadcast discovery packets\n",
+        "      --interface                 Only listen on the network interface with this name, such as eth0 (Linux only)\n",
+        "      --v6only                    Set IPV6_V6ONLY on IPv6 sockets, so they don't accept IPv4 clients\n",
+        "      --reuse-addr                Set SO_REUSEADDR on UDP sockets, and on TCP ones on Windows (always set for TCP on Unix)\n",
+        "      --reuse-port                Set SO_REUSEPORT on TCP and UDP sockets, so several processes can bind them (Unix only)\n",
+        "      --udp-reuse-addr            Set SO_REUSEADDR on UDP sockets only\n",
+        "      --udp-response-ports        Comma-separated ports to also send UDP responses from, rotating round-robin\n",
+        "      --udp-shards                Bind this many UDP sockets to each address with SO_REUSEPORT to spread load (Unix only)\n",
+        "      --udp-log-batch             Log a summary line for every this many UDP packets instead of one per packet\n",
+        "      --udp-fresh-source          Send each UDP response from a new socket with its own ephemeral port\n",
+        "      --udp-jitter                Delay each UDP response by a random amount of up to this many milliseconds\n",
+        "      --udp-coalesce              Send a single response to bursts of UDP packets from a client within this many ms\n",
+        "      --drop-probability          Deliberately drop UDP responses with this probability, between 0 and 1\n",
+        "      --random-seed               Seed for the random jitter and drops, to make them reproducible\n",
+        "      --unbind-idle               Unbind sockets that received no requests for this many seconds, except the last one\n",
+        "      --csv-log                   Write a CSV row for each served request to this file\n",
+        "      --log-file                  Append a line for each served request to this file\n",
+        "      --stats-interval            Print aggregate statistics about the served requests every this many seconds\n",
+        "      --metrics                   Serve Prometheus metrics over HTTP at this socket address, under /metrics\n",
+        "      --resolve                   Include the client's reverse DNS hostname in TCP text responses (Unix only)\n",
+        "      --tcp-info                  Include the TCP connection's smoothed RTT in responses and logs (Linux only)\n",
+        "      --report-mss                Include the TCP connection's maximum segment size in responses and logs (Linux only)\n",
+        "      --tcp-timestamps            Report whether TCP timestamps were negotiated, and log the RTT derived from them (Linux only)\n",
+        "      --roundtrip                 Wait for TCP clients to echo back a byte after responding, and log the RTT\n",
+        "      --length-framed             Answer each length-prefixed request from TCP clients until they close the connection\n",
+        "      --max-request-size          The largest request accepted with --length-framed, in bytes (default 65536)\n",
+        "      --http                      Answer TCP clients over HTTP/1.1, with the response as the body of each reply\n",
+        "      --http-headers              With --http, also tell clients their address in X-Client-IP, X-Client-Port and X-Real-IP headers\n",
+        "      --http-chunked              With --http, send responses with chunked transfer encoding instead of a Content-Length\n",
+        "      --trust-forwarded           With --http, respond with the client address from Forwarded or X-Forwarded-For headers\n",
+        "      --trusted-proxy             Trust forwarding headers from proxies in this CIDR range, may be given multiple times\n",
+        "      --proxy-protocol            Read a PROXY protocol v1 or v2 header from TCP connections and respond with its client address\n",
+        "      --delay-close               Wait this many milliseconds after sending a TCP response before closing the connection\n",
+        "      --split-response            Write TCP responses in this many separate writes, to send them across segments\n",
+        "      --prespawn                  Pre-spawn this many idle tasks per TCP listener to hand connections off to\n",
+        "      --max-connections           Handle at most this many TCP connections at once across all listeners\n",
+        "      --when-full                 Whether to reject or queue TCP connections over --max-connections (default reject)\n",
+        "      --full-message              Send this message to TCP connections rejected by --max-connections before closing them\n",
+        "      --shutdown-timeout          Wait up to this many seconds for TCP connections to finish on shutdown (default 5)\n",
+        "      --body-file                 Respond to TCP clients with the contents of a file, replacing placeholders\n",
+        "      --error-format              Print fatal errors as either human-readable text or a JSON object (default text)\n",
+        "      --rate-limit                Limit the TCP and UDP responses per second sent to each client IP\n",
+        "      --udp-rate                  Limit the sustained UDP responses per second sent to each client IP\n",
+        "      --udp-burst                 Allow bursts of this many UDP responses per client IP (default same as rate)\n",
+        "      --rate-limit-max-ips        Track at most this many client IPs for rate limiting, evicting the least recent\n",
+        "      --otlp                      Export spans and metrics to an OTLP/HTTP collector at the given endpoint\n",
+        "      --tls-cert                  Serve TCP clients over TLS with the PEM certificate chain in this file\n",
+        "      --tls-key                   The PEM private key for the certificate given with --tls-cert\n",
+        "      --tls-pkcs12                Serve TCP clients over TLS with the key and certificates in this PKCS#12 bundle\n",
+        "      --tls-pkcs12-password       The password the bundle given with --tls-pkcs12 is encrypted with (default none)\n",
+        "      --tls-cipher-suites         Only negotiate these comma-separated TLS cipher suites, such as TLS13_AES_256_GCM_SHA384\n",
+        "      --min-tls-version           Reject TLS clients that don't support at least this version, either 1.2 or 1.3 (default 1.2)\n",
         "\n",
         "Socket addresses may be specified as an IPv4 or IPv6 address, or a domainname, and may include a port number. If ",
-        "no port is specified, then the default of 6969 will be used. If no address is specified for a transport protocol, ",
-        "then [::] and/or 0.0.0.0 will be used. To disable listening on a protocol, use \"-t -\" or \"-u -\".\n",
+        "no port is specified, then the default of 6969, or the one given with --default-port, will be used. The default ",
+        "port applies to all addresses regardless of the order of the arguments, except --resolve-only, which only uses ",
+        "a --default-port given before it. Link-local IPv6 addresses without a scope, such as [fe80::1]:6969, are ",
+        "scoped to the interface given with --default-scope, which applies the same way. If no address is specified for ",
+        "a transport protocol, then [::] and/or 0.0.0.0 will be used. To disable listening on a protocol, use \"-t -\" ",
+        "or \"-u -\". Address files may contain blank lines and comment lines starting with #. An address may end with ",
+        "=FORMAT, such as 0.0.0.0:6969=json, to respond on it in that format instead of the one given with --format, ",
+        "where FORMAT is text, json, ip-bytes or ip-port-bytes, or http for a TCP address to answer over HTTP on just ",
+        "that address. On Unix, a TCP address ",
+        "may also be given as unix:PATH to listen on a Unix domain socket at that path instead, which can't be used ",
+        "with --http, --length-framed, --roundtrip, --proxy-protocol, --split-response, --tls-cert, --tls-pkcs12 nor the ",
+        "ip-bytes formats. If no sockets are specified and the process was started through systemd socket activation, ",
+        "the sockets passed in with LISTEN_FDS are served instead of binding the default addresses.\n",
+        "\n",
+        "The ip-bytes format responds with just the client's IP address as raw bytes in network order, 4 bytes for IPv4 ",
+        "or 16 bytes for IPv6, with IPv4-mapped IPv6 addresses sent as IPv4. The ip-port-bytes format follows these with ",
+        "the client's port as 2 bytes in network order.\n",
+        "\n",
+        "The json format responds with an object with the client's address, ip, port, the protocol, and the count of ",
+        "connections or packets, such as {\"address\":\"1.2.3.4:5678\",\"ip\":\"1.2.3.4\",\"port\":5678,\"protocol\":\"tcp\",",
+        "\"count\":1}. The fields of the object given with --json-extra are added to it, except for any with the same ",
+        "name as these. With --json-numeric-port false, the port is written as a string instead, such as \"5678\".\n",
+        "\n",
+        "Any other format is used as a template, which may contain the placeholders {addr}, {ip}, {port}, {count}, ",
+        "{proto} and {time}, which are replaced by the client's socket address, IP address, port, the connection or ",
+        "packet number, the protocol, and the current Unix time in seconds respectively. The placeholder {uptime} is ",
+        "replaced by the time since the server started, in seconds with millisecond precision, or as a duration such ",
+        "as \"1d 2h 3m 4s\" with --uptime-format human. The placeholders {pid} and {worker} are replaced by the ",
+        "server's process ID and a number identifying the thread that built the response, which tells apart the ",
+        "threads started with --workers. With --http, the placeholder {user_agent} is replaced by the client's ",
+        "User-Agent header, keeping only printable ASCII characters and at most 256 of them, or by nothing if there is ",
+        "none. Over TLS, the placeholders {tls_version} and {cipher} are replaced by the negotiated protocol version, ",
+        "such as TLSv1.3, and cipher suite, such as TLS13_AES_256_GCM_SHA384. The placeholder {hextets} is ",
+        "replaced by the client's IPv6 address in full, as eight groups of four hexadecimal digits with no zeros left ",
+        "out, or by its IPv4 address as it is. Checksums and TCP info fields are only included in the text format. With ",
+        "--track-per-ip, the placeholder {ip_count} is replaced by how many requests have been received from the ",
+        "client's IP address, including this one. UDP responses longer than 1400 bytes are cut short, except in the ",
+        "json format, where they're dropped instead.\n",
+        "\n",
+        "The CSV log has the columns timestamp, proto, remote_ip, remote_port, counter and bytes, where timestamp is ",
+        "the Unix time in seconds and bytes is the size of the response sent.\n",
+        "\n",
+        "Each line in the log file has the Unix time in seconds, the protocol, the local and remote socket addresses, ",
+        "and the connection or packet number, separated by spaces, such as \"1700000000.123 tcp 0.0.0.0:6969 ",
+        "1.2.3.4:5678 1\".\n",
+        "\n",
+        "With --length-framed, TCP clients send requests prefixed by their length as 4 bytes in network order, and each ",
+        "is answered with the response prefixed the same way, until the client closes the connection. The contents of ",
+        "requests are ignored, and a request larger than --max-request-size closes the connection.\n",
+        "\n",
+        "With --http, each GET or HEAD request is answered with a 200 OK with the response as the body, regardless of ",
+        "the path. A --body-file is served with the Content-Type for its extension, such as text/html for .html files, ",
+        "or text/plain if unknown. Connections are kept alive between requests unless the client asks otherwise. With ",
+        "--http-headers, the client's IP address and port are also sent in the X-Client-IP, X-Client-Port and ",
+        "X-Real-IP headers, as a reverse proxy would, with the IP address hashed if --hash-ip is used. With ",
+        "--http-chunked, the body is sent with Transfer-Encoding: chunked, in chunks of up to 256 bytes, except to ",
+        "HTTP/1.0 clients, which don't support it. With ",
+        "--trust-forwarded, the client is found by walking back the addresses in the Forwarded or X-Forwarded-For ",
+        "header from the connection's peer for as long as each is a trusted proxy, as given with --trusted-proxy, which ",
+        "is required so clients can't claim any address. Forwarded addresses without a port are shown with port 0.\n",
+        "\n",
+        "With --echo, the payload of each UDP packet is appended to its text response, after \" | echo: \". It can't ",
+        "be used with other UDP formats, as the payload would make them unparseable. Responses are kept within 1400 ",
+        "bytes, so a long payload is cut short, which is noted by saying \"echo (truncated)\" instead.\n",
+        "\n",
+        "With --once, the first TCP connection or UDP packet to be served across all sockets is answered, after which ",
+        "the server shuts down and exits successfully. Clients dropped by filters such as --allow don't count.\n",
+        "\n",
+        "With --full-message, TCP and Unix connections rejected by --max-connections are sent the message before being ",
+        "closed, as the body of a 503 Service Unavailable response on addresses answering over HTTP. It can't be used ",
+        "with TLS, as rejected connections are closed before their handshake. UDP has no connection limit, so packets ",
+        "are never turned away this way.\n",
+        "\n",
+        "With --udp-shards, the kernel spreads the packets sent to each UDP address across the shards, which pairs well ",
+        "with --workers. Each shard keeps its own packet count, so the counts in UDP responses restart for every shard.\n",
+        "\n",
+        "A body file may contain the same placeholders as a template format, and like with --format, an unknown ",
+        "placeholder is an error.\n",
+        "\n",
+        "--capabilities prints one identifier per line: tcp and udp, then those available on this platform out of unix, ",
+        "fd (--fd-tcp and --fd-udp), systemd (socket activation), reuse-port, udp-shards, resolve, interface, tcp-info, ",
+        "tcp-mss (--report-mss) and tcp-timestamps, then otlp and tls if those features were compiled in.\n",
         "\n",
         "\n",
         "Examples:\n",
@@ -41,19 +221,146 @@ pub fn get_help_string() -> &'static str {
     )
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Gets the list of capabilities supported by this build, one machine-readable identifier each. These are the
+/// transports, the options that depend on the platform, and the optional Cargo features compiled in.
+pub fn get_capabilities() -> Vec<&'static str> {
+    let mut capabilities = vec!["tcp", "udp"];
+    if cfg!(unix) {
+        capabilities.extend(["unix", "fd", "systemd", "reuse-port", "udp-shards", "resolve"]);
+    }
+    if cfg!(target_os = "linux") {
+        capabilities.extend(["interface", "tcp-info", "tcp-mss", "tcp-timestamps"]);
+    }
+    if cfg!(feature = "otlp") {
+        capabilities.push("otlp");
+    }
+    if cfg!(feature = "tls") {
+        capabilities.push("tls");
+    }
+
+    capabilities
+}
+
+#[derive(Debug, PartialEq)]
 pub enum ArgumentsRequest {
     Help,
     Version,
-    Run(StartupArguments),
+    Capabilities,
+    ResolveOnly(Vec<SocketAddr>),
+    Run(Box<StartupArguments>),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq)]
 pub struct StartupArguments {
     pub verbose: bool,
     pub silent: bool,
+    pub warn_on_private: bool,
+    /// If not empty, only clients with an IP address in one of these ranges are served.
+    pub allow: Vec<IpNetwork>,
+    /// Clients with an IP address in any of these ranges are not served, even if also allowed.
+    pub deny: Vec<IpNetwork>,
+    pub udp_response_port_offset: u16,
+    pub tcp_format: ResponseFormat,
+    pub udp_format: ResponseFormat,
+    /// The formats given to single TCP addresses with an `=format` suffix, replacing `tcp_format` for them.
+    pub tcp_address_formats: HashMap<SocketAddr, AddressFormat>,
+    /// The formats given to single UDP addresses with an `=format` suffix, replacing `udp_format` for them.
+    pub udp_address_formats: HashMap<SocketAddr, ResponseFormat>,
+    /// The salt to hash client IP addresses with in responses, if `--hash-ip` was specified.
+    pub ip_salt: Option<String>,
+    /// The fields from `--json-extra` to add to json format responses, already serialized and without braces.
+    pub json_extra: Option<String>,
+    /// Whether the port is written as a number in json format responses, rather than as a string.
+    pub json_numeric_port: bool,
+    pub uptime_format: UptimeFormat,
+    pub track_per_ip: bool,
+    pub first_seen_message: Option<String>,
+    pub first_seen_ttl: Duration,
+    pub checksum: Option<ChecksumType>,
+    pub empty_udp: EmptyUdpPolicy,
+    pub echo: Option<EchoMode>,
+    pub udp_require_size: Option<usize>,
+    pub udp_broadcast: bool,
+    pub interface: Option<String>,
+    pub v6only: bool,
+    pub reuse_addr: bool,
+    pub reuse_port: bool,
+    pub udp_reuse_addr: bool,
+    pub udp_response_ports: Vec<u16>,
+    /// How many sockets are bound to each UDP address. Each shard counts the packets it receives on its own.
+    pub udp_shards: NonZeroUsize,
+    pub udp_log_batch: Option<NonZeroUsize>,
+    pub udp_fresh_source: bool,
+    pub udp_jitter: Option<Duration>,
+    pub udp_coalesce: Option<Duration>,
+    pub drop_probability: f64,
+    pub random_seed: Option<u64>,
+    pub unbind_idle: Option<Duration>,
+    pub csv_log: Option<String>,
+    pub log_file: Option<String>,
+    pub stats_interval: Option<Duration>,
+    pub metrics_address: Option<SocketAddr>,
+    pub resolve_hostnames: bool,
+    pub tcp_info: bool,
+    pub report_mss: bool,
+    pub tcp_timestamps: bool,
+    pub roundtrip: bool,
+    pub length_framed: bool,
+    pub max_request_size: usize,
+    pub http: bool,
+    /// Whether HTTP responses tell the client its address in headers as well as in the body, with `--http-headers`.
+    pub http_headers: bool,
+    /// Whether HTTP responses are sent with chunked transfer encoding instead of a Content-Length, with `--http-chunked`.
+    pub http_chunked: bool,
+    pub trust_forwarded: bool,
+    pub trusted_proxies: Vec<IpNetwork>,
+    pub proxy_protocol: bool,
+    pub delay_close: Option<Duration>,
+    pub split_response: Option<NonZeroUsize>,
+    pub prespawn: Option<usize>,
+    pub max_connections: Option<NonZeroUsize>,
+    pub connection_limit_policy: ConnectionLimitPolicy,
+    pub full_message: Option<String>,
+    pub shutdown_timeout: Duration,
+    pub workers: Option<NonZeroUsize>,
+    pub once: bool,
+    /// The `--body-file` contents, parsed as a template with the same placeholders as `--format`.
+    pub body_template: Option<ResponseTemplate>,
+    /// The Content-Type the `--body-file` is served with in `--http` mode, taken from its extension.
+    pub body_content_type: &'static str,
+    pub error_format: ErrorFormat,
+    /// The amount of simulated errors each server returns from accept/recv before using its socket. This is set by
+    /// the hidden `--inject-errors` option, meant only for testing.
+    pub inject_errors: u32,
+    /// The TCP connections per second served to each client IP, from `--rate-limit`. Connections over the limit are
+    /// closed without a response.
+    pub tcp_rate: Option<NonZeroU32>,
+    pub udp_rate: Option<NonZeroU32>,
+    pub udp_burst: Option<NonZeroU32>,
+    pub rate_limit_max_ips: Option<NonZeroUsize>,
+    pub otlp_endpoint: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_pkcs12: Option<String>,
+    pub tls_pkcs12_password: Option<String>,
+    pub tls_cipher_suites: Vec<String>,
+    pub min_tls_version: Option<TlsVersion>,
     pub tcp_addresses: Vec<SocketAddr>,
     pub udp_addresses: Vec<SocketAddr>,
+    /// Whether `tcp_addresses` holds the default addresses, rather than ones explicitly specified by the user.
+    pub tcp_addresses_default: bool,
+    /// Whether `udp_addresses` holds the default addresses, rather than ones explicitly specified by the user.
+    pub udp_addresses_default: bool,
+    /// Inherited file descriptors of already bound TCP listeners to serve, in addition to `tcp_addresses`.
+    pub tcp_fds: Vec<i32>,
+    /// Paths of Unix domain sockets to listen on, given as TCP addresses of the form `unix:PATH`.
+    pub unix_paths: Vec<PathBuf>,
+    /// Inherited file descriptors of already bound UDP sockets to serve, in addition to `udp_addresses`.
+    pub udp_fds: Vec<i32>,
+    /// File descriptors passed by systemd socket activation, served instead of the default addresses if both are in
+    /// use. These aren't parsed from the arguments, the caller takes them from the environment before starting any
+    /// threads, as with `utils::take_systemd_listen_fds`.
+    pub activation_fds: Vec<i32>,
 }
 
 impl StartupArguments {
@@ -61,8 +368,254 @@ impl StartupArguments {
         StartupArguments {
             verbose: false,
             silent: false,
+            warn_on_private: false,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            udp_response_port_offset: 0,
+            tcp_format: ResponseFormat::Text,
+            udp_format: ResponseFormat::Text,
+            tcp_address_formats: HashMap::new(),
+            udp_address_formats: HashMap::new(),
+            ip_salt: None,
+            json_extra: None,
+            json_numeric_port: true,
+            uptime_format: UptimeFormat::Seconds,
+            track_per_ip: false,
+            first_seen_message: None,
+            first_seen_ttl: DEFAULT_FIRST_SEEN_TTL,
+            checksum: None,
+            empty_udp: EmptyUdpPolicy::Respond,
+            echo: None,
+            udp_require_size: None,
+            udp_broadcast: false,
+            interface: None,
+            v6only: false,
+            reuse_addr: false,
+            reuse_port: false,
+            udp_reuse_addr: false,
+            udp_response_ports: Vec::new(),
+            udp_shards: NonZeroUsize::MIN,
+            udp_log_batch: None,
+            udp_fresh_source: false,
+            udp_jitter: None,
+            udp_coalesce: None,
+            drop_probability: 0.0,
+            random_seed: None,
+            unbind_idle: None,
+            csv_log: None,
+            log_file: None,
+            stats_interval: None,
+            metrics_address: None,
+            resolve_hostnames: false,
+            tcp_info: false,
+            report_mss: false,
+            tcp_timestamps: false,
+            roundtrip: false,
+            length_framed: false,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            http: false,
+            http_headers: false,
+            http_chunked: false,
+            trust_forwarded: false,
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            delay_close: None,
+            split_response: None,
+            prespawn: None,
+            max_connections: None,
+            connection_limit_policy: ConnectionLimitPolicy::Reject,
+            full_message: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            workers: None,
+            once: false,
+            body_template: None,
+            body_content_type: "text/plain; charset=utf-8",
+            error_format: ErrorFormat::Text,
+            inject_errors: 0,
+            tcp_rate: None,
+            udp_rate: None,
+            udp_burst: None,
+            rate_limit_max_ips: None,
+            otlp_endpoint: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_pkcs12: None,
+            tls_pkcs12_password: None,
+            tls_cipher_suites: Vec::new(),
+            min_tls_version: None,
             tcp_addresses: Vec::new(),
             udp_addresses: Vec::new(),
+            tcp_addresses_default: false,
+            udp_addresses_default: false,
+            tcp_fds: Vec::new(),
+            unix_paths: Vec::new(),
+            udp_fds: Vec::new(),
+            activation_fds: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumType {
+    Crc32,
+    Sha256,
+}
+
+impl FromStr for ChecksumType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("crc32") {
+            Ok(Self::Crc32)
+        } else if s.eq_ignore_ascii_case("sha256") {
+            Ok(Self::Sha256)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// How the time since the server started is written in place of `{uptime}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UptimeFormat {
+    /// Seconds with millisecond precision, such as "93784.250".
+    Seconds,
+    /// A duration in days, hours, minutes and seconds, such as "1d 2h 3m 4s".
+    Human,
+}
+
+impl FromStr for UptimeFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("seconds") {
+            Ok(Self::Seconds)
+        } else if s.eq_ignore_ascii_case("human") {
+            Ok(Self::Human)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// The lowest TLS version clients are allowed to negotiate with `--min-tls-version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(Self::Tls12),
+            "1.3" => Ok(Self::Tls13),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyUdpPolicy {
+    Respond,
+    Drop,
+}
+
+/// How the payload of a UDP packet is echoed back to its sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoMode {
+    /// The payload is appended to the response, with `--echo`.
+    Append,
+    /// The payload is sent back instead of the response, with `--echo-only`.
+    Replace,
+}
+
+impl FromStr for EmptyUdpPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("respond") {
+            Ok(Self::Respond)
+        } else if s.eq_ignore_ascii_case("drop") {
+            Ok(Self::Drop)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// What to do with TCP connections accepted while `--max-connections` connections are already being handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimitPolicy {
+    /// Close the connection right away, after sending the `--full-message` if there is one.
+    Reject,
+    /// Stop accepting connections until one finishes, leaving new ones queued in the listen backlog.
+    Queue,
+}
+
+impl FromStr for ConnectionLimitPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("reject") {
+            Ok(Self::Reject)
+        } else if s.eq_ignore_ascii_case("queue") {
+            Ok(Self::Queue)
+        } else {
+            Err(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Text,
+    IpBytes,
+    IpPortBytes,
+    Json,
+    Template(ResponseTemplate),
+}
+
+impl ResponseFormat {
+    /// The name of the format, as used for the `format` label in metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::IpBytes => "ip-bytes",
+            Self::IpPortBytes => "ip-port-bytes",
+            Self::Json => "json",
+            Self::Template(_) => "template",
+        }
+    }
+}
+
+/// The format given to a single address with an `=format` suffix, such as `-t 0.0.0.0:80=http`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressFormat {
+    /// Respond in this format instead of the one given with `--format`.
+    Format(ResponseFormat),
+    /// Answer over HTTP/1.1, the same as `--http` does for every TCP address. Only valid for TCP addresses.
+    Http,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("text") {
+            Ok(Self::Text)
+        } else if s.eq_ignore_ascii_case("json") {
+            Ok(Self::Json)
+        } else {
+            Err(())
         }
     }
 }
@@ -70,8 +623,19 @@ impl StartupArguments {
 #[derive(Debug, PartialEq, Eq)]
 pub enum ArgumentsError {
     UnknownArgument(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+    NotSupported(String),
+    ReadFileError(String, String),
+    UnknownPlaceholder(String, String),
+    IncompatibleArguments(String, String),
+    RequiresArgument(String, String),
+    TooManyAddresses(usize, usize),
     TcpListenError(SocketErrorType),
     UdpListenError(SocketErrorType),
+    ResolveOnlyError(SocketErrorType),
+    IpFilterError(SocketErrorType),
+    RandomSaltError(String),
     NoSocketsSpecified,
 }
 
@@ -79,17 +643,36 @@ impl fmt::Display for ArgumentsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::UnknownArgument(arg) => write!(f, "Unknown argument: {arg}"),
+            Self::MissingValue(arg) => write!(f, "Expected value after {arg}"),
+            Self::InvalidValue(arg, value) => write!(f, "Invalid value after {arg}: {value}"),
+            Self::NotSupported(arg) => write!(f, "{arg} is not supported by this build"),
+            Self::ReadFileError(path, error) => write!(f, "Failed to read file {path}: {error}"),
+            Self::UnknownPlaceholder(arg, placeholder) => write!(f, "Unknown placeholder after {arg}: {{{placeholder}}}"),
+            Self::IncompatibleArguments(first, second) => write!(f, "{first} can't be used together with {second}"),
+            Self::RequiresArgument(first, second) => write!(f, "{first} requires {second}"),
+            Self::TooManyAddresses(count, max) => {
+                write!(
+                    f,
+                    "Too many sockets to bind: {count}, but at most {max} are allowed (see --max-addresses)"
+                )
+            }
             Self::TcpListenError(tcp_error) => tcp_error.fmt(f),
             Self::UdpListenError(udp_error) => udp_error.fmt(f),
+            Self::ResolveOnlyError(resolve_error) => resolve_error.fmt(f),
+            Self::IpFilterError(filter_error) => filter_error.fmt(f),
+            Self::RandomSaltError(error) => write!(f, "Failed to generate a random salt for --hash-ip: {error}"),
             Self::NoSocketsSpecified => write!(f, "No sockets were specified for TCP nor UDP!"),
         }
     }
 }
 
+impl std::error::Error for ArgumentsError {}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum SocketErrorType {
     UnexpectedEnd(String),
     InvalidSocketAddress(String, String),
+    InvalidNetwork(String, String),
 }
 
 impl fmt::Display for SocketErrorType {
@@ -97,6 +680,7 @@ impl fmt::Display for SocketErrorType {
         match self {
             Self::UnexpectedEnd(arg) => write!(f, "Expected socket address after {arg}"),
             Self::InvalidSocketAddress(arg, addr) => write!(f, "Invalid socket address after {arg}: {addr}"),
+            Self::InvalidNetwork(arg, network) => write!(f, "Invalid CIDR range after {arg}: {network}"),
         }
     }
 }
@@ -106,6 +690,7 @@ fn parse_socket_arg(
     arg: String,
     maybe_arg2: Option<String>,
     default_port: u16,
+    default_scope: u32,
 ) -> Result<(), SocketErrorType> {
     let arg2 = match maybe_arg2 {
         Some(value) => value,
@@ -121,7 +706,13 @@ fn parse_socket_arg(
         Err(_) => return Err(SocketErrorType::InvalidSocketAddress(arg, arg2)),
     };
 
-    for sockaddr in iter {
+    for mut sockaddr in iter {
+        if let SocketAddr::V6(v6) = &mut sockaddr {
+            if v6.scope_id() == 0 && utils::is_link_local_v6(*v6.ip()) {
+                v6.set_scope_id(default_scope);
+            }
+        }
+
         if !result_vec.contains(&sockaddr) {
             result_vec.push(sockaddr);
         }
@@ -130,6 +721,196 @@ fn parse_socket_arg(
     Ok(())
 }
 
+fn parse_network_arg(result_vec: &mut Vec<IpNetwork>, arg: String, maybe_arg2: Option<String>) -> Result<(), ArgumentsError> {
+    let arg2 = match maybe_arg2 {
+        Some(value) => value,
+        None => return Err(ArgumentsError::MissingValue(arg)),
+    };
+
+    match arg2.trim().parse() {
+        Ok(network) => {
+            if !result_vec.contains(&network) {
+                result_vec.push(network);
+            }
+            Ok(())
+        }
+        Err(_) => Err(ArgumentsError::IpFilterError(SocketErrorType::InvalidNetwork(arg, arg2))),
+    }
+}
+
+fn parse_value_arg<T: FromStr>(arg: String, maybe_arg2: Option<String>) -> Result<T, ArgumentsError> {
+    let arg2 = match maybe_arg2 {
+        Some(value) => value,
+        None => return Err(ArgumentsError::MissingValue(arg)),
+    };
+
+    match arg2.trim().parse() {
+        Ok(value) => Ok(value),
+        Err(_) => Err(ArgumentsError::InvalidValue(arg, arg2)),
+    }
+}
+
+fn parse_list_arg<T: FromStr>(arg: String, maybe_arg2: Option<String>) -> Result<Vec<T>, ArgumentsError> {
+    let arg2 = match maybe_arg2 {
+        Some(value) => value,
+        None => return Err(ArgumentsError::MissingValue(arg)),
+    };
+
+    match arg2.split(',').map(|s| s.trim().parse()).collect() {
+        Ok(values) => Ok(values),
+        Err(_) => Err(ArgumentsError::InvalidValue(arg, arg2)),
+    }
+}
+
+/// Reads a file with one socket address per line, ignoring blank lines and lines starting with `#`, and adds each
+/// address to `pending` to be parsed once the default port is known, along with its location for error messages.
+fn read_socket_file_arg(pending: &mut Vec<(String, String)>, arg: String, maybe_arg2: Option<String>) -> Result<(), ArgumentsError> {
+    let path = match maybe_arg2 {
+        Some(value) => value,
+        None => return Err(ArgumentsError::MissingValue(arg)),
+    };
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) => return Err(ArgumentsError::ReadFileError(path, error.to_string())),
+    };
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            pending.push((format!("{arg} {path} (line {})", index + 1), line.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits the `=format` suffix off each pending socket address, such as `0.0.0.0:80=http`. Only the named formats
+/// may be given this way, as a template could contain an equals sign itself, and `http` only for TCP addresses.
+/// Unix domain socket paths are left as they are, since an equals sign is valid in a path.
+fn split_address_formats(
+    pending: Vec<(String, String)>,
+    allow_http: bool,
+) -> Result<Vec<(String, String, Option<AddressFormat>)>, ArgumentsError> {
+    let mut result = Vec::with_capacity(pending.len());
+    for (arg, arg2) in pending {
+        let Some((address, name)) = arg2.trim().rsplit_once('=').filter(|_| !arg2.trim().starts_with("unix:")) else {
+            result.push((arg, arg2, None));
+            continue;
+        };
+
+        let name = name.trim();
+        let format = if name.eq_ignore_ascii_case("text") {
+            AddressFormat::Format(ResponseFormat::Text)
+        } else if name.eq_ignore_ascii_case("json") {
+            AddressFormat::Format(ResponseFormat::Json)
+        } else if name.eq_ignore_ascii_case("ip-bytes") {
+            AddressFormat::Format(ResponseFormat::IpBytes)
+        } else if name.eq_ignore_ascii_case("ip-port-bytes") {
+            AddressFormat::Format(ResponseFormat::IpPortBytes)
+        } else if name.eq_ignore_ascii_case("http") && allow_http {
+            AddressFormat::Http
+        } else {
+            return Err(ArgumentsError::InvalidValue(arg, arg2));
+        };
+
+        let address = address.to_string();
+        result.push((arg, address, Some(format)));
+    }
+
+    Ok(result)
+}
+
+/// Parses a comma-separated list of file descriptors into `result_vec`, rejecting any that were already specified,
+/// as each descriptor is taken ownership of when serving it.
+fn parse_fd_arg(result_vec: &mut Vec<i32>, other_fds: &[i32], arg: String, maybe_arg2: Option<String>) -> Result<(), ArgumentsError> {
+    if !cfg!(unix) {
+        return Err(ArgumentsError::NotSupported(arg));
+    }
+
+    let arg2 = match maybe_arg2 {
+        Some(value) => value,
+        None => return Err(ArgumentsError::MissingValue(arg)),
+    };
+
+    for value in arg2.split(',') {
+        match value.trim().parse() {
+            Ok(fd) if fd >= 0 && !result_vec.contains(&fd) && !other_fds.contains(&fd) => result_vec.push(fd),
+            _ => return Err(ArgumentsError::InvalidValue(arg, arg2)),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_unix_path_arg(result_vec: &mut Vec<PathBuf>, arg: String, path: &str) -> Result<(), ArgumentsError> {
+    if !cfg!(unix) {
+        return Err(ArgumentsError::NotSupported(format!("{arg} unix:{path}")));
+    }
+
+    if path.is_empty() {
+        return Err(ArgumentsError::InvalidValue(arg, "unix:".into()));
+    }
+
+    let path = PathBuf::from(path);
+    if !result_vec.contains(&path) {
+        result_vec.push(path);
+    }
+
+    Ok(())
+}
+
+fn parse_format_arg(arg: String, maybe_arg2: Option<String>) -> Result<ResponseFormat, ArgumentsError> {
+    let arg2 = match maybe_arg2 {
+        Some(value) => value,
+        None => return Err(ArgumentsError::MissingValue(arg)),
+    };
+
+    let name = arg2.trim();
+    if name.eq_ignore_ascii_case("text") {
+        Ok(ResponseFormat::Text)
+    } else if name.eq_ignore_ascii_case("json") {
+        Ok(ResponseFormat::Json)
+    } else if name.eq_ignore_ascii_case("ip-bytes") {
+        Ok(ResponseFormat::IpBytes)
+    } else if name.eq_ignore_ascii_case("ip-port-bytes") {
+        Ok(ResponseFormat::IpPortBytes)
+    } else {
+        match ResponseTemplate::parse(&arg2) {
+            Ok(template) => Ok(ResponseFormat::Template(template)),
+            Err(placeholder) => Err(ArgumentsError::UnknownPlaceholder(arg, placeholder)),
+        }
+    }
+}
+
+/// Parses a JSON object to add to json format responses, returning its fields serialized without the surrounding
+/// braces. Fields that collide with the ones in the response are left out, since those take precedence.
+fn parse_json_extra_arg(arg: String, maybe_arg2: Option<String>) -> Result<Option<String>, ArgumentsError> {
+    let arg2 = match maybe_arg2 {
+        Some(value) => value,
+        None => return Err(ArgumentsError::MissingValue(arg)),
+    };
+
+    let object: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(&arg2) {
+        Ok(object) => object,
+        Err(_) => return Err(ArgumentsError::InvalidValue(arg, arg2)),
+    };
+
+    let fields: Vec<String> = object
+        .into_iter()
+        .filter(|(key, _)| !JSON_RESPONSE_FIELDS.contains(&key.as_str()))
+        .map(|(key, value)| format!("{}:{value}", serde_json::Value::String(key)))
+        .collect();
+
+    Ok(if fields.is_empty() { None } else { Some(fields.join(",")) })
+}
+
+/// Parses the given arguments, not including the program's name, as if they were passed on the command line.
+pub fn parse_args_from(args: &[&str]) -> Result<ArgumentsRequest, ArgumentsError> {
+    let program_name = env!("CARGO_PKG_NAME").to_string();
+    parse_arguments(std::iter::once(program_name).chain(args.iter().map(|arg| arg.to_string())))
+}
+
 pub fn parse_arguments<T>(mut args: T) -> Result<ArgumentsRequest, ArgumentsError>
 where
     T: Iterator<Item = String>,
@@ -141,6 +922,18 @@ where
 
     let mut tcp_specified = false;
     let mut udp_specified = false;
+    let mut max_addresses = DEFAULT_MAX_ADDRESSES;
+    let mut default_port = DEFAULT_PORT;
+    let mut default_scope = 0;
+    let mut tcp_pending = Vec::new();
+    let mut hash_ip = false;
+    let mut ip_salt = None;
+    let mut udp_pending = Vec::new();
+    let mut format = ResponseFormat::Text;
+    let mut tcp_format = None;
+    let mut udp_format = None;
+    let mut rate_limit = None;
+    let mut max_request_size = None;
 
     while let Some(arg) = args.next() {
         if arg.is_empty() {
@@ -149,48 +942,887 @@ where
             return Ok(ArgumentsRequest::Help);
         } else if arg.eq("-V") || arg.eq_ignore_ascii_case("--version") {
             return Ok(ArgumentsRequest::Version);
+        } else if arg.eq_ignore_ascii_case("--capabilities") {
+            return Ok(ArgumentsRequest::Capabilities);
+        } else if arg.eq_ignore_ascii_case("--resolve-only") {
+            let mut addresses = Vec::new();
+            parse_socket_arg(&mut addresses, arg, args.next(), default_port, default_scope).map_err(ArgumentsError::ResolveOnlyError)?;
+            return Ok(ArgumentsRequest::ResolveOnly(addresses));
         } else if arg.eq("-v") || arg.eq_ignore_ascii_case("--verbose") {
             result.verbose = true;
         } else if arg.eq("-s") || arg.eq_ignore_ascii_case("--silent") {
             result.silent = true;
+        } else if arg.eq_ignore_ascii_case("--max-addresses") {
+            max_addresses = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--warn-on-private") {
+            result.warn_on_private = true;
+        } else if arg.eq_ignore_ascii_case("--allow") {
+            parse_network_arg(&mut result.allow, arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--deny") {
+            parse_network_arg(&mut result.deny, arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--udp-response-port-offset") {
+            result.udp_response_port_offset = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--format") {
+            format = parse_format_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--json") {
+            format = ResponseFormat::Json;
+        } else if arg.eq_ignore_ascii_case("--format-tcp") {
+            tcp_format = Some(parse_format_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--format-udp") {
+            udp_format = Some(parse_format_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--json-extra") {
+            result.json_extra = parse_json_extra_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--json-numeric-port") {
+            result.json_numeric_port = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--uptime-format") {
+            result.uptime_format = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--track-per-ip") {
+            result.track_per_ip = true;
+        } else if arg.eq_ignore_ascii_case("--first-seen-message") {
+            result.first_seen_message = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--first-seen-ttl") {
+            let secs: NonZeroU64 = parse_value_arg(arg, args.next())?;
+            result.first_seen_ttl = Duration::from_secs(secs.get());
+        } else if arg.eq_ignore_ascii_case("--hash-ip") {
+            hash_ip = true;
+        } else if arg.eq_ignore_ascii_case("--ip-salt") {
+            ip_salt = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--checksum") {
+            result.checksum = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--empty-udp") {
+            result.empty_udp = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--echo") {
+            result.echo = Some(EchoMode::Append);
+        } else if arg.eq_ignore_ascii_case("--echo-only") {
+            result.echo = Some(EchoMode::Replace);
+        } else if arg.eq_ignore_ascii_case("--udp-require-size") {
+            result.udp_require_size = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--udp-broadcast") {
+            result.udp_broadcast = true;
+        } else if arg.eq_ignore_ascii_case("--interface") {
+            if !cfg!(target_os = "linux") {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.interface = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--v6only") {
+            result.v6only = true;
+        } else if arg.eq_ignore_ascii_case("--reuse-addr") {
+            result.reuse_addr = true;
+        } else if arg.eq_ignore_ascii_case("--reuse-port") {
+            if !cfg!(unix) {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.reuse_port = true;
+        } else if arg.eq_ignore_ascii_case("--udp-reuse-addr") {
+            result.udp_reuse_addr = true;
+        } else if arg.eq_ignore_ascii_case("--udp-response-ports") {
+            result.udp_response_ports = parse_list_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--udp-shards") {
+            if !cfg!(unix) {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.udp_shards = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--udp-log-batch") {
+            result.udp_log_batch = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--udp-fresh-source") {
+            result.udp_fresh_source = true;
+        } else if arg.eq_ignore_ascii_case("--udp-jitter") {
+            result.udp_jitter = Some(Duration::from_millis(parse_value_arg(arg, args.next())?));
+        } else if arg.eq_ignore_ascii_case("--udp-coalesce") {
+            let millis: NonZeroU64 = parse_value_arg(arg, args.next())?;
+            result.udp_coalesce = Some(Duration::from_millis(millis.get()));
+        } else if arg.eq_ignore_ascii_case("--drop-probability") {
+            let arg2 = args.next();
+            let probability: f64 = parse_value_arg(arg.clone(), arg2.clone())?;
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(ArgumentsError::InvalidValue(arg, arg2.unwrap_or_default()));
+            }
+            result.drop_probability = probability;
+        } else if arg.eq_ignore_ascii_case("--random-seed") {
+            result.random_seed = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--unbind-idle") {
+            let secs: NonZeroU64 = parse_value_arg(arg, args.next())?;
+            result.unbind_idle = Some(Duration::from_secs(secs.get()));
+        } else if arg.eq_ignore_ascii_case("--csv-log") {
+            result.csv_log = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--log-file") {
+            result.log_file = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--stats-interval") {
+            let secs: NonZeroU64 = parse_value_arg(arg, args.next())?;
+            result.stats_interval = Some(Duration::from_secs(secs.get()));
+        } else if arg.eq_ignore_ascii_case("--metrics") {
+            result.metrics_address = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--resolve") {
+            result.resolve_hostnames = true;
+        } else if arg.eq_ignore_ascii_case("--tcp-info") {
+            result.tcp_info = true;
+        } else if arg.eq_ignore_ascii_case("--report-mss") {
+            result.report_mss = true;
+        } else if arg.eq_ignore_ascii_case("--tcp-timestamps") {
+            result.tcp_timestamps = true;
+        } else if arg.eq_ignore_ascii_case("--roundtrip") {
+            result.roundtrip = true;
+        } else if arg.eq_ignore_ascii_case("--length-framed") {
+            result.length_framed = true;
+        } else if arg.eq_ignore_ascii_case("--http") {
+            result.http = true;
+        } else if arg.eq_ignore_ascii_case("--http-headers") {
+            result.http_headers = true;
+        } else if arg.eq_ignore_ascii_case("--http-chunked") {
+            result.http_chunked = true;
+        } else if arg.eq_ignore_ascii_case("--trust-forwarded") {
+            result.trust_forwarded = true;
+        } else if arg.eq_ignore_ascii_case("--trusted-proxy") {
+            parse_network_arg(&mut result.trusted_proxies, arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--proxy-protocol") {
+            result.proxy_protocol = true;
+        } else if arg.eq_ignore_ascii_case("--max-request-size") {
+            max_request_size = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--delay-close") {
+            result.delay_close = Some(Duration::from_millis(parse_value_arg(arg, args.next())?));
+        } else if arg.eq_ignore_ascii_case("--split-response") {
+            result.split_response = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--max-connections") {
+            result.max_connections = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--when-full") {
+            result.connection_limit_policy = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--full-message") {
+            result.full_message = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--prespawn") {
+            result.prespawn = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--shutdown-timeout") {
+            result.shutdown_timeout = Duration::from_secs(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--workers") {
+            result.workers = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--once") {
+            result.once = true;
+        } else if arg.eq_ignore_ascii_case("--body-file") {
+            let path: String = parse_value_arg(arg, args.next())?;
+            let contents = fs::read_to_string(&path).map_err(|error| ArgumentsError::ReadFileError(path.clone(), error.to_string()))?;
+            match ResponseTemplate::parse(&contents) {
+                Ok(template) => result.body_template = Some(template),
+                Err(placeholder) => return Err(ArgumentsError::UnknownPlaceholder("--body-file".into(), placeholder)),
+            }
+            result.body_content_type = http::content_type_for_path(&path);
+        } else if arg.eq_ignore_ascii_case("--error-format") {
+            result.error_format = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--inject-errors") {
+            result.inject_errors = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--rate-limit") {
+            rate_limit = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--udp-rate") {
+            result.udp_rate = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--udp-burst") {
+            result.udp_burst = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--rate-limit-max-ips") {
+            result.rate_limit_max_ips = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--otlp") {
+            if !cfg!(feature = "otlp") {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.otlp_endpoint = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--tls-cert") {
+            if !cfg!(feature = "tls") {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.tls_cert = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--tls-key") {
+            if !cfg!(feature = "tls") {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.tls_key = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--tls-pkcs12") {
+            if !cfg!(feature = "tls") {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.tls_pkcs12 = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--tls-pkcs12-password") {
+            if !cfg!(feature = "tls") {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.tls_pkcs12_password = Some(parse_value_arg(arg, args.next())?);
+        } else if arg.eq_ignore_ascii_case("--tls-cipher-suites") {
+            if !cfg!(feature = "tls") {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.tls_cipher_suites = parse_list_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--min-tls-version") {
+            if !cfg!(feature = "tls") {
+                return Err(ArgumentsError::NotSupported(arg));
+            }
+            result.min_tls_version = Some(parse_value_arg(arg, args.next())?);
         } else if arg.eq("-t") || arg.eq_ignore_ascii_case("--listen-tcp") {
             tcp_specified = true;
-            let arg2 = args.next();
-            if !arg2.as_deref().is_some_and(|s| s.trim() == "-") {
-                parse_socket_arg(&mut result.tcp_addresses, arg, arg2, DEFAULT_PORT).map_err(ArgumentsError::TcpListenError)?;
+            match args.next() {
+                Some(arg2) if arg2.trim() == "-" => {}
+                Some(arg2) => tcp_pending.push((arg, arg2)),
+                None => return Err(ArgumentsError::TcpListenError(SocketErrorType::UnexpectedEnd(arg))),
             }
         } else if arg.eq("-u") || arg.eq_ignore_ascii_case("--listen-udp") {
             udp_specified = true;
-            let arg2 = args.next();
-            if !arg2.as_deref().is_some_and(|s| s.trim() == "-") {
-                parse_socket_arg(&mut result.udp_addresses, arg, arg2, DEFAULT_PORT).map_err(ArgumentsError::UdpListenError)?;
+            match args.next() {
+                Some(arg2) if arg2.trim() == "-" => {}
+                Some(arg2) => udp_pending.push((arg, arg2)),
+                None => return Err(ArgumentsError::UdpListenError(SocketErrorType::UnexpectedEnd(arg))),
             }
+        } else if arg.eq_ignore_ascii_case("--tcp-file") {
+            tcp_specified = true;
+            read_socket_file_arg(&mut tcp_pending, arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--udp-file") {
+            udp_specified = true;
+            read_socket_file_arg(&mut udp_pending, arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--default-port") {
+            default_port = parse_value_arg(arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--default-scope") {
+            let interface: String = parse_value_arg(arg.clone(), args.next())?;
+            default_scope = match utils::interface_index(&interface) {
+                Some(index) => index,
+                None => return Err(ArgumentsError::InvalidValue(arg, interface)),
+            };
+        } else if arg.eq_ignore_ascii_case("--fd-tcp") {
+            tcp_specified = true;
+            parse_fd_arg(&mut result.tcp_fds, &result.udp_fds, arg, args.next())?;
+        } else if arg.eq_ignore_ascii_case("--fd-udp") {
+            udp_specified = true;
+            parse_fd_arg(&mut result.udp_fds, &result.tcp_fds, arg, args.next())?;
         } else {
             return Err(ArgumentsError::UnknownArgument(arg));
         }
     }
 
+    result.tcp_format = tcp_format.unwrap_or_else(|| format.clone());
+    result.udp_format = udp_format.unwrap_or(format);
+
+    // The formats given to single addresses are split off first, so the checks below consider every format in use.
+    let tcp_pending = split_address_formats(tcp_pending, true)?;
+    let udp_pending = split_address_formats(udp_pending, false)?;
+    let address_format = |(_, _, format): &(String, String, Option<AddressFormat>)| match format {
+        Some(AddressFormat::Format(format)) => Some(format.clone()),
+        _ => None,
+    };
+    let tcp_formats: Vec<ResponseFormat> = std::iter::once(result.tcp_format.clone())
+        .chain(tcp_pending.iter().filter_map(address_format))
+        .collect();
+    let udp_formats: Vec<ResponseFormat> = std::iter::once(result.udp_format.clone())
+        .chain(udp_pending.iter().filter_map(address_format))
+        .collect();
+    let any_format = |matches: &dyn Fn(&ResponseFormat) -> bool| tcp_formats.iter().chain(&udp_formats).any(matches);
+    let http = result.http || tcp_pending.iter().any(|(_, _, format)| *format == Some(AddressFormat::Http));
+    result.tcp_rate = rate_limit;
+    result.udp_rate = result.udp_rate.or(rate_limit);
+
+    if hash_ip {
+        // The binary formats have no room for a hash, and sending the raw address would defeat the purpose.
+        let is_binary = |format: &ResponseFormat| matches!(format, ResponseFormat::IpBytes | ResponseFormat::IpPortBytes);
+        if any_format(&is_binary) {
+            return Err(ArgumentsError::IncompatibleArguments(
+                "--hash-ip".into(),
+                "the ip-bytes formats".into(),
+            ));
+        }
+
+        result.ip_salt = match ip_salt {
+            Some(salt) => Some(salt),
+            None => Some(utils::random_salt().map_err(|error| ArgumentsError::RandomSaltError(error.to_string()))?),
+        };
+    }
+
+    let uses_ip_count = |format: &ResponseFormat| matches!(format, ResponseFormat::Template(t) if t.uses(Placeholder::IpCount));
+    let body_uses_ip_count = result.body_template.as_ref().is_some_and(|t| t.uses(Placeholder::IpCount));
+    if !result.track_per_ip && (uses_ip_count(&result.tcp_format) || uses_ip_count(&result.udp_format) || body_uses_ip_count) {
+        return Err(ArgumentsError::RequiresArgument(
+            "The {ip_count} placeholder".into(),
+            "--track-per-ip".into(),
+        ));
+    }
+
+    if let Some(max_request_size) = max_request_size {
+        if !result.length_framed {
+            return Err(ArgumentsError::RequiresArgument(
+                "--max-request-size".into(),
+                "--length-framed".into(),
+            ));
+        }
+        result.max_request_size = max_request_size;
+    }
+
+    if result.length_framed && result.roundtrip {
+        return Err(ArgumentsError::IncompatibleArguments(
+            "--length-framed".into(),
+            "--roundtrip".into(),
+        ));
+    }
+
+    if result.http_headers && !http {
+        return Err(ArgumentsError::RequiresArgument("--http-headers".into(), "--http".into()));
+    }
+
+    if result.http_chunked && !http {
+        return Err(ArgumentsError::RequiresArgument("--http-chunked".into(), "--http".into()));
+    }
+
+    if result.trust_forwarded && !http {
+        return Err(ArgumentsError::RequiresArgument("--trust-forwarded".into(), "--http".into()));
+    }
+
+    // Trusting forwarding headers from anyone would let any client claim to be any address.
+    if result.trust_forwarded && result.trusted_proxies.is_empty() {
+        return Err(ArgumentsError::RequiresArgument(
+            "--trust-forwarded".into(),
+            "--trusted-proxy".into(),
+        ));
+    }
+
+    if !result.trusted_proxies.is_empty() && !result.trust_forwarded {
+        return Err(ArgumentsError::RequiresArgument(
+            "--trusted-proxy".into(),
+            "--trust-forwarded".into(),
+        ));
+    }
+
+    if http && result.length_framed {
+        return Err(ArgumentsError::IncompatibleArguments("--http".into(), "--length-framed".into()));
+    }
+
+    if http && result.roundtrip {
+        return Err(ArgumentsError::IncompatibleArguments("--http".into(), "--roundtrip".into()));
+    }
+
+    if result.once && result.udp_jitter.is_some() {
+        return Err(ArgumentsError::IncompatibleArguments("--once".into(), "--udp-jitter".into()));
+    }
+
+    if result.udp_shards.get() > 1 && !result.udp_response_ports.is_empty() {
+        return Err(ArgumentsError::IncompatibleArguments(
+            "--udp-shards".into(),
+            "--udp-response-ports".into(),
+        ));
+    }
+
+    if result.tls_cert.is_some() != result.tls_key.is_some() {
+        let (first, second) = match result.tls_cert {
+            Some(_) => ("--tls-cert", "--tls-key"),
+            None => ("--tls-key", "--tls-cert"),
+        };
+        return Err(ArgumentsError::RequiresArgument(first.into(), second.into()));
+    }
+
+    if result.tls_pkcs12.is_some() && result.tls_cert.is_some() {
+        return Err(ArgumentsError::IncompatibleArguments("--tls-pkcs12".into(), "--tls-cert".into()));
+    }
+
+    if result.tls_pkcs12_password.is_some() && result.tls_pkcs12.is_none() {
+        return Err(ArgumentsError::RequiresArgument(
+            "--tls-pkcs12-password".into(),
+            "--tls-pkcs12".into(),
+        ));
+    }
+
+    if result.tls_cert.is_none() && result.tls_pkcs12.is_none() {
+        let tls_only = [
+            (!result.tls_cipher_suites.is_empty(), "--tls-cipher-suites"),
+            (result.min_tls_version.is_some(), "--min-tls-version"),
+        ];
+        if let Some((_, arg)) = tls_only.into_iter().find(|(used, _)| *used) {
+            return Err(ArgumentsError::RequiresArgument(arg.into(), "--tls-cert".into()));
+        }
+    }
+
+    if result.full_message.is_some() {
+        if result.max_connections.is_none() {
+            return Err(ArgumentsError::RequiresArgument(
+                "--full-message".into(),
+                "--max-connections".into(),
+            ));
+        }
+        if result.connection_limit_policy == ConnectionLimitPolicy::Queue {
+            return Err(ArgumentsError::IncompatibleArguments(
+                "--full-message".into(),
+                "--when-full queue".into(),
+            ));
+        }
+        // Rejected connections are turned away before the TLS handshake, so the message can't be sent over TLS.
+        if result.tls_cert.is_some() || result.tls_pkcs12.is_some() {
+            let tls_arg = if result.tls_cert.is_some() { "--tls-cert" } else { "--tls-pkcs12" };
+            return Err(ArgumentsError::IncompatibleArguments("--full-message".into(), tls_arg.into()));
+        }
+    }
+
+    if result.first_seen_message.is_some() && !any_format(&|format| *format == ResponseFormat::Text) {
+        return Err(ArgumentsError::IncompatibleArguments(
+            "--first-seen-message".into(),
+            "formats other than text".into(),
+        ));
+    }
+
+    // Appending the raw payload would make JSON invalid and the byte formats unparseable.
+    if result.echo == Some(EchoMode::Append) && udp_formats.iter().any(|format| *format != ResponseFormat::Text) {
+        return Err(ArgumentsError::IncompatibleArguments(
+            "--echo".into(),
+            "formats other than text".into(),
+        ));
+    }
+
+    if result.json_extra.is_some() && !any_format(&|format| *format == ResponseFormat::Json) {
+        return Err(ArgumentsError::IncompatibleArguments(
+            "--json-extra".into(),
+            "formats other than json".into(),
+        ));
+    }
+
+    // Addresses are parsed once all the arguments were seen, so --default-port applies regardless of its position.
+    for (arg, arg2, format) in tcp_pending {
+        if let Some(path) = arg2.trim().strip_prefix("unix:") {
+            parse_unix_path_arg(&mut result.unix_paths, arg, path)?;
+            continue;
+        }
+
+        let start = result.tcp_addresses.len();
+        parse_socket_arg(&mut result.tcp_addresses, arg, Some(arg2), default_port, default_scope)
+            .map_err(ArgumentsError::TcpListenError)?;
+        if let Some(format) = format {
+            for addr in &result.tcp_addresses[start..] {
+                result.tcp_address_formats.insert(*addr, format.clone());
+            }
+        }
+    }
+
+    // Unix domain sockets are only sent the plain response, as there's no TCP connection nor client address to use.
+    if !result.unix_paths.is_empty() {
+        let tcp_only = [
+            (result.http, "--http"),
+            (result.length_framed, "--length-framed"),
+            (result.roundtrip, "--roundtrip"),
+            (result.proxy_protocol, "--proxy-protocol"),
+            (result.split_response.is_some(), "--split-response"),
+            (result.tls_cert.is_some(), "--tls-cert"),
+            (result.tls_pkcs12.is_some(), "--tls-pkcs12"),
+            (
+                matches!(result.tcp_format, ResponseFormat::IpBytes | ResponseFormat::IpPortBytes),
+                "the ip-bytes formats",
+            ),
+        ];
+        if let Some((_, arg)) = tcp_only.into_iter().find(|(used, _)| *used) {
+            return Err(ArgumentsError::IncompatibleArguments("unix:PATH sockets".into(), arg.into()));
+        }
+    }
+
+    for (arg, arg2, format) in udp_pending {
+        let start = result.udp_addresses.len();
+        parse_socket_arg(&mut result.udp_addresses, arg, Some(arg2), default_port, default_scope)
+            .map_err(ArgumentsError::UdpListenError)?;
+        if let Some(AddressFormat::Format(format)) = format {
+            for addr in &result.udp_addresses[start..] {
+                result.udp_address_formats.insert(*addr, format.clone());
+            }
+        }
+    }
+
     if !tcp_specified {
+        result.tcp_addresses_default = true;
         result
             .tcp_addresses
-            .push(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, DEFAULT_PORT, 0, 0)));
+            .push(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, default_port, 0, 0)));
         result
             .tcp_addresses
-            .push(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)));
+            .push(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, default_port)));
     }
 
     if !udp_specified {
+        result.udp_addresses_default = true;
         result
             .udp_addresses
-            .push(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, DEFAULT_PORT, 0, 0)));
+            .push(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, default_port, 0, 0)));
         result
             .udp_addresses
-            .push(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)));
+            .push(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, default_port)));
     }
 
-    if result.udp_addresses.is_empty() && result.tcp_addresses.is_empty() {
+    if result.udp_addresses.is_empty()
+        && result.tcp_addresses.is_empty()
+        && result.tcp_fds.is_empty()
+        && result.unix_paths.is_empty()
+        && result.udp_fds.is_empty()
+    {
         return Err(ArgumentsError::NoSocketsSpecified);
     }
 
-    Ok(ArgumentsRequest::Run(result))
+    let udp_sockets_per_address = 1 + result.udp_response_ports.len();
+    let socket_count = result.tcp_addresses.len()
+        + result.tcp_fds.len()
+        + result.unix_paths.len()
+        + (result.udp_addresses.len() * result.udp_shards.get() + result.udp_fds.len()) * udp_sockets_per_address;
+    if socket_count > max_addresses {
+        return Err(ArgumentsError::TooManyAddresses(socket_count, max_addresses));
+    }
+
+    Ok(ArgumentsRequest::Run(Box::new(result)))
+}
+
+/// Finds the error format requested in the arguments without fully parsing them, so errors found while parsing
+/// can still be reported in the requested format. Invalid values are ignored.
+pub fn find_error_format<T>(args: T) -> ErrorFormat
+where
+    T: Iterator<Item = String>,
+{
+    let mut error_format = ErrorFormat::Text;
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        if arg.eq_ignore_ascii_case("--error-format") {
+            if let Some(Ok(format)) = args.peek().map(|s| s.trim().parse()) {
+                error_format = format;
+            }
+        }
+    }
+
+    error_format
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_run(args: &[&str]) -> StartupArguments {
+        match parse_args_from(args) {
+            Ok(ArgumentsRequest::Run(startup_args)) => *startup_args,
+            other => panic!("expected to run the server, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn defaults_to_both_protocols_on_all_interfaces() {
+        let startup_args = parse_run(&[]);
+        let expected: Vec<SocketAddr> = vec!["[::]:6969".parse().unwrap(), "0.0.0.0:6969".parse().unwrap()];
+        assert_eq!(startup_args.tcp_addresses, expected);
+        assert_eq!(startup_args.udp_addresses, expected);
+        assert!(startup_args.tcp_addresses_default && startup_args.udp_addresses_default);
+        assert_eq!(startup_args.tcp_format, ResponseFormat::Text);
+    }
+
+    #[test]
+    fn default_port_applies_regardless_of_order() {
+        let startup_args = parse_run(&["-t", "127.0.0.1", "--default-port", "7000", "-u", "-"]);
+        assert_eq!(startup_args.tcp_addresses, vec!["127.0.0.1:7000".parse::<SocketAddr>().unwrap()]);
+        assert!(startup_args.udp_addresses.is_empty());
+        assert!(!startup_args.tcp_addresses_default);
+    }
+
+    #[test]
+    fn parses_per_address_formats() {
+        let startup_args = parse_run(&[
+            "-t",
+            "127.0.0.1:80=http",
+            "-t",
+            "[::1]:6969=JSON",
+            "-t",
+            "127.0.0.1:7000",
+            "-u",
+            "127.0.0.1=ip-bytes",
+        ]);
+        let tcp_formats = &startup_args.tcp_address_formats;
+        assert_eq!(tcp_formats.len(), 2);
+        assert_eq!(tcp_formats[&"127.0.0.1:80".parse().unwrap()], AddressFormat::Http);
+        assert_eq!(
+            tcp_formats[&"[::1]:6969".parse().unwrap()],
+            AddressFormat::Format(ResponseFormat::Json)
+        );
+        assert_eq!(
+            startup_args.udp_address_formats[&"127.0.0.1:6969".parse().unwrap()],
+            ResponseFormat::IpBytes
+        );
+        assert_eq!(startup_args.tcp_format, ResponseFormat::Text);
+
+        assert_eq!(
+            parse_args_from(&["-t", "127.0.0.1=yaml"]).err(),
+            Some(ArgumentsError::InvalidValue("-t".into(), "127.0.0.1=yaml".into()))
+        );
+        assert_eq!(
+            parse_args_from(&["-u", "127.0.0.1=http"]).err(),
+            Some(ArgumentsError::InvalidValue("-u".into(), "127.0.0.1=http".into()))
+        );
+        assert_eq!(
+            parse_args_from(&["-t", "127.0.0.1=http", "--roundtrip"]).err(),
+            Some(ArgumentsError::IncompatibleArguments("--http".into(), "--roundtrip".into()))
+        );
+    }
+
+    #[test]
+    fn http_options_require_http() {
+        assert_eq!(
+            parse_args_from(&["--http-headers"]).unwrap_err(),
+            ArgumentsError::RequiresArgument("--http-headers".into(), "--http".into())
+        );
+        assert!(parse_run(&["--http", "--http-headers"]).http_headers);
+        assert!(parse_run(&["-t", "127.0.0.1:80=http", "--http-headers"]).http_headers);
+        assert_eq!(
+            parse_args_from(&["--http-chunked"]).unwrap_err(),
+            ArgumentsError::RequiresArgument("--http-chunked".into(), "--http".into())
+        );
+    }
+
+    #[test]
+    fn parses_json_numeric_port() {
+        assert!(parse_run(&[]).json_numeric_port);
+        assert!(!parse_run(&["--json", "--json-numeric-port", "false"]).json_numeric_port);
+        assert!(matches!(
+            parse_args_from(&["--json-numeric-port", "string"]),
+            Err(ArgumentsError::InvalidValue(_, _))
+        ));
+    }
+
+    #[test]
+    fn caps_the_number_of_bound_sockets() {
+        let args = ["-t", "-", "-u", "127.0.0.1:7000", "--udp-response-ports", "7001,7002,7003"];
+        assert_eq!(parse_run(&args).udp_response_ports.len(), 3);
+        assert_eq!(
+            parse_args_from(&[&args[..], &["--max-addresses", "3"]].concat()).unwrap_err(),
+            ArgumentsError::TooManyAddresses(4, 3)
+        );
+
+        let ports = (7001..=9000).map(|port| port.to_string()).collect::<Vec<_>>().join(",");
+        assert_eq!(
+            parse_args_from(&["-t", "-", "-u", "127.0.0.1:7000", "--udp-response-ports", &ports]).unwrap_err(),
+            ArgumentsError::TooManyAddresses(2001, DEFAULT_MAX_ADDRESSES)
+        );
+    }
+
+    #[test]
+    fn json_extra_is_merged_into_json_responses() {
+        let startup_args = parse_run(&["--json", "--json-extra", r#"{"region":"us-east","tags":[1,2],"ip":"spoofed"}"#]);
+        let client = utils::ClientAddress::new("192.0.2.1:1234".parse().unwrap(), None);
+        let response = utils::format_json_response("tcp", &client, 5, true, startup_args.json_extra.as_deref());
+
+        let value: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(value["region"], "us-east");
+        assert_eq!(value["tags"], serde_json::json!([1, 2]));
+        assert_eq!(value["ip"], "192.0.2.1");
+        assert_eq!(value["count"], 5);
+
+        assert!(matches!(
+            parse_args_from(&["--json", "--json-extra", "[1,2]"]),
+            Err(ArgumentsError::InvalidValue(_, _))
+        ));
+    }
+
+    #[test]
+    fn default_scope_applies_to_link_local_addresses() {
+        let startup_args = parse_run(&[
+            "--default-scope",
+            "3",
+            "-t",
+            "[fe80::1]",
+            "-t",
+            "[fe80::2%5]",
+            "-t",
+            "[2001:db8::1]",
+            "-u",
+            "-",
+        ]);
+        let scopes: Vec<u32> = startup_args
+            .tcp_addresses
+            .iter()
+            .map(|addr| match addr {
+                SocketAddr::V6(addr) => addr.scope_id(),
+                SocketAddr::V4(_) => panic!("expected an IPv6 address, got {addr}"),
+            })
+            .collect();
+        assert_eq!(scopes, [3, 5, 0]);
+
+        assert!(matches!(
+            parse_args_from(&["--default-scope", "no-such-interface0"]),
+            Err(ArgumentsError::InvalidValue(_, _))
+        ));
+    }
+
+    #[test]
+    fn disabling_both_protocols_is_an_error() {
+        assert_eq!(
+            parse_args_from(&["-t", "-", "-u", "-"]).unwrap_err(),
+            ArgumentsError::NoSocketsSpecified
+        );
+    }
+
+    #[test]
+    fn format_can_be_overridden_per_protocol() {
+        let startup_args = parse_run(&["--format", "json", "--format-udp", "ip-port-bytes"]);
+        assert_eq!(startup_args.tcp_format, ResponseFormat::Json);
+        assert_eq!(startup_args.udp_format, ResponseFormat::IpPortBytes);
+
+        let startup_args = parse_run(&["--format", "{ip}:{port}"]);
+        assert_eq!(
+            startup_args.tcp_format,
+            ResponseFormat::Template(ResponseTemplate::parse("{ip}:{port}").unwrap())
+        );
+    }
+
+    #[test]
+    fn reports_invalid_arguments() {
+        assert_eq!(
+            parse_args_from(&["--bogus"]).unwrap_err(),
+            ArgumentsError::UnknownArgument("--bogus".into())
+        );
+        assert_eq!(
+            parse_args_from(&["--format", "{nope}"]).unwrap_err(),
+            ArgumentsError::UnknownPlaceholder("--format".into(), "nope".into())
+        );
+        assert_eq!(
+            parse_args_from(&["--unbind-idle", "0"]).unwrap_err(),
+            ArgumentsError::InvalidValue("--unbind-idle".into(), "0".into())
+        );
+        assert_eq!(
+            parse_args_from(&["--hash-ip", "--format", "ip-bytes"]).unwrap_err(),
+            ArgumentsError::IncompatibleArguments("--hash-ip".into(), "the ip-bytes formats".into())
+        );
+    }
+
+    #[test]
+    fn parses_hidden_inject_errors() {
+        assert_eq!(parse_run(&[]).inject_errors, 0);
+        assert_eq!(parse_run(&["--inject-errors", "3"]).inject_errors, 3);
+    }
+
+    #[test]
+    fn echo_is_only_appended_to_text() {
+        assert_eq!(parse_run(&["--echo"]).echo, Some(EchoMode::Append));
+        assert_eq!(parse_run(&["--echo-only", "--format", "json"]).echo, Some(EchoMode::Replace));
+        assert_eq!(
+            parse_args_from(&["--echo", "--format-udp", "json"]).unwrap_err(),
+            ArgumentsError::IncompatibleArguments("--echo".into(), "formats other than text".into())
+        );
+    }
+
+    #[test]
+    fn parses_body_file_as_template() {
+        let path = std::env::temp_dir().join(format!("whereyoufrom-body-test-{}.html", std::process::id()));
+        let path_arg = path.to_str().unwrap();
+
+        fs::write(&path, "<p>{ip}:{port}</p>").unwrap();
+        let startup_args = parse_run(&["--body-file", path_arg]);
+        assert_eq!(
+            startup_args.body_template,
+            Some(ResponseTemplate::parse("<p>{ip}:{port}</p>").unwrap())
+        );
+        assert_eq!(startup_args.body_content_type, "text/html; charset=utf-8");
+
+        fs::write(&path, "<p>{nope}</p>").unwrap();
+        assert_eq!(
+            parse_args_from(&["--body-file", path_arg]).unwrap_err(),
+            ArgumentsError::UnknownPlaceholder("--body-file".into(), "nope".into())
+        );
+
+        fs::write(&path, "{ip_count}").unwrap();
+        assert_eq!(
+            parse_args_from(&["--body-file", path_arg]).unwrap_err(),
+            ArgumentsError::RequiresArgument("The {ip_count} placeholder".into(), "--track-per-ip".into())
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn help_and_version_do_not_run() {
+        assert_eq!(parse_args_from(&["--help"]).unwrap(), ArgumentsRequest::Help);
+        assert_eq!(parse_args_from(&["-V"]).unwrap(), ArgumentsRequest::Version);
+    }
+
+    #[test]
+    fn full_message_requires_rejecting_connections() {
+        let startup_args = parse_run(&["--max-connections", "2", "--full-message", "busy"]);
+        assert_eq!(startup_args.full_message.as_deref(), Some("busy"));
+        assert_eq!(
+            parse_args_from(&["--full-message", "busy"]).unwrap_err(),
+            ArgumentsError::RequiresArgument("--full-message".into(), "--max-connections".into())
+        );
+        assert_eq!(
+            parse_args_from(&["--max-connections", "2", "--when-full", "queue", "--full-message", "busy"]).unwrap_err(),
+            ArgumentsError::IncompatibleArguments("--full-message".into(), "--when-full queue".into())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_sockets_reject_tcp_only_options() {
+        assert_eq!(
+            parse_args_from(&["-t", "unix:/tmp/whereyoufrom.sock", "--http"]).unwrap_err(),
+            ArgumentsError::IncompatibleArguments("unix:PATH sockets".into(), "--http".into())
+        );
+        assert_eq!(
+            parse_args_from(&["-t", "unix:/tmp/whereyoufrom.sock", "--format-tcp", "ip-bytes"]).unwrap_err(),
+            ArgumentsError::IncompatibleArguments("unix:PATH sockets".into(), "the ip-bytes formats".into())
+        );
+        assert!(parse_args_from(&["-t", "unix:/tmp/whereyoufrom.sock", "--max-connections", "1"]).is_ok());
+    }
+
+    #[test]
+    fn trust_forwarded_requires_trusted_proxy() {
+        assert_eq!(
+            parse_args_from(&["--http", "--trust-forwarded"]).unwrap_err(),
+            ArgumentsError::RequiresArgument("--trust-forwarded".into(), "--trusted-proxy".into())
+        );
+        assert!(parse_args_from(&["--http", "--trust-forwarded", "--trusted-proxy", "127.0.0.0/8"]).is_ok());
+    }
+
+    #[test]
+    fn parses_tls_pkcs12() {
+        if !cfg!(feature = "tls") {
+            assert_eq!(
+                parse_args_from(&["--tls-pkcs12", "identity.p12"]).unwrap_err(),
+                ArgumentsError::NotSupported("--tls-pkcs12".into())
+            );
+            return;
+        }
+
+        let startup_args = parse_run(&["--tls-pkcs12", "identity.p12", "--tls-pkcs12-password", "hunter2"]);
+        assert_eq!(startup_args.tls_pkcs12.as_deref(), Some("identity.p12"));
+        assert_eq!(startup_args.tls_pkcs12_password.as_deref(), Some("hunter2"));
+
+        assert_eq!(
+            parse_args_from(&["--tls-pkcs12", "identity.p12", "--tls-cert", "cert.pem", "--tls-key", "key.pem"]).unwrap_err(),
+            ArgumentsError::IncompatibleArguments("--tls-pkcs12".into(), "--tls-cert".into())
+        );
+        assert_eq!(
+            parse_args_from(&["--tls-pkcs12-password", "hunter2"]).unwrap_err(),
+            ArgumentsError::RequiresArgument("--tls-pkcs12-password".into(), "--tls-pkcs12".into())
+        );
+    }
+
+    #[test]
+    fn tls_cipher_suites_require_tls() {
+        if !cfg!(feature = "tls") {
+            return;
+        }
+
+        let startup_args = parse_run(&[
+            "--tls-pkcs12",
+            "identity.p12",
+            "--tls-cipher-suites",
+            "TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384",
+        ]);
+        assert_eq!(
+            startup_args.tls_cipher_suites,
+            ["TLS13_AES_128_GCM_SHA256", "TLS13_AES_256_GCM_SHA384"]
+        );
+        assert_eq!(
+            parse_args_from(&["--tls-cipher-suites", "TLS13_AES_128_GCM_SHA256"]).unwrap_err(),
+            ArgumentsError::RequiresArgument("--tls-cipher-suites".into(), "--tls-cert".into())
+        );
+    }
+
+    #[test]
+    fn parses_min_tls_version() {
+        if !cfg!(feature = "tls") {
+            return;
+        }
+
+        assert_eq!(parse_run(&["--tls-pkcs12", "identity.p12"]).min_tls_version, None);
+        let startup_args = parse_run(&["--tls-pkcs12", "identity.p12", "--min-tls-version", "1.3"]);
+        assert_eq!(startup_args.min_tls_version, Some(TlsVersion::Tls13));
+        assert_eq!(
+            parse_args_from(&["--tls-pkcs12", "identity.p12", "--min-tls-version", "1.1"]).unwrap_err(),
+            ArgumentsError::InvalidValue("--min-tls-version".into(), "1.1".into())
+        );
+        assert_eq!(
+            parse_args_from(&["--min-tls-version", "1.3"]).unwrap_err(),
+            ArgumentsError::RequiresArgument("--min-tls-version".into(), "--tls-cert".into())
+        );
+    }
 }
@@ -0,0 +1,80 @@
+use std::{
+    fs::File,
+    io::{self, LineWriter, Write},
+    net::SocketAddr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const HEADER: &str = "timestamp,proto,remote_ip,remote_port,counter,bytes";
+
+/// Writes a CSV row for each served request to a file, for `--csv-log`. Rows are flushed as they're written, so the
+/// file can be followed while the server runs.
+pub struct CsvLog {
+    writer: Mutex<LineWriter<File>>,
+}
+
+impl CsvLog {
+    /// Creates the file at the given path, truncating it if it exists, and writes the header row.
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut writer = LineWriter::new(File::create(path)?);
+        writeln!(writer, "{HEADER}")?;
+        Ok(CsvLog {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Writes a row for a request from `remote_address`, where `bytes` is the size of the response sent. Requests to
+    /// Unix domain sockets have no remote address, so its columns are left empty.
+    pub fn record(&self, protocol: &str, remote_address: Option<SocketAddr>, counter: u64, bytes: usize) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let (ip, port) = match remote_address {
+            Some(remote_address) => (remote_address.ip().to_string(), remote_address.port().to_string()),
+            None => (String::new(), String::new()),
+        };
+        writeln!(
+            self.writer.lock().unwrap(),
+            "{timestamp:.3},{},{},{port},{counter},{bytes}",
+            escape_csv(protocol),
+            escape_csv(&ip),
+        )
+    }
+}
+
+/// Quotes a CSV field if it contains a comma, quote or line break, doubling any quotes in it.
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_header_and_rows() {
+        let path = std::env::temp_dir().join(format!("whereyoufrom-csvlog-test-{}.csv", std::process::id()));
+        let csv_log = CsvLog::create(path.to_str().unwrap()).unwrap();
+        csv_log.record("tcp", Some("192.0.2.1:5678".parse().unwrap()), 1, 42).unwrap();
+        csv_log.record("unix", None, 2, 7).unwrap();
+        drop(csv_log);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], HEADER);
+        assert!(lines[1].ends_with(",tcp,192.0.2.1,5678,1,42"), "{}", lines[1]);
+        assert!(lines[2].ends_with(",unix,,,2,7"), "{}", lines[2]);
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn escapes_fields() {
+        assert_eq!(escape_csv("tcp"), "tcp");
+        assert_eq!(escape_csv("a,b"), "\"a,b\"");
+        assert_eq!(escape_csv("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    fmt::{self, Write},
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The largest HTTP request head, the request line and headers, accepted from a client with `--http`.
+pub const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+
+/// The longest User-Agent kept from a request, in bytes. Longer ones are cut short.
+pub const MAX_USER_AGENT_LEN: usize = 256;
+
+/// The largest chunk a body is split into with `--http-chunked`.
+const CHUNK_SIZE: usize = 256;
+
+/// The response sent to requests with a method other than GET or HEAD.
+pub const METHOD_NOT_ALLOWED_RESPONSE: &[u8] =
+    b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// The parts of an HTTP request head needed to answer it.
+pub struct RequestHead {
+    pub method: String,
+    /// Whether the client wants the connection kept open for further requests after the response.
+    pub keep_alive: bool,
+    /// Whether the client can receive a chunked body, which HTTP/1.0 clients can't.
+    pub accepts_chunked: bool,
+    /// The addresses proxies forwarded the request for, from the `Forwarded` header or else `X-Forwarded-For`, with
+    /// the original client first. Addresses that couldn't be parsed, such as obfuscated ones, are `None`.
+    pub forwarded_for: Vec<Option<SocketAddr>>,
+    /// The client's `User-Agent` header, with anything but printable ASCII characters removed and cut short to
+    /// [`MAX_USER_AGENT_LEN`] bytes.
+    pub user_agent: Option<String>,
+}
+
+/// An error while reading an HTTP request head.
+pub enum RequestError {
+    Io(io::Error),
+    TooLarge,
+    Malformed,
+}
+
+impl RequestError {
+    /// The status to respond to the client with, if the error is the client's fault.
+    pub fn status(&self) -> Option<&'static str> {
+        match self {
+            Self::Io(_) => None,
+            Self::TooLarge => Some("431 Request Header Fields Too Large"),
+            Self::Malformed => Some("400 Bad Request"),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => error.fmt(f),
+            Self::TooLarge => write!(f, "request head larger than {MAX_REQUEST_HEAD_SIZE} bytes"),
+            Self::Malformed => write!(f, "malformed request head"),
+        }
+    }
+}
+
+/// Reads the next request head from a stream, keeping any bytes read past it in `buf` for the next call. Returns
+/// `None` if the client closed the connection before starting another request.
+pub async fn read_request_head<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut Vec<u8>) -> Result<Option<RequestHead>, RequestError> {
+    let head_len = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+
+        if buf.len() >= MAX_REQUEST_HEAD_SIZE {
+            return Err(RequestError::TooLarge);
+        }
+
+        let mut chunk = [0u8; 1024];
+        let len = stream.read(&mut chunk).await.map_err(RequestError::Io)?;
+        if len == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(RequestError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    };
+
+    if head_len > MAX_REQUEST_HEAD_SIZE {
+        return Err(RequestError::TooLarge);
+    }
+
+    let head = parse_request_head(&buf[..head_len]);
+    buf.drain(..head_len);
+    head.map(Some)
+}
+
+fn parse_request_head(head: &[u8]) -> Result<RequestHead, RequestError> {
+    let head = std::str::from_utf8(head).map_err(|_| RequestError::Malformed)?;
+    let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+
+    let request_line = lines.next().ok_or(RequestError::Malformed)?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(_target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(RequestError::Malformed);
+    };
+
+    let accepts_chunked = version == "HTTP/1.1";
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(RequestError::Malformed),
+    };
+
+    let mut has_body = false;
+    let mut forwarded = Vec::new();
+    let mut x_forwarded_for = Vec::new();
+    let mut user_agent = None;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(RequestError::Malformed)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+        } else if (name.eq_ignore_ascii_case("content-length") && value != "0") || name.eq_ignore_ascii_case("transfer-encoding") {
+            has_body = true;
+        } else if name.eq_ignore_ascii_case("forwarded") {
+            for element in value.split(',') {
+                let for_value = element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then_some(value)
+                });
+                forwarded.push(for_value.and_then(parse_forwarded_address));
+            }
+        } else if name.eq_ignore_ascii_case("x-forwarded-for") {
+            x_forwarded_for.extend(value.split(',').map(parse_forwarded_address));
+        } else if name.eq_ignore_ascii_case("user-agent") {
+            let printable = value.chars().filter(|c| matches!(c, ' '..='~'));
+            user_agent = Some(printable.take(MAX_USER_AGENT_LEN).collect());
+        }
+    }
+
+    // Request bodies aren't read, so the connection can't be reused once one is sent.
+    Ok(RequestHead {
+        method: method.to_string(),
+        keep_alive: keep_alive && !has_body,
+        accepts_chunked,
+        forwarded_for: if forwarded.is_empty() { x_forwarded_for } else { forwarded },
+        user_agent,
+    })
+}
+
+/// Parses an address from a `Forwarded` or `X-Forwarded-For` header, which may be quoted and may have a port. The port
+/// is 0 if there is none.
+fn parse_forwarded_address(value: &str) -> Option<SocketAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(address) = value.parse() {
+        return Some(address);
+    }
+
+    let ip: IpAddr = value.trim_start_matches('[').trim_end_matches(']').parse().ok()?;
+    Some(SocketAddr::new(ip, 0))
+}
+
+/// Gets the Content-Type to serve a file with in `--http` mode, from its extension. Unknown extensions are served as
+/// plain text, as the file is a template that's expected to hold text.
+pub fn content_type_for_path(path: &str) -> &'static str {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "csv" => "text/csv; charset=utf-8",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+/// Formats an HTTP response with the given status, additional headers and body, leaving the body out if
+/// `include_body` is false, as is done for HEAD requests. The header values must not contain line breaks. If
+/// `chunked` is true, the body is sent with chunked transfer encoding instead of with a Content-Length.
+pub fn format_response(
+    status: &str,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+    keep_alive: bool,
+    include_body: bool,
+    chunked: bool,
+) -> Vec<u8> {
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let mut head = format!("HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n");
+    match chunked {
+        true => head.push_str("Transfer-Encoding: chunked\r\n"),
+        false => {
+            let _ = write!(head, "Content-Length: {}\r\n", body.len());
+        }
+    }
+    for (name, value) in headers {
+        let _ = write!(head, "{name}: {value}\r\n");
+    }
+    let _ = write!(head, "Connection: {connection}\r\n\r\n");
+
+    let mut response = head.into_bytes();
+
+    if include_body && chunked {
+        for chunk in body.chunks(CHUNK_SIZE) {
+            response.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            response.extend_from_slice(chunk);
+            response.extend_from_slice(b"\r\n");
+        }
+        response.extend_from_slice(b"0\r\n\r\n");
+    } else if include_body {
+        response.extend_from_slice(body);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_head(request: &[u8]) -> Result<Option<RequestHead>, RequestError> {
+        let mut stream = request;
+        read_request_head(&mut stream, &mut Vec::new()).await
+    }
+
+    #[tokio::test]
+    async fn reads_keep_alive_from_version_and_connection_header() {
+        let head = read_head(b"GET / HTTP/1.1\r\nHost: example\r\n\r\n").await.ok().flatten().unwrap();
+        assert_eq!(head.method, "GET");
+        assert!(head.keep_alive);
+
+        let head = read_head(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+        assert!(!head.keep_alive);
+
+        let head = read_head(b"HEAD / HTTP/1.0\r\n\r\n").await.ok().flatten().unwrap();
+        assert!(!head.keep_alive);
+
+        let head = read_head(b"HEAD / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n")
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+        assert!(head.keep_alive);
+
+        let head = read_head(b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc")
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+        assert!(!head.keep_alive);
+    }
+
+    #[tokio::test]
+    async fn keeps_pipelined_requests_for_the_next_read() {
+        let mut stream: &[u8] = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let mut buf = Vec::new();
+        assert!(read_request_head(&mut stream, &mut buf).await.ok().flatten().is_some());
+        assert!(read_request_head(&mut stream, &mut buf).await.ok().flatten().is_some());
+        assert!(matches!(read_request_head(&mut stream, &mut buf).await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn reads_forwarded_addresses() {
+        let head = read_head(b"GET / HTTP/1.1\r\nForwarded: for=192.0.2.1;proto=http, for=\"[2001:db8::1]:8080\", for=_hidden\r\n\r\n")
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+        assert_eq!(
+            head.forwarded_for,
+            vec![
+                Some("192.0.2.1:0".parse().unwrap()),
+                Some("[2001:db8::1]:8080".parse().unwrap()),
+                None
+            ]
+        );
+
+        let head = read_head(b"GET / HTTP/1.1\r\nX-Forwarded-For: 192.0.2.1, 198.51.100.1:1234\r\n\r\n")
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+        assert_eq!(
+            head.forwarded_for,
+            vec![Some("192.0.2.1:0".parse().unwrap()), Some("198.51.100.1:1234".parse().unwrap())]
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_sanitized_user_agent() {
+        let head = read_head(b"GET / HTTP/1.1\r\n\r\n").await.ok().flatten().unwrap();
+        assert_eq!(head.user_agent, None);
+
+        let head = read_head("GET / HTTP/1.1\r\nUser-Agent: curl/8.0 \u{7}caf\u{e9}\tx\r\n\r\n".as_bytes())
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+        assert_eq!(head.user_agent.as_deref(), Some("curl/8.0 cafx"));
+
+        let request = format!("GET / HTTP/1.1\r\nUser-Agent: {}\r\n\r\n", "a".repeat(1000));
+        let head = read_head(request.as_bytes()).await.ok().flatten().unwrap();
+        assert_eq!(head.user_agent.unwrap().len(), MAX_USER_AGENT_LEN);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_and_oversized_requests() {
+        assert!(matches!(read_head(b"GET /\r\n\r\n").await, Err(RequestError::Malformed)));
+        assert!(matches!(read_head(b"GET / HTTP/2\r\n\r\n").await, Err(RequestError::Malformed)));
+        assert!(matches!(
+            read_head(b"GET / HTTP/1.1\r\nNo colon\r\n\r\n").await,
+            Err(RequestError::Malformed)
+        ));
+        assert!(matches!(read_head(b"").await, Ok(None)));
+        assert!(matches!(read_head(b"GET / HTTP/1.1\r\n").await, Err(RequestError::Io(_))));
+
+        let mut request = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.resize(MAX_REQUEST_HEAD_SIZE + 10, b'a');
+        request.extend_from_slice(b"\r\n\r\n");
+        assert!(matches!(read_head(&request).await, Err(RequestError::TooLarge)));
+    }
+
+    #[test]
+    fn formats_response_without_body_for_head() {
+        assert_eq!(
+            format_response("200 OK", "text/plain", &[], b"hi", true, true, false),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nConnection: keep-alive\r\n\r\nhi"
+        );
+        assert_eq!(
+            format_response("200 OK", "text/plain", &[], b"hi", false, false, false),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn content_type_comes_from_extension() {
+        assert_eq!(content_type_for_path("/srv/index.HTML"), "text/html; charset=utf-8");
+        assert_eq!(content_type_for_path("whoami.json"), "application/json");
+        assert_eq!(content_type_for_path("body"), "text/plain; charset=utf-8");
+        assert_eq!(content_type_for_path("body.unknown"), "text/plain; charset=utf-8");
+    }
+
+    /// Decodes a chunked body, checking that every chunk is well-formed and that it ends with the zero-length chunk.
+    fn decode_chunked(mut body: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        loop {
+            let line_end = body.windows(2).position(|w| w == b"\r\n").expect("chunk size line not terminated");
+            let size = usize::from_str_radix(std::str::from_utf8(&body[..line_end]).unwrap(), 16).unwrap();
+            body = &body[(line_end + 2)..];
+            assert_eq!(&body[size..(size + 2)], b"\r\n", "chunk not terminated");
+            decoded.extend_from_slice(&body[..size]);
+            body = &body[(size + 2)..];
+            if size == 0 {
+                assert!(body.is_empty(), "data after the last chunk");
+                return decoded;
+            }
+        }
+    }
+
+    #[test]
+    fn formats_chunked_body() {
+        let body: Vec<u8> = (0..600).map(|i| b'a' + (i % 26) as u8).collect();
+        let response = format_response("200 OK", "text/plain", &[], &body, false, true, true);
+        let head_len = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = std::str::from_utf8(&response[..head_len]).unwrap();
+        assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"), "{head}");
+        assert!(!head.contains("Content-Length"), "{head}");
+        assert!(response[head_len..].starts_with(b"100\r\n"));
+        assert_eq!(decode_chunked(&response[head_len..]), body);
+
+        let response = format_response("200 OK", "text/plain", &[], b"", false, true, true);
+        assert!(response.ends_with(b"\r\n\r\n0\r\n\r\n"));
+
+        let response = format_response("200 OK", "text/plain", &[], b"hi", false, false, true);
+        assert!(response.ends_with(b"Connection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn formats_additional_headers() {
+        let headers = [("X-Client-IP", "192.0.2.1".to_string()), ("X-Client-Port", "1234".to_string())];
+        assert_eq!(
+            format_response("200 OK", "text/plain", &headers, b"hi", false, true, false),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nX-Client-IP: 192.0.2.1\r\nX-Client-Port: 1234\r\nConnection: close\r\n\r\nhi"
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::lru::LruMap;
+
+/// The most client IP addresses tracked at once for `--track-per-ip` and `--first-seen-message`.
+pub const MAX_TRACKED_IPS: usize = 65536;
+
+/// How often expired addresses are removed from `SeenIps`.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Counts the requests received from each client IP address, for `--track-per-ip`.
+///
+/// At most `MAX_TRACKED_IPS` addresses are tracked at once, evicting the least recently seen address when a new one
+/// arrives, so flooding from many (possibly spoofed) sources can't grow the table without bound.
+pub struct IpTracker {
+    counts: LruMap<IpAddr, u64>,
+}
+
+impl IpTracker {
+    pub fn new() -> Self {
+        IpTracker {
+            counts: LruMap::new(MAX_TRACKED_IPS),
+        }
+    }
+
+    /// Records a request from the given IP address, returning how many requests have been seen from it including this
+    /// one. IPv4-mapped IPv6 addresses are counted as IPv4, so a client gets the same count through any socket.
+    pub fn record(&mut self, ip: IpAddr) -> u64 {
+        let count = self.counts.get_or_insert_with(ip.to_canonical(), || 0);
+        *count += 1;
+        *count
+    }
+}
+
+/// Remembers which client IP addresses were seen recently, for `--first-seen-message`. An address counts as seen for
+/// `ttl` after its first request, after which its next request counts as a first one again.
+///
+/// Like `IpTracker`, at most `MAX_TRACKED_IPS` addresses are remembered at once, forgetting the oldest when full.
+pub struct SeenIps {
+    ttl: Duration,
+    first_seen: LruMap<IpAddr, Instant>,
+    last_prune: Instant,
+}
+
+impl SeenIps {
+    pub fn new(ttl: Duration) -> Self {
+        SeenIps {
+            ttl,
+            first_seen: LruMap::new(MAX_TRACKED_IPS),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Records a request from the given IP address, returning whether it's the first one within the TTL.
+    pub fn record(&mut self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        if now.saturating_duration_since(self.last_prune) >= self.ttl.min(PRUNE_INTERVAL) {
+            self.prune(now);
+        }
+
+        // Addresses are only marked as used when (re)inserted, so the least recently used is the oldest first seen.
+        if let Some(first_seen) = self.first_seen.peek(&ip) {
+            if now.saturating_duration_since(*first_seen) < self.ttl {
+                return false;
+            }
+        }
+
+        self.first_seen.insert(ip, now);
+        true
+    }
+
+    /// Forgets the addresses whose TTL has expired.
+    fn prune(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.first_seen
+            .retain(|_, first_seen| now.saturating_duration_since(*first_seen) < ttl);
+        self.last_prune = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_requests_per_address() {
+        let mut tracker = IpTracker::new();
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let second: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_eq!(tracker.record(first), 1);
+        assert_eq!(tracker.record(first), 2);
+        assert_eq!(tracker.record(second), 1);
+        assert_eq!(tracker.record(first), 3);
+    }
+
+    #[test]
+    fn counts_mapped_addresses_as_ipv4() {
+        let mut tracker = IpTracker::new();
+        assert_eq!(tracker.record("192.0.2.1".parse().unwrap()), 1);
+        assert_eq!(tracker.record("::ffff:192.0.2.1".parse().unwrap()), 2);
+    }
+
+    #[test]
+    fn seen_ips_remembers_addresses_within_ttl() {
+        let mut seen_ips = SeenIps::new(Duration::from_secs(3600));
+        assert!(seen_ips.record("192.0.2.1".parse().unwrap()));
+        assert!(!seen_ips.record("192.0.2.1".parse().unwrap()));
+        assert!(!seen_ips.record("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(seen_ips.record("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn seen_ips_forgets_addresses_after_ttl() {
+        let mut seen_ips = SeenIps::new(Duration::ZERO);
+        assert!(seen_ips.record("192.0.2.1".parse().unwrap()));
+        assert!(seen_ips.record("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn seen_ips_forgets_oldest_address_when_full() {
+        let mut seen_ips = SeenIps::new(Duration::from_secs(3600));
+        let first: IpAddr = "10.0.0.0".parse().unwrap();
+        seen_ips.record(first);
+        for i in 1..MAX_TRACKED_IPS as u32 {
+            seen_ips.record(IpAddr::V4((0x0a00_0000 + i).into()));
+        }
+
+        // Seeing the first address again doesn't make it any newer, so it's the one forgotten to make room.
+        assert!(!seen_ips.record(first));
+        seen_ips.record("192.0.2.1".parse().unwrap());
+        assert!(seen_ips.record(first));
+    }
+
+    #[test]
+    fn forgets_least_recently_seen_address_when_full() {
+        let mut tracker = IpTracker::new();
+        let first: IpAddr = "10.0.0.0".parse().unwrap();
+        tracker.record(first);
+        tracker.record(first);
+        for i in 1..MAX_TRACKED_IPS as u32 {
+            tracker.record(IpAddr::V4((0x0a00_0000 + i).into()));
+        }
+
+        // The table is now full, so a new address evicts the first one, which was seen the longest ago.
+        tracker.record("192.0.2.1".parse().unwrap());
+        assert_eq!(tracker.record(first), 1);
+    }
+}
@@ -0,0 +1,45 @@
+//! The argument parsing and servers behind the `whereyoufrom` binary, exposed so other tools can reuse them.
+//!
+//! The servers spawn their tasks with [`tokio::spawn`], so [`run_server`] must be run within a Tokio runtime, which
+//! may be either current-thread or multi-thread. It serves until the shutdown future it's given completes, then
+//! returns once the servers have shut down. If the servers can't start, such as when no sockets could be bound, it
+//! returns a [`ServerError`] instead. The servers log through [`tracing`], so their output goes to whichever
+//! subscriber is installed, such as the one set up by [`logging::init`]. To build the responses with logic of your
+//! own instead of in one of the formats, implement [`response::ResponseProvider`] and run the servers with
+//! [`run_server_with_provider`].
+//!
+//! ```no_run
+//! use whereyoufrom::{run_server, server::break_signal, StartupArguments};
+//!
+//! let mut startup_args = StartupArguments::empty();
+//! startup_args.tcp_addresses.push("127.0.0.1:6969".parse().unwrap());
+//! startup_args.udp_addresses.push("127.0.0.1:6969".parse().unwrap());
+//!
+//! let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+//! if let Err(error) = runtime.block_on(run_server(startup_args, break_signal())) {
+//!     eprintln!("{error}");
+//! }
+//! ```
+
+mod accesslog;
+pub mod args;
+pub mod cidr;
+mod csvlog;
+mod http;
+mod iptracker;
+pub mod logging;
+mod lru;
+mod metrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+mod proxy;
+mod ratelimit;
+pub mod response;
+pub mod server;
+pub mod template;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod utils;
+
+pub use args::{parse_args_from, parse_arguments, ArgumentsError, ArgumentsRequest, StartupArguments};
+pub use server::{run_server, run_server_with_provider, BindFailure, ServerError};
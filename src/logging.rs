@@ -0,0 +1,43 @@
+use std::{fmt, io};
+
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, writer::MakeWriterExt, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+/// Installs a global subscriber that prints events the way the server always has: just the message, with warnings
+/// and errors going to stderr and everything else to stdout.
+///
+/// `--silent` hides everything below warnings, while `--verbose` also shows debug events. Returns an error, leaving
+/// the existing subscriber in place, if one was already installed.
+pub fn init(verbose: bool, silent: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let max_level = if silent {
+        Level::WARN
+    } else if verbose {
+        Level::DEBUG
+    } else {
+        Level::INFO
+    };
+
+    let writer = io::stderr.with_max_level(Level::WARN).or_else(io::stdout);
+    tracing_subscriber::fmt()
+        .with_max_level(max_level)
+        .with_writer(writer)
+        .event_format(MessageOnly)
+        .try_init()
+}
+
+/// Formats an event as only its fields, leaving out the timestamp, level, target and spans.
+struct MessageOnly;
+
+impl<S, N> FormatEvent<S, N> for MessageOnly
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
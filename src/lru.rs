@@ -0,0 +1,145 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+/// A map with a maximum size, which evicts its least recently used entry to make room for a new one when full. Entries
+/// are ordered by a counter bumped on every use, so finding the one to evict takes O(log n) rather than a full scan.
+pub struct LruMap<K, V> {
+    max_len: usize,
+    entries: HashMap<K, (V, u64)>,
+    /// The keys of the entries, ordered by when they were last used.
+    order: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruMap<K, V> {
+    pub fn new(max_len: usize) -> Self {
+        LruMap {
+            max_len,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// The number of entries in the map.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Gets the entry for a key without marking it as used.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    /// Gets the entry for a key, marking it as the most recently used. If there is none, one is inserted with
+    /// `default`, first evicting the least recently used entry if the map is full.
+    pub fn get_or_insert_with(&mut self, key: K, default: impl FnOnce() -> V) -> &mut V {
+        if !self.entries.contains_key(&key) {
+            self.insert(key.clone(), default());
+            return &mut self.entries.get_mut(&key).unwrap().0;
+        }
+
+        self.clock += 1;
+        let (value, last_used) = self.entries.get_mut(&key).unwrap();
+        self.order.remove(last_used);
+        *last_used = self.clock;
+        self.order.insert(self.clock, key);
+        value
+    }
+
+    /// Sets the entry for a key, marking it as the most recently used. If there was none, the least recently used entry
+    /// is evicted first if the map is full.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.clock += 1;
+        match self.entries.get(&key) {
+            Some((_, last_used)) => {
+                self.order.remove(last_used);
+            }
+            None if self.entries.len() >= self.max_len => self.evict_least_recent(),
+            None => {}
+        }
+
+        self.entries.insert(key.clone(), (value, self.clock));
+        self.order.insert(self.clock, key);
+    }
+
+    /// Removes the entry that was least recently used.
+    fn evict_least_recent(&mut self) {
+        if let Some((_, key)) = self.order.pop_first() {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Keeps only the entries for which `f` returns true.
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let order = &mut self.order;
+        self.entries.retain(|key, (value, last_used)| {
+            let keep = f(key, value);
+            if !keep {
+                order.remove(last_used);
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_entry() {
+        let mut map = LruMap::new(2);
+        map.insert("a", 1);
+        map.insert("b", 2);
+        *map.get_or_insert_with("a", || 0) += 10;
+        map.insert("c", 3);
+
+        assert_eq!(map.entries.len(), 2);
+        assert_eq!(*map.get_or_insert_with("c", || 0), 3);
+        assert_eq!(*map.get_or_insert_with("a", || 0), 11);
+        assert_eq!(*map.get_or_insert_with("b", || 0), 0);
+    }
+
+    #[test]
+    fn peek_does_not_mark_as_used() {
+        let mut map = LruMap::new(2);
+        map.insert("a", 1);
+        map.insert("b", 2);
+        assert_eq!(map.peek(&"a"), Some(&1));
+        map.insert("c", 3);
+
+        assert_eq!(map.peek(&"a"), None);
+        assert_eq!(map.peek(&"b"), Some(&2));
+    }
+
+    #[test]
+    fn replacing_an_entry_does_not_evict() {
+        let mut map = LruMap::new(2);
+        map.insert("a", 1);
+        map.insert("b", 2);
+        map.insert("a", 3);
+
+        assert_eq!(map.entries.len(), 2);
+        assert_eq!(*map.get_or_insert_with("b", || 0), 2);
+        assert_eq!(*map.get_or_insert_with("a", || 0), 3);
+    }
+
+    #[test]
+    fn retain_keeps_order_consistent() {
+        let mut map = LruMap::new(2);
+        map.insert("a", 1);
+        map.insert("b", 2);
+        map.retain(|_, value| *value != 1);
+        map.insert("c", 3);
+        map.insert("d", 4);
+
+        assert_eq!(map.entries.len(), 2);
+        assert_eq!(map.order.len(), 2);
+        assert_eq!(*map.get_or_insert_with("c", || 0), 3);
+        assert_eq!(*map.get_or_insert_with("d", || 0), 4);
+    }
+}
@@ -1,17 +1,19 @@
 use std::{env, process::exit};
 
-use tokio::task::LocalSet;
-
-use crate::args::ArgumentsRequest;
-
-mod args;
-mod server;
-mod utils;
+#[cfg(feature = "otlp")]
+use whereyoufrom::otlp;
+use whereyoufrom::{
+    args::{self, ArgumentsRequest, ErrorFormat},
+    logging, server, utils,
+};
 
 fn main() {
     let arguments = match args::parse_arguments(env::args()) {
         Err(err) => {
-            eprintln!("{err}\n\nType 'whereyoufrom --help' for a help menu");
+            match args::find_error_format(env::args()) {
+                ErrorFormat::Text => eprintln!("{err}\n\nType 'whereyoufrom --help' for a help menu"),
+                ErrorFormat::Json => utils::print_json_error("arguments", None, &err.to_string()),
+            }
             exit(1);
         }
         Ok(arguments) => arguments,
@@ -23,16 +25,83 @@ fn main() {
             println!("GPS? Don't need that anymore ⌐■_■");
             return;
         }
+        ArgumentsRequest::Capabilities => {
+            for capability in args::get_capabilities() {
+                println!("{capability}");
+            }
+            return;
+        }
+        ArgumentsRequest::ResolveOnly(addresses) => {
+            for addr in addresses {
+                println!("{addr}");
+            }
+            return;
+        }
         ArgumentsRequest::Help => {
             println!("{}", args::get_help_string());
             return;
         }
-        ArgumentsRequest::Run(startup_args) => startup_args,
+        ArgumentsRequest::Run(startup_args) => *startup_args,
     };
 
-    let maybe_runtime = tokio::runtime::Builder::new_current_thread().enable_all().build();
-    match maybe_runtime {
-        Ok(runtime) => LocalSet::new().block_on(&runtime, server::run_server(startup_args)),
-        Err(err) => eprintln!("Failed to start Tokio runtime: {err}"),
+    // The environment can only be modified safely while there's a single thread, so the sockets passed by systemd socket
+    // activation are taken before the OTLP exporters and the runtime start theirs.
+    #[cfg(unix)]
+    let startup_args = args::StartupArguments {
+        activation_fds: utils::take_systemd_listen_fds(),
+        ..startup_args
+    };
+
+    #[cfg(feature = "otlp")]
+    let otlp_providers = match &startup_args.otlp_endpoint {
+        Some(endpoint) => match otlp::init(endpoint) {
+            Ok(providers) => Some(providers),
+            Err(err) => {
+                match startup_args.error_format {
+                    ErrorFormat::Text => eprintln!("Failed to set up OTLP exporting: {err}"),
+                    ErrorFormat::Json => utils::print_json_error("otlp", None, &format!("Failed to set up OTLP exporting: {err}")),
+                }
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    if let Err(err) = logging::init(startup_args.verbose, startup_args.silent) {
+        eprintln!("Failed to set up logging: {err}");
+    }
+
+    let mut builder = match startup_args.workers {
+        Some(workers) => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(workers.get());
+            builder
+        }
+        None => tokio::runtime::Builder::new_current_thread(),
+    };
+
+    let error_format = startup_args.error_format;
+    let result = match builder.enable_all().build() {
+        Ok(runtime) => runtime.block_on(server::run_server(startup_args, server::break_signal())),
+        Err(err) => {
+            match error_format {
+                ErrorFormat::Text => eprintln!("Failed to start Tokio runtime: {err}"),
+                ErrorFormat::Json => utils::print_json_error("runtime", None, &format!("Failed to start Tokio runtime: {err}")),
+            }
+            exit(1);
+        }
+    };
+
+    #[cfg(feature = "otlp")]
+    if let Some(providers) = otlp_providers {
+        providers.shutdown();
+    }
+
+    if let Err(err) = result {
+        match error_format {
+            ErrorFormat::Text => eprintln!("ERROR! {err}"),
+            ErrorFormat::Json => eprintln!("{}", err.to_json()),
+        }
+        exit(1);
     }
 }
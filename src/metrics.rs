@@ -0,0 +1,162 @@
+use std::{fmt::Write, io, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, error};
+
+/// How long a client of the metrics endpoint has to send its request and receive the response.
+const METRICS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest HTTP request head accepted by the metrics endpoint.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// The counters exposed by the metrics endpoint.
+pub struct MetricsSnapshot {
+    pub tcp_connections: u64,
+    pub udp_packets: u64,
+    pub errors: u64,
+    /// The amount of responses sent, keyed by protocol and response format.
+    pub responses: Vec<((&'static str, &'static str), u64)>,
+}
+
+/// Formats the counters in the Prometheus text exposition format.
+pub fn format_metrics(snapshot: &MetricsSnapshot) -> String {
+    let metrics = [
+        (
+            "whereyoufrom_tcp_connections_total",
+            "Accepted TCP connections.",
+            snapshot.tcp_connections,
+        ),
+        ("whereyoufrom_udp_packets_total", "Received UDP packets.", snapshot.udp_packets),
+        (
+            "whereyoufrom_errors_total",
+            "Errors while accepting, receiving or responding.",
+            snapshot.errors,
+        ),
+    ];
+
+    let mut result = String::new();
+    for (name, help, value) in metrics {
+        let _ = write!(result, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n");
+    }
+
+    let mut responses = snapshot.responses.clone();
+    responses.sort_unstable();
+    let name = "whereyoufrom_responses_total";
+    let _ = write!(
+        result,
+        "# HELP {name} Responses sent, by protocol and format.\n# TYPE {name} counter\n"
+    );
+    for ((proto, format), value) in responses {
+        let _ = writeln!(result, "{name}{{proto=\"{proto}\",format=\"{format}\"}} {value}");
+    }
+
+    result
+}
+
+/// Serves `GET /metrics` over HTTP on the given listener, with the counters returned by `snapshot`. Requests are
+/// handled one at a time, since this is only meant to be scraped periodically.
+pub async fn run_metrics_server(listener: TcpListener, snapshot: impl Fn() -> MetricsSnapshot) {
+    loop {
+        let (mut stream, remote_address) = match listener.accept().await {
+            Ok(t) => t,
+            Err(error) => {
+                error!("Error while accepting from metrics listener: {error}");
+                continue;
+            }
+        };
+
+        debug!("Metrics listener accepted connection from {remote_address}");
+        let result = tokio::time::timeout(METRICS_TIMEOUT, serve_metrics_request(&mut stream, &snapshot)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => debug!("Failed to serve metrics to {remote_address}: {error}"),
+            Err(_) => debug!("Timed out serving metrics to {remote_address}"),
+        }
+    }
+}
+
+async fn serve_metrics_request(stream: &mut TcpStream, snapshot: &impl Fn() -> MetricsSnapshot) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+        }
+
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    let request_line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+
+    let response = match (method, path) {
+        (b"GET", b"/metrics") => {
+            let body = format_metrics(&snapshot());
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        (b"GET", _) => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_counters_in_the_text_format() {
+        let snapshot = MetricsSnapshot {
+            tcp_connections: 3,
+            udp_packets: 5,
+            errors: 1,
+            responses: Vec::new(),
+        };
+
+        let metrics = format_metrics(&snapshot);
+        assert!(metrics.contains("# TYPE whereyoufrom_tcp_connections_total counter\nwhereyoufrom_tcp_connections_total 3\n"));
+        assert!(metrics.contains("# TYPE whereyoufrom_udp_packets_total counter\nwhereyoufrom_udp_packets_total 5\n"));
+        assert!(metrics.contains("# TYPE whereyoufrom_errors_total counter\nwhereyoufrom_errors_total 1\n"));
+        assert!(
+            metrics.lines().all(|line| line.starts_with('#') || line.split(' ').count() == 2),
+            "{metrics}"
+        );
+    }
+
+    #[test]
+    fn labels_responses_by_protocol_and_format() {
+        let snapshot = MetricsSnapshot {
+            tcp_connections: 0,
+            udp_packets: 0,
+            errors: 0,
+            responses: vec![(("udp", "text"), 4), (("tcp", "json"), 2), (("tcp", "http"), 1)],
+        };
+
+        let metrics = format_metrics(&snapshot);
+        let series: Vec<&str> = metrics
+            .lines()
+            .filter(|line| line.starts_with("whereyoufrom_responses_total{"))
+            .collect();
+        assert_eq!(
+            series,
+            [
+                "whereyoufrom_responses_total{proto=\"tcp\",format=\"http\"} 1",
+                "whereyoufrom_responses_total{proto=\"tcp\",format=\"json\"} 2",
+                "whereyoufrom_responses_total{proto=\"udp\",format=\"text\"} 4",
+            ]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::{net::SocketAddr, sync::OnceLock, time::Duration};
+
+use opentelemetry::{
+    global::{self, BoxedSpan},
+    metrics::Counter,
+    trace::{Span, Tracer},
+    KeyValue,
+};
+use opentelemetry_otlp::{ExporterBuildError, MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    metrics::{data::ResourceMetrics, exporter::PushMetricExporter, SdkMeterProvider, Temporality},
+    trace::{SdkTracerProvider, SpanData},
+    Resource,
+};
+
+const INSTRUMENTATION_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// The OpenTelemetry providers exporting to an OTLP collector. These must be kept alive for as long as telemetry
+/// should be exported, and shut down before exiting so pending data is flushed.
+pub struct OtlpProviders {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+struct Instruments {
+    tcp_connections: Counter<u64>,
+    udp_packets: Counter<u64>,
+    errors: Counter<u64>,
+}
+
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+fn instruments() -> &'static Instruments {
+    INSTRUMENTS.get_or_init(|| {
+        let meter = global::meter(INSTRUMENTATION_NAME);
+        Instruments {
+            tcp_connections: meter.u64_counter("whereyoufrom.tcp.connections").build(),
+            udp_packets: meter.u64_counter("whereyoufrom.udp.packets").build(),
+            errors: meter.u64_counter("whereyoufrom.errors").build(),
+        }
+    })
+}
+
+/// Sets up exporting spans and metrics over OTLP/HTTP to the collector at the given base endpoint, such as
+/// `http://localhost:4318`, and installs the providers globally.
+///
+/// Exporting happens on background threads, so this must be called before starting the Tokio runtime.
+pub fn init(endpoint: &str) -> Result<OtlpProviders, ExporterBuildError> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let resource = Resource::builder().with_service_name(INSTRUMENTATION_NAME).build();
+
+    let span_exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{endpoint}/v1/traces"))
+        .build()?;
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_resource(resource.clone())
+        .with_batch_exporter(LoggingSpanExporter(span_exporter))
+        .build();
+
+    let metric_exporter = MetricExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{endpoint}/v1/metrics"))
+        .build()?;
+    let meter_provider = SdkMeterProvider::builder()
+        .with_resource(resource)
+        .with_periodic_exporter(LoggingMetricExporter(metric_exporter))
+        .build();
+
+    global::set_tracer_provider(tracer_provider.clone());
+    global::set_meter_provider(meter_provider.clone());
+
+    Ok(OtlpProviders {
+        tracer_provider,
+        meter_provider,
+    })
+}
+
+impl OtlpProviders {
+    /// Flushes any pending telemetry and shuts down the exporters.
+    pub fn shutdown(self) {
+        if let Err(error) = self.tracer_provider.shutdown() {
+            eprintln!("Failed to shut down OTLP span exporter: {error}");
+        }
+
+        if let Err(error) = self.meter_provider.shutdown() {
+            eprintln!("Failed to shut down OTLP metric exporter: {error}");
+        }
+    }
+}
+
+/// Starts a span covering the handling of a single request, which ends when the returned span is dropped. If OTLP
+/// exporting was not set up, this is a no-op span.
+pub fn start_request_span(protocol: &'static str, local_address: SocketAddr, remote_address: SocketAddr) -> BoxedSpan {
+    let mut span = global::tracer(INSTRUMENTATION_NAME).start(format!("{protocol} request"));
+    span.set_attribute(KeyValue::new("network.transport", protocol));
+    span.set_attribute(KeyValue::new("server.address", local_address.to_string()));
+    span.set_attribute(KeyValue::new("client.address", remote_address.ip().to_string()));
+    span.set_attribute(KeyValue::new("client.port", remote_address.port() as i64));
+    span
+}
+
+pub fn record_tcp_connection() {
+    instruments().tcp_connections.add(1, &[]);
+}
+
+pub fn record_udp_packet() {
+    instruments().udp_packets.add(1, &[]);
+}
+
+pub fn record_error(protocol: &'static str) {
+    instruments().errors.add(1, &[KeyValue::new("network.transport", protocol)]);
+}
+
+/// Wraps a span exporter to log export failures, since these would otherwise go unnoticed.
+#[derive(Debug)]
+struct LoggingSpanExporter(SpanExporter);
+
+impl opentelemetry_sdk::trace::SpanExporter for LoggingSpanExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let result = self.0.export(batch).await;
+        if let Err(error) = &result {
+            eprintln!("Failed to export spans over OTLP: {error}");
+        }
+        result
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.0.set_resource(resource);
+    }
+}
+
+/// Wraps a metric exporter to log export failures, since these would otherwise go unnoticed.
+struct LoggingMetricExporter(MetricExporter);
+
+impl PushMetricExporter for LoggingMetricExporter {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        let result = self.0.export(metrics).await;
+        if let Err(error) = &result {
+            eprintln!("Failed to export metrics over OTLP: {error}");
+        }
+        result
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.0.temporality()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_spans_are_noops_until_exporting_is_set_up() {
+        let span = start_request_span("udp", "127.0.0.1:6969".parse().unwrap(), "192.0.2.1:5678".parse().unwrap());
+        assert!(!span.span_context().is_valid());
+        assert!(!span.is_recording());
+    }
+}
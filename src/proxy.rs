@@ -0,0 +1,212 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The longest a version 1 header may be, including the trailing CRLF.
+const MAX_V1_HEADER_SIZE: usize = 107;
+
+/// The largest address block accepted in a version 2 header. This leaves room for TLVs after the addresses, which are
+/// skipped.
+const MAX_V2_ADDRESS_SIZE: usize = 512;
+
+/// The signature every version 2 header starts with.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// An error while reading a PROXY protocol header.
+pub enum ProxyHeaderError {
+    Io(io::Error),
+    Invalid,
+}
+
+impl From<io::Error> for ProxyHeaderError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl fmt::Display for ProxyHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => error.fmt(f),
+            Self::Invalid => write!(f, "invalid PROXY protocol header"),
+        }
+    }
+}
+
+/// Reads a version 1 or 2 PROXY protocol header from the start of a stream, without reading anything past it.
+/// Returns the address of the client the proxy accepted the connection from, or `None` if the proxy didn't give one,
+/// such as for its own health checks.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut start = [0u8; 5];
+    stream.read_exact(&mut start).await?;
+
+    if &start == b"PROXY" {
+        read_v1_header(stream).await
+    } else if start == V2_SIGNATURE[..5] {
+        read_v2_header(stream).await
+    } else {
+        Err(ProxyHeaderError::Invalid)
+    }
+}
+
+/// Reads the rest of a version 1 header, such as `PROXY TCP4 1.2.3.4 5.6.7.8 5678 80\r\n`, after the leading `PROXY`.
+async fn read_v1_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut line = Vec::with_capacity(MAX_V1_HEADER_SIZE);
+    while !line.ends_with(b"\r\n") {
+        if line.len() + 5 >= MAX_V1_HEADER_SIZE {
+            return Err(ProxyHeaderError::Invalid);
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..(line.len() - 2)]).map_err(|_| ProxyHeaderError::Invalid)?;
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(ProxyHeaderError::Invalid),
+    }
+
+    let (Some(source), Some(_destination), Some(source_port), Some(_destination_port), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ProxyHeaderError::Invalid);
+    };
+
+    let ip: IpAddr = source.parse().map_err(|_| ProxyHeaderError::Invalid)?;
+    let port: u16 = source_port.parse().map_err(|_| ProxyHeaderError::Invalid)?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Reads the rest of a version 2 header after the first 5 bytes of its signature.
+async fn read_v2_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut header = [0u8; 11];
+    stream.read_exact(&mut header).await?;
+    if header[..7] != V2_SIGNATURE[5..] {
+        return Err(ProxyHeaderError::Invalid);
+    }
+
+    let (version_command, family) = (header[7], header[8]);
+    let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+    if version_command >> 4 != 2 || len > MAX_V2_ADDRESS_SIZE {
+        return Err(ProxyHeaderError::Invalid);
+    }
+
+    let mut addresses = vec![0u8; len];
+    stream.read_exact(&mut addresses).await?;
+
+    // The LOCAL command is used for connections the proxy makes on its own, which have no client.
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(ProxyHeaderError::Invalid),
+    }
+
+    match family {
+        0x11 if len >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x21 if len >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port)))
+        }
+        0x11 | 0x21 => Err(ProxyHeaderError::Invalid),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(header: &[u8]) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+        let mut stream = header;
+        read_header(&mut stream).await
+    }
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 5678 80\r\nGET /";
+        let address = read_header(&mut stream).await.ok().flatten();
+        assert_eq!(address, Some("192.0.2.1:5678".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+
+        let address = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 5678 80\r\n").await.ok().flatten();
+        assert_eq!(address, Some("[2001:db8::1]:5678".parse().unwrap()));
+
+        assert!(matches!(read(b"PROXY UNKNOWN\r\n").await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v1_header() {
+        assert!(matches!(
+            read(b"PROXY UDP4 192.0.2.1 198.51.100.1 5678 80\r\n").await,
+            Err(ProxyHeaderError::Invalid)
+        ));
+        assert!(matches!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 5678\r\n").await,
+            Err(ProxyHeaderError::Invalid)
+        ));
+        assert!(matches!(
+            read(b"PROXY TCP4 nope 198.51.100.1 5678 80\r\n").await,
+            Err(ProxyHeaderError::Invalid)
+        ));
+        assert!(matches!(
+            read(&[b"PROXY ".as_slice(), &[b'x'; 200]].concat()).await,
+            Err(ProxyHeaderError::Invalid)
+        ));
+        assert!(matches!(read(b"GET / HTTP/1.1\r\n\r\n").await, Err(ProxyHeaderError::Invalid)));
+        assert!(matches!(read(b"PROXY TCP4").await, Err(ProxyHeaderError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn reads_v2_header() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend_from_slice(&5678u16.to_be_bytes());
+        addresses.extend_from_slice(&80u16.to_be_bytes());
+        let mut header = v2_header(1, 0x11, &addresses);
+        header.extend_from_slice(b"rest");
+        let mut stream = header.as_slice();
+        let address = read_header(&mut stream).await.ok().flatten();
+        assert_eq!(address, Some("192.0.2.1:5678".parse().unwrap()));
+        assert_eq!(stream, b"rest");
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&5678u16.to_be_bytes());
+        addresses.extend_from_slice(&80u16.to_be_bytes());
+        let address = read(&v2_header(1, 0x21, &addresses)).await.ok().flatten();
+        assert_eq!(address, Some("[2001:db8::1]:5678".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn reads_v2_header_without_client() {
+        assert!(matches!(read(&v2_header(0, 0x00, &[])).await, Ok(None)));
+        assert!(matches!(read(&v2_header(1, 0x31, &[0; 216])).await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v2_header() {
+        assert!(matches!(read(&v2_header(1, 0x11, &[0; 4])).await, Err(ProxyHeaderError::Invalid)));
+        assert!(matches!(read(&v2_header(2, 0x11, &[0; 12])).await, Err(ProxyHeaderError::Invalid)));
+        assert!(matches!(read(&v2_header(1, 0x11, &[0; 600])).await, Err(ProxyHeaderError::Invalid)));
+
+        let mut header = v2_header(1, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        assert!(matches!(read(&header).await, Err(ProxyHeaderError::Invalid)));
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    net::IpAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    time::{Duration, Instant},
+};
+
+use crate::lru::LruMap;
+
+/// How often the rate limiter removes buckets that have fully refilled.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A token bucket that refills continuously at a fixed rate, up to a maximum capacity.
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, now: Instant) -> Self {
+        TokenBucket {
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, capacity: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.last_refill = now;
+    }
+
+    /// Takes a token if one is available, returning whether it was taken.
+    fn try_take(&mut self) -> bool {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Limits how many times something may happen per second overall, using a single token bucket that allows bursts
+/// of up to a second's worth.
+pub struct Throttle {
+    rate: f64,
+    bucket: TokenBucket,
+}
+
+impl Throttle {
+    pub fn new(rate: NonZeroU32) -> Self {
+        let rate = rate.get() as f64;
+        Throttle {
+            rate,
+            bucket: TokenBucket::new(rate, Instant::now()),
+        }
+    }
+
+    /// Attempts to take a token, returning whether the operation should go ahead.
+    pub fn try_acquire(&mut self) -> bool {
+        self.bucket.refill(self.rate, self.rate, Instant::now());
+        self.bucket.try_take()
+    }
+}
+
+/// Limits how many requests are served to each source IP address, using a token bucket per address. Each bucket
+/// allows bursts of up to `burst` requests, and refills at a sustained `rate` of requests per second.
+///
+/// If `max_ips` is specified, at most that many addresses are tracked at once, evicting the least recently seen
+/// address when a new one arrives. This bounds memory usage even when flooded from many (possibly spoofed) sources.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: LruMap<IpAddr, TokenBucket>,
+    last_prune: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: NonZeroU32, burst: NonZeroU32, max_ips: Option<NonZeroUsize>) -> Self {
+        RateLimiter {
+            rate: rate.get() as f64,
+            burst: burst.get() as f64,
+            buckets: LruMap::new(max_ips.map_or(usize::MAX, NonZeroUsize::get)),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Attempts to take a token for the given IP address, returning whether the request should be served. IPv4-mapped
+    /// IPv6 addresses share the bucket of the IPv4 address they map to, so a client gets the same limit through any
+    /// socket.
+    pub fn try_acquire(&mut self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        if now.saturating_duration_since(self.last_prune) >= PRUNE_INTERVAL {
+            self.prune(now);
+        }
+
+        let burst = self.burst;
+        let bucket = self.buckets.get_or_insert_with(ip, || TokenBucket::new(burst, now));
+        bucket.refill(self.rate, self.burst, now);
+        bucket.try_take()
+    }
+
+    /// Removes the buckets that have fully refilled, as these behave the same as a freshly created bucket.
+    fn prune(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(rate, burst, now);
+            bucket.tokens < burst
+        });
+        self.last_prune = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: u32, burst: u32, max_ips: Option<usize>) -> RateLimiter {
+        RateLimiter::new(
+            NonZeroU32::new(rate).unwrap(),
+            NonZeroU32::new(burst).unwrap(),
+            max_ips.and_then(NonZeroUsize::new),
+        )
+    }
+
+    #[test]
+    fn allows_bursts_then_limits() {
+        let mut limiter = limiter(1, 3, None);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(limiter.try_acquire(ip));
+        assert!(limiter.try_acquire(ip));
+        assert!(limiter.try_acquire(ip));
+        assert!(!limiter.try_acquire(ip));
+    }
+
+    #[test]
+    fn limits_each_address_separately() {
+        let mut limiter = limiter(1, 1, None);
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let second: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(limiter.try_acquire(first));
+        assert!(!limiter.try_acquire(first));
+        assert!(limiter.try_acquire(second));
+    }
+
+    #[test]
+    fn limits_mapped_addresses_as_ipv4() {
+        let mut limiter = limiter(1, 1, None);
+        assert!(limiter.try_acquire("192.0.2.1".parse().unwrap()));
+        assert!(!limiter.try_acquire("::ffff:192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn evicts_least_recently_seen_address_when_full() {
+        let mut limiter = limiter(1, 1, Some(2));
+        let ips: Vec<IpAddr> = ["192.0.2.1", "192.0.2.2", "192.0.2.3"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        assert!(limiter.try_acquire(ips[0]));
+        assert!(limiter.try_acquire(ips[1]));
+        assert!(!limiter.try_acquire(ips[0]));
+        assert!(limiter.try_acquire(ips[2]));
+
+        // The second address was the least recently seen, so it was forgotten and gets a fresh bucket.
+        assert!(limiter.try_acquire(ips[1]));
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, now);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        bucket.refill(1.0, 2.0, now + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn throttle_limits_overall_rate() {
+        let mut throttle = Throttle::new(NonZeroU32::new(2).unwrap());
+        assert!(throttle.try_acquire());
+        assert!(throttle.try_acquire());
+        assert!(!throttle.try_acquire());
+    }
+
+    #[test]
+    fn tracks_at_most_max_ips_when_flooded() {
+        let mut limiter = limiter(1, 1, Some(100));
+        for i in 0..10_000u32 {
+            assert!(limiter.try_acquire(IpAddr::V4((0x0a00_0000 + i).into())));
+            assert!(limiter.buckets.len() <= 100);
+        }
+        assert_eq!(limiter.buckets.len(), 100);
+    }
+}
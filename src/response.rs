@@ -0,0 +1,426 @@
+//! Builds the responses sent to clients in each format, from the details of the request being answered.
+//!
+//! Values such as hostnames come from outside the server, so each format escapes them by its own rules before they're
+//! written: JSON strings are escaped, and the text format and templates have line breaks and other control characters
+//! removed, so a value can't add lines to a response or break out of the field it's in.
+
+use std::{
+    borrow::Cow,
+    fmt::{Display, Write as _},
+    io::Write as _,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use crate::{
+    args::{ChecksumType, ResponseFormat, StartupArguments},
+    template::ResponseTemplate,
+    utils::{self, ClientAddress},
+};
+
+/// The details of a request that its response is built from.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// The protocol the request was received over, either "tcp", "udp" or "unix".
+    pub protocol: &'static str,
+    /// The address of the socket the request was received on. Unix domain sockets have none.
+    pub local_address: Option<SocketAddr>,
+    /// The client's address, as told by the PROXY protocol or trusted forwarding headers if those are in use. Unix
+    /// domain sockets have none.
+    pub remote_address: Option<SocketAddr>,
+    /// The client's address as shown in responses, which has its IP address hashed with `--hash-ip`.
+    pub client: ClientAddress,
+    /// The connection or packet number.
+    pub counter: u64,
+    /// The size of the received packet, for UDP requests.
+    pub bytes: Option<usize>,
+    /// The amount of requests received from the client's IP address, including this one, with `--track-per-ip`.
+    pub ip_count: Option<u64>,
+    /// The client's hostname, found by a reverse DNS lookup with `--resolve`.
+    pub hostname: Option<String>,
+    /// The time since the server started.
+    pub uptime: Duration,
+    /// The client's `User-Agent` header, for requests over HTTP that have one.
+    pub user_agent: Option<String>,
+    /// The parameters negotiated with the client, for TCP connections served over TLS.
+    pub tls: Option<TlsParameters>,
+}
+
+/// The parameters a TLS connection was established with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsParameters {
+    /// The protocol version, such as "TLSv1.3".
+    pub version: &'static str,
+    /// The cipher suite, named the same as with `--tls-cipher-suites`, such as "TLS13_AES_256_GCM_SHA384".
+    pub cipher: &'static str,
+}
+
+/// Builds the responses sent to clients in place of the built-in formats, for embedders of the library that want
+/// fully custom responses while reusing the servers. A provider is given to [`crate::server::run_server_with_provider`].
+///
+/// The trait is object safe, and the servers hold the provider as an `Arc<dyn ResponseProvider>` shared by all of
+/// their tasks, which is why it must be `Send + Sync`. [`ResponseProvider::build`] is called on the task serving the
+/// request, so it must not block. There is no async variant, as async methods would make the trait no longer object
+/// safe without boxing every future it returns. A provider that needs slow lookups, such as from a database, should
+/// run them in a task of its own and have `build` read their latest results.
+pub trait ResponseProvider: Send + Sync {
+    /// Builds the response to a request. With `--http`, this is the body of the HTTP response. UDP responses longer
+    /// than 1400 bytes are cut short, the same as with a template.
+    fn build(&self, context: &RequestContext) -> Vec<u8>;
+}
+
+/// Builds a response in a given format, escaping the values written into it as that format requires.
+pub struct ResponseBuilder<'a> {
+    startup_args: &'a StartupArguments,
+    /// The provider that replaces the built-in formats, if the servers were given one.
+    provider: Option<&'a dyn ResponseProvider>,
+    format: &'a ResponseFormat,
+    /// The `--body-file` template, which replaces the format for TCP and Unix domain socket clients.
+    body_template: Option<&'a ResponseTemplate>,
+    context: RequestContext,
+    /// Additional fields only included in the text format, such as TCP info.
+    text_fields: Vec<(&'static str, String)>,
+    first_seen: bool,
+}
+
+impl<'a> ResponseBuilder<'a> {
+    pub fn new(startup_args: &'a StartupArguments, format: &'a ResponseFormat, context: RequestContext) -> Self {
+        let body_template = match context.protocol {
+            "udp" => None,
+            _ => startup_args.body_template.as_ref(),
+        };
+
+        ResponseBuilder {
+            startup_args,
+            provider: None,
+            format,
+            body_template,
+            context,
+            text_fields: Vec::new(),
+            first_seen: false,
+        }
+    }
+
+    /// Sets the provider that builds the response instead of the built-in formats.
+    pub fn provider(&mut self, provider: Option<&'a dyn ResponseProvider>) {
+        self.provider = provider;
+    }
+
+    /// Returns whether the response is built in the text format, the only one that includes additional fields.
+    pub fn is_text(&self) -> bool {
+        self.provider.is_none() && self.body_template.is_none() && *self.format == ResponseFormat::Text
+    }
+
+    /// Gets the details of the request, to fill in any that are only needed by some formats, such as the hostname.
+    pub fn context_mut(&mut self) -> &mut RequestContext {
+        &mut self.context
+    }
+
+    /// Adds a field to the text format, written after the ones every response has as " | name: value".
+    pub fn text_field(&mut self, name: &'static str, value: impl Display) {
+        self.text_fields.push((name, value.to_string()));
+    }
+
+    /// Sets whether this is the first request seen from the client's IP address, in which case the text format
+    /// includes the `--first-seen-message`.
+    pub fn first_seen(&mut self, first_seen: bool) {
+        self.first_seen = first_seen;
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        if let Some(provider) = self.provider {
+            return provider.build(&self.context);
+        }
+
+        if let Some(template) = self.body_template {
+            return template.render(&self.context, self.startup_args.uptime_format).into_bytes();
+        }
+
+        match self.format {
+            ResponseFormat::Text => self.build_text(),
+            ResponseFormat::Json => self.build_json(),
+            ResponseFormat::IpBytes => self
+                .context
+                .remote_address
+                .map_or_else(Vec::new, |address| address_bytes(address, false)),
+            ResponseFormat::IpPortBytes => self
+                .context
+                .remote_address
+                .map_or_else(Vec::new, |address| address_bytes(address, true)),
+            ResponseFormat::Template(template) => template.render(&self.context, self.startup_args.uptime_format).into_bytes(),
+        }
+    }
+
+    fn build_text(&self) -> Vec<u8> {
+        let context = &self.context;
+        let mut response = format!("you: {}", text_value(&context.client.address));
+        if let Some(hostname) = &context.hostname {
+            let _ = write!(response, " ({})", text_value(hostname));
+        }
+        match context.bytes {
+            Some(bytes) => {
+                let _ = write!(response, " | bytes: {bytes} | packet_number: {}", context.counter);
+            }
+            None => {
+                let _ = write!(response, " | connection_number: {}", context.counter);
+            }
+        }
+        if let Some(ip_count) = context.ip_count {
+            let _ = write!(response, " | ip_count: {ip_count}");
+        }
+        for (name, value) in &self.text_fields {
+            let _ = write!(response, " | {name}: {}", text_value(value));
+        }
+        if let Some(message) = self.startup_args.first_seen_message.as_ref().filter(|_| self.first_seen) {
+            let _ = write!(response, " | {}", text_value(message));
+        }
+
+        let mut response = response.into_bytes();
+        if let Some(checksum_type) = self.startup_args.checksum {
+            append_checksum(&mut response, checksum_type);
+        }
+        response
+    }
+
+    fn build_json(&self) -> Vec<u8> {
+        let context = &self.context;
+        utils::format_json_response(
+            context.protocol,
+            &context.client,
+            context.counter,
+            self.startup_args.json_numeric_port,
+            self.startup_args.json_extra.as_deref(),
+        )
+        .into_bytes()
+    }
+}
+
+/// Removes line breaks and other control characters from a value written into a text response or template.
+pub(crate) fn text_value(value: &str) -> Cow<'_, str> {
+    if value.contains(char::is_control) {
+        Cow::Owned(value.chars().filter(|c| !c.is_control()).collect())
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Gets a client's IP address as raw bytes in network order, 4 bytes for IPv4 or 16 bytes for IPv6, optionally
+/// followed by its port as 2 bytes in network order. IPv4-mapped IPv6 addresses are written as IPv4.
+fn address_bytes(remote_address: SocketAddr, include_port: bool) -> Vec<u8> {
+    let mut response = match remote_address.ip().to_canonical() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    if include_port {
+        response.extend_from_slice(&remote_address.port().to_be_bytes());
+    }
+
+    response
+}
+
+/// Appends a checksum field to a response, computed over everything written to it so far.
+fn append_checksum(response: &mut Vec<u8>, checksum_type: ChecksumType) {
+    let checksum = utils::compute_checksum(checksum_type, response);
+    let _ = write!(response, " | checksum: {checksum}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddrV6;
+
+    /// A request whose values are full of characters that could break a response if written as they are.
+    fn adversarial_context() -> RequestContext {
+        RequestContext {
+            protocol: "tcp",
+            local_address: Some("192.0.2.1:6969".parse().unwrap()),
+            remote_address: Some("198.51.100.7:1234".parse().unwrap()),
+            client: ClientAddress {
+                address: "a\"b\\c\r\nd:1234".into(),
+                ip: "a\"b\\c\r\nd\u{0}".into(),
+                port: 1234,
+            },
+            counter: 3,
+            bytes: None,
+            ip_count: None,
+            hostname: Some("evil.example\r\nyou: 127.0.0.1".into()),
+            uptime: Duration::ZERO,
+            user_agent: None,
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn text_includes_hostname() {
+        let startup_args = StartupArguments::empty();
+        let mut context = RequestContext {
+            protocol: "tcp",
+            local_address: None,
+            remote_address: Some("192.0.2.1:1234".parse().unwrap()),
+            client: ClientAddress::new("192.0.2.1:1234".parse().unwrap(), None),
+            counter: 3,
+            bytes: None,
+            ip_count: None,
+            hostname: Some("client.example".into()),
+            uptime: Duration::ZERO,
+            user_agent: None,
+            tls: None,
+        };
+
+        let response = ResponseBuilder::new(&startup_args, &ResponseFormat::Text, context.clone()).build();
+        assert_eq!(response, b"you: 192.0.2.1:1234 (client.example) | connection_number: 3");
+
+        context.hostname = None;
+        let response = ResponseBuilder::new(&startup_args, &ResponseFormat::Text, context.clone()).build();
+        assert_eq!(response, b"you: 192.0.2.1:1234 | connection_number: 3");
+    }
+
+    #[test]
+    fn long_responses_are_not_truncated() {
+        let mut startup_args = StartupArguments::empty();
+        let remote_address = SocketAddr::V6(SocketAddrV6::new(
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff".parse().unwrap(),
+            u16::MAX,
+            0,
+            u32::MAX,
+        ));
+        let context = RequestContext {
+            protocol: "tcp",
+            local_address: None,
+            remote_address: Some(remote_address),
+            client: ClientAddress::new(remote_address, None),
+            counter: u64::MAX,
+            bytes: None,
+            ip_count: Some(u64::MAX),
+            hostname: Some("h".repeat(253)),
+            uptime: Duration::ZERO,
+            user_agent: None,
+            tls: None,
+        };
+
+        let response = ResponseBuilder::new(&startup_args, &ResponseFormat::Text, context.clone()).build();
+        let expected = format!(
+            "you: [ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff%4294967295]:65535 ({}) | connection_number: {} | ip_count: {}",
+            "h".repeat(253),
+            u64::MAX,
+            u64::MAX
+        );
+        assert!(expected.len() > 256);
+        assert_eq!(String::from_utf8(response).unwrap(), expected);
+
+        let template = format!("{} {{addr}} {{count}}", "x".repeat(1000));
+        startup_args.body_template = Some(ResponseTemplate::parse(&template).unwrap());
+        let response = ResponseBuilder::new(&startup_args, &ResponseFormat::Text, context).build();
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            format!("{} {remote_address} {}", "x".repeat(1000), u64::MAX)
+        );
+    }
+
+    #[test]
+    fn text_strips_line_breaks_from_values() {
+        let mut startup_args = StartupArguments::empty();
+        startup_args.first_seen_message = Some("hi\nthere".into());
+        let context = adversarial_context();
+
+        let mut builder = ResponseBuilder::new(&startup_args, &ResponseFormat::Text, context.clone());
+        assert!(builder.is_text());
+        builder.text_field("note", "x\ny");
+        builder.first_seen(true);
+        let response = String::from_utf8(builder.build()).unwrap();
+
+        assert!(!response.contains(char::is_control), "{response:?}");
+        assert_eq!(
+            response,
+            "you: a\"b\\cd:1234 (evil.exampleyou: 127.0.0.1) | connection_number: 3 | note: xy | hithere"
+        );
+    }
+
+    #[test]
+    fn json_escapes_values() {
+        let mut startup_args = StartupArguments::empty();
+        startup_args.json_extra = Some("\"region\":\"eu\"".into());
+        let context = adversarial_context();
+
+        let response = ResponseBuilder::new(&startup_args, &ResponseFormat::Json, context.clone()).build();
+        let value: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        assert_eq!(value["address"], context.client.address);
+        assert_eq!(value["ip"], context.client.ip);
+        assert_eq!(value["port"], 1234);
+        assert_eq!(value["region"], "eu");
+    }
+
+    #[test]
+    fn json_port_is_a_number_or_a_string() {
+        let mut startup_args = StartupArguments::empty();
+        let context = adversarial_context();
+
+        let response = ResponseBuilder::new(&startup_args, &ResponseFormat::Json, context.clone()).build();
+        let value: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        assert_eq!(value["port"], serde_json::json!(1234));
+
+        startup_args.json_numeric_port = false;
+        let response = ResponseBuilder::new(&startup_args, &ResponseFormat::Json, context.clone()).build();
+        let value: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        assert_eq!(value["port"], serde_json::json!("1234"));
+    }
+
+    #[test]
+    fn templates_strip_line_breaks_from_values() {
+        let startup_args = StartupArguments::empty();
+        let format = ResponseFormat::Template(ResponseTemplate::parse("{ip}\n{addr}").unwrap());
+        let context = adversarial_context();
+
+        let response = ResponseBuilder::new(&startup_args, &format, context.clone()).build();
+        assert_eq!(response, b"a\"b\\cd\na\"b\\cd:1234");
+    }
+
+    #[test]
+    fn byte_formats_have_no_fields() {
+        let mut startup_args = StartupArguments::empty();
+        startup_args.checksum = Some(ChecksumType::Crc32);
+        let context = adversarial_context();
+
+        let mut builder = ResponseBuilder::new(&startup_args, &ResponseFormat::IpPortBytes, context.clone());
+        assert!(!builder.is_text());
+        builder.text_field("note", "x");
+        assert_eq!(builder.build(), [198, 51, 100, 7, 0x04, 0xd2]);
+    }
+
+    struct CannedProvider;
+
+    impl ResponseProvider for CannedProvider {
+        fn build(&self, context: &RequestContext) -> Vec<u8> {
+            format!("canned #{}", context.counter).into_bytes()
+        }
+    }
+
+    #[test]
+    fn provider_replaces_the_format() {
+        let mut startup_args = StartupArguments::empty();
+        startup_args.body_template = Some(ResponseTemplate::parse("<p>{count}</p>").unwrap());
+
+        let mut builder = ResponseBuilder::new(&startup_args, &ResponseFormat::Text, adversarial_context());
+        builder.provider(Some(&CannedProvider));
+        assert!(!builder.is_text());
+        assert_eq!(builder.build(), b"canned #3");
+    }
+
+    #[test]
+    fn body_template_replaces_the_format_except_for_udp() {
+        let mut startup_args = StartupArguments::empty();
+        startup_args.body_template = Some(ResponseTemplate::parse("<p>{count}</p>").unwrap());
+        let mut context = adversarial_context();
+
+        let builder = ResponseBuilder::new(&startup_args, &ResponseFormat::Text, context.clone());
+        assert!(!builder.is_text());
+        assert_eq!(builder.build(), b"<p>3</p>");
+
+        context.protocol = "udp";
+        context.bytes = Some(5);
+        let response = ResponseBuilder::new(&startup_args, &ResponseFormat::Text, context.clone()).build();
+        assert!(response.starts_with(b"you: "));
+    }
+}
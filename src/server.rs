@@ -1,182 +1,1073 @@
 use std::{
-    io::{Cursor, Write},
-    net::SocketAddr,
-    process::exit,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    future::{self, Future},
+    io,
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpListener, UdpSocket},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, watch, Notify, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time::Instant,
 };
+use tracing::{debug, error, info, instrument, warn};
 
-use crate::{args::StartupArguments, printlnif};
+#[cfg(feature = "otlp")]
+use crate::otlp;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::{
+    accesslog::AccessLog,
+    args::{AddressFormat, ConnectionLimitPolicy, EchoMode, EmptyUdpPolicy, ResponseFormat, StartupArguments},
+    csvlog::CsvLog,
+    http,
+    iptracker::{IpTracker, SeenIps},
+    metrics::{self, MetricsSnapshot},
+    proxy,
+    ratelimit::{RateLimiter, Throttle},
+    response::{RequestContext, ResponseBuilder, ResponseProvider, TlsParameters},
+    utils::{self, ClientAddress, Rng},
+};
 
 pub const UDP_BUF_SIZE: usize = 1400;
 
-pub async fn run_server(startup_args: StartupArguments) {
-    let tcp_listeners = bind_tcp_listeners(startup_args.verbose, &startup_args.tcp_addresses);
-    let udp_sockets = bind_udp_sockets(startup_args.verbose, &startup_args.udp_addresses);
+/// The maximum length of the queue of pending connections for TCP listeners.
+const TCP_BACKLOG: i32 = 1024;
+
+/// The most clients a UDP server holds back responses for at once with `--udp-coalesce`.
+const MAX_COALESCED_CLIENTS: usize = 4096;
+
+/// How long a TLS client has to complete its handshake when serving over TLS.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to try sending the `--full-message` to a rejected client before closing its connection.
+const FULL_MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an HTTP client with `--http` may stay idle between requests before the connection is closed.
+const HTTP_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a proxy has to send the PROXY protocol header of a connection with `--proxy-protocol`.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a reverse DNS lookup with `--resolve` before responding without the hostname.
+const REVERSE_DNS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to wait for a client to echo back its response in `--roundtrip` mode.
+pub const ROUNDTRIP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum number of fresh sockets created per second for each UDP socket with `--udp-fresh-source`.
+const FRESH_SOURCE_MAX_RATE: NonZeroU32 = match NonZeroU32::new(100) {
+    Some(rate) => rate,
+    None => unreachable!(),
+};
+
+/// State shared between all the TCP and UDP servers.
+struct SharedState {
+    /// When the server started, to tell clients its uptime.
+    started_at: Instant,
+    /// The amount of TCP listeners and UDP sockets still being served.
+    active_sockets: AtomicUsize,
+    /// The amount of TCP connections currently being handled.
+    active_connections: AtomicUsize,
+    /// Notified when the last TCP connection being handled finishes.
+    connections_drained: Notify,
+    /// Whether the shutdown grace period is over, so the connections still being handled must be cut off.
+    terminated: AtomicBool,
+    /// Notified when `terminated` is set.
+    terminate: Notify,
+    /// Whether the single request served with `--once` was already taken, if it was specified.
+    once_claimed: Option<AtomicBool>,
+    /// Notified when the single request served with `--once` has been answered.
+    once_served: Notify,
+    /// The permits for handling TCP connections, if limited by `--max-connections`.
+    connection_permits: Option<Arc<Semaphore>>,
+    tcp_connections: AtomicU64,
+    udp_packets: AtomicU64,
+    errors: AtomicU64,
+    /// The amount of responses sent, keyed by protocol and response format.
+    responses: Mutex<HashMap<(&'static str, &'static str), u64>>,
+    csv_log: Option<CsvLog>,
+    access_log: Option<AccessLog>,
+    ip_tracker: Option<Mutex<IpTracker>>,
+    /// The per-IP rate limit on TCP connections across all listeners, if `--rate-limit` was specified.
+    tcp_rate_limiter: Option<Mutex<RateLimiter>>,
+    /// The per-IP rate limit on UDP responses across all sockets and shards, if `--udp-rate` was specified.
+    udp_rate_limiter: Option<Mutex<RateLimiter>>,
+    seen_ips: Option<Mutex<SeenIps>>,
+    /// The provider that builds responses instead of the built-in formats, if given to [`run_server_with_provider`].
+    provider: Option<Arc<dyn ResponseProvider>>,
+    /// The formats given to single TCP addresses with an `=format` suffix, keyed by the local address of the listener
+    /// bound for each, which differs from the one given if it had port 0.
+    tcp_formats: HashMap<SocketAddr, AddressFormat>,
+    /// The formats given to single UDP addresses with an `=format` suffix, keyed by the local address of the sockets
+    /// bound for each.
+    udp_formats: HashMap<SocketAddr, ResponseFormat>,
+    /// The acceptor TCP connections are wrapped in if `--tls-cert` or `--tls-pkcs12` was specified.
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+}
+
+impl SharedState {
+    fn new(
+        active_sockets: usize,
+        csv_log: Option<CsvLog>,
+        access_log: Option<AccessLog>,
+        tcp_formats: HashMap<SocketAddr, AddressFormat>,
+        udp_formats: HashMap<SocketAddr, ResponseFormat>,
+        #[cfg(feature = "tls")] tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+        startup_args: &StartupArguments,
+    ) -> Self {
+        SharedState {
+            started_at: Instant::now(),
+            active_sockets: AtomicUsize::new(active_sockets),
+            active_connections: AtomicUsize::new(0),
+            connections_drained: Notify::new(),
+            terminated: AtomicBool::new(false),
+            terminate: Notify::new(),
+            once_claimed: startup_args.once.then(|| AtomicBool::new(false)),
+            once_served: Notify::new(),
+            connection_permits: startup_args.max_connections.map(|max| Arc::new(Semaphore::new(max.get()))),
+            tcp_connections: AtomicU64::new(0),
+            udp_packets: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            responses: Mutex::new(HashMap::new()),
+            csv_log,
+            access_log,
+            ip_tracker: startup_args.track_per_ip.then(|| Mutex::new(IpTracker::new())),
+            tcp_rate_limiter: startup_args
+                .tcp_rate
+                .map(|rate| Mutex::new(RateLimiter::new(rate, rate, startup_args.rate_limit_max_ips))),
+            udp_rate_limiter: startup_args.udp_rate.map(|rate| {
+                let burst = startup_args.udp_burst.unwrap_or(rate);
+                Mutex::new(RateLimiter::new(rate, burst, startup_args.rate_limit_max_ips))
+            }),
+            seen_ips: startup_args
+                .first_seen_message
+                .is_some()
+                .then(|| Mutex::new(SeenIps::new(startup_args.first_seen_ttl))),
+            provider: None,
+            tcp_formats,
+            udp_formats,
+            #[cfg(feature = "tls")]
+            tls_acceptor,
+        }
+    }
+
+    /// Starts building a response in the given format, which the provider given to [`run_server_with_provider`]
+    /// builds instead if there is one.
+    fn response_builder<'a>(
+        &'a self,
+        startup_args: &'a StartupArguments,
+        format: &'a ResponseFormat,
+        context: RequestContext,
+    ) -> ResponseBuilder<'a> {
+        let mut builder = ResponseBuilder::new(startup_args, format, context);
+        builder.provider(self.provider.as_deref());
+        builder
+    }
+
+    /// Gets the format a TCP listener responds in, which is the one given to its address if it had an `=format`
+    /// suffix, or the one for all TCP listeners otherwise.
+    fn tcp_format<'a>(&'a self, startup_args: &'a StartupArguments, addr: SocketAddr) -> &'a ResponseFormat {
+        match self.tcp_formats.get(&addr) {
+            Some(AddressFormat::Format(format)) => format,
+            _ => &startup_args.tcp_format,
+        }
+    }
+
+    /// Returns whether a TCP listener answers over HTTP, either with `--http` or an `=http` suffix on its address.
+    fn serves_http(&self, startup_args: &StartupArguments, addr: SocketAddr) -> bool {
+        startup_args.http || self.tcp_formats.get(&addr) == Some(&AddressFormat::Http)
+    }
+
+    /// Gets the format a UDP socket responds in, which is the one given to its address if it had an `=format` suffix,
+    /// or the one for all UDP sockets otherwise.
+    fn udp_format<'a>(&'a self, startup_args: &'a StartupArguments, addr: SocketAddr) -> &'a ResponseFormat {
+        self.udp_formats.get(&addr).unwrap_or(&startup_args.udp_format)
+    }
+
+    fn record_tcp_connection(&self) {
+        self.tcp_connections.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "otlp")]
+        otlp::record_tcp_connection();
+    }
+
+    fn record_udp_packet(&self) {
+        self.udp_packets.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "otlp")]
+        otlp::record_udp_packet();
+    }
+
+    #[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+    fn record_error(&self, protocol: &'static str) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "otlp")]
+        otlp::record_error(protocol);
+    }
+
+    /// Records a served request in the response counters, and in the CSV log and access log if there are any.
+    fn record_served(
+        &self,
+        protocol: &'static str,
+        format: &'static str,
+        local_address: SocketAddr,
+        remote_address: SocketAddr,
+        counter: u64,
+        bytes: usize,
+    ) {
+        *self.responses.lock().unwrap().entry((protocol, format)).or_default() += 1;
+
+        if let Some(csv_log) = &self.csv_log {
+            if let Err(error) = csv_log.record(protocol, Some(remote_address), counter, bytes) {
+                error!("Failed to write to CSV log: {error}");
+            }
+        }
+
+        if let Some(access_log) = &self.access_log {
+            if let Err(error) = access_log.record(protocol, local_address, remote_address, counter) {
+                error!("Failed to write to log file: {error}");
+            }
+        }
+    }
+
+    /// Records a request served over the Unix domain socket at `path` in the response counters, and in the CSV log and
+    /// access log if there are any.
+    #[cfg(unix)]
+    fn record_unix_served(&self, format: &'static str, path: &str, counter: u64, bytes: usize) {
+        *self.responses.lock().unwrap().entry(("unix", format)).or_default() += 1;
+
+        if let Some(csv_log) = &self.csv_log {
+            if let Err(error) = csv_log.record("unix", None, counter, bytes) {
+                error!("Failed to write to CSV log: {error}");
+            }
+        }
+
+        if let Some(access_log) = &self.access_log {
+            if let Err(error) = access_log.record("unix", path, "unix", counter) {
+                error!("Failed to write to log file: {error}");
+            }
+        }
+    }
+
+    /// Waits until no TCP connections are being handled.
+    async fn wait_connections_drained(&self) {
+        loop {
+            // The notification is enabled before checking, so one sent in between isn't missed.
+            let notified = self.connections_drained.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.active_connections.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Cuts off the connections still being handled, once the shutdown grace period is over.
+    fn terminate_connections(&self) {
+        self.terminated.store(true, Ordering::SeqCst);
+        self.terminate.notify_waiters();
+    }
+
+    /// Waits until the connections still being handled must be cut off.
+    async fn wait_terminated(&self) {
+        let notified = self.terminate.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if !self.terminated.load(Ordering::SeqCst) {
+            notified.await;
+        }
+    }
+
+    /// Takes the single request served with `--once`, returning false if another one already took it. Without
+    /// `--once`, every request may be served.
+    fn claim_request(&self) -> bool {
+        self.once_claimed
+            .as_ref()
+            .map_or(true, |claimed| !claimed.swap(true, Ordering::SeqCst))
+    }
+
+    /// Marks the single request served with `--once` as answered, if it was specified.
+    fn finish_once(&self) {
+        if self.once_claimed.is_some() {
+            self.once_served.notify_one();
+        }
+    }
+
+    /// Takes a token from the TCP rate limit for the given IP address, returning whether its connection should be
+    /// served.
+    fn try_acquire_tcp(&self, ip: IpAddr) -> bool {
+        self.tcp_rate_limiter
+            .as_ref()
+            .map_or(true, |limiter| limiter.lock().unwrap().try_acquire(ip))
+    }
+
+    /// Takes a token from the UDP rate limit for the given IP address, returning whether its packet should be answered.
+    fn try_acquire_udp(&self, ip: IpAddr) -> bool {
+        self.udp_rate_limiter
+            .as_ref()
+            .map_or(true, |limiter| limiter.lock().unwrap().try_acquire(ip))
+    }
+
+    /// Records a request from the given IP address, returning how many have been seen from it if `--track-per-ip` was
+    /// specified.
+    fn record_ip(&self, ip: IpAddr) -> Option<u64> {
+        self.ip_tracker.as_ref().map(|tracker| tracker.lock().unwrap().record(ip))
+    }
+
+    /// Records a request from the given IP address, returning whether it's the first one seen from it within the TTL
+    /// if `--first-seen-message` was specified.
+    fn record_first_seen(&self, ip: IpAddr) -> bool {
+        self.seen_ips.as_ref().is_some_and(|seen_ips| seen_ips.lock().unwrap().record(ip))
+    }
+
+    fn metrics_snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            tcp_connections: self.tcp_connections.load(Ordering::Relaxed),
+            udp_packets: self.udp_packets.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            responses: self
+                .responses
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&labels, &count)| (labels, count))
+                .collect(),
+        }
+    }
+
+    /// Marks a socket as no longer being served.
+    fn remove_socket(&self) {
+        self.active_sockets.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Marks an idle socket as no longer being served, unless it's the last remaining one. Returns whether the socket
+    /// should be unbound.
+    fn try_unbind_idle_socket(&self) -> bool {
+        self.active_sockets
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |sockets| (sockets > 1).then(|| sockets - 1))
+            .is_ok()
+    }
+}
+
+/// Marks a TCP connection as being handled until dropped, which also happens if the task handling it is aborted.
+struct ConnectionGuard {
+    shared: Arc<SharedState>,
+    /// Whether the connection took the single request served with `--once`.
+    claimed: bool,
+}
+
+impl ConnectionGuard {
+    /// Creates the guard for a connection that already took the single request served with `--once`.
+    fn new(shared: &Arc<SharedState>) -> Self {
+        let mut guard = Self::unclaimed(shared);
+        guard.claimed = true;
+        guard
+    }
+
+    /// Creates the guard for a connection that has yet to take the single request served with `--once`.
+    fn unclaimed(shared: &Arc<SharedState>) -> Self {
+        shared.active_connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            shared: Arc::clone(shared),
+            claimed: false,
+        }
+    }
+
+    /// Takes the single request served with `--once`, returning false if another connection already took it.
+    fn claim(&mut self) -> bool {
+        self.claimed = self.shared.claim_request();
+        self.claimed
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.shared.active_connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.connections_drained.notify_waiters();
+        }
+
+        // With `--once`, only the claimed connection is served, so it's done when this is.
+        if self.claimed {
+            self.shared.finish_once();
+        }
+    }
+}
+
+/// A fatal error that kept the servers from starting.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The log file given with `--log-file` couldn't be opened.
+    LogFile(String, io::Error),
+    /// The CSV log given with `--csv-log` couldn't be created.
+    CsvLog(String, io::Error),
+    /// The TLS certificate or private key couldn't be loaded.
+    Tls(String),
+    /// A file descriptor passed by systemd socket activation isn't a TCP nor UDP socket.
+    UnsupportedActivationSocket(i32),
+    /// None of the sockets could be bound. Holds why each of them failed.
+    NoSocketsBound(Vec<BindFailure>),
+}
+
+/// A socket that couldn't be bound or adopted.
+#[derive(Debug)]
+pub struct BindFailure {
+    /// The socket, as its address, `unix:PATH` for Unix domain sockets, or `fd:N` for inherited file descriptors.
+    pub socket: String,
+    pub message: String,
+}
+
+impl BindFailure {
+    fn new(socket: impl ToString, message: String) -> Self {
+        BindFailure {
+            socket: socket.to_string(),
+            message,
+        }
+    }
+}
+
+impl ServerError {
+    /// A short name for the kind of error, used as the `kind` of errors printed with `--error-format json`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::LogFile(_, _) => "log_file",
+            Self::CsvLog(_, _) => "csv_log",
+            Self::Tls(_) => "tls",
+            Self::UnsupportedActivationSocket(_) => "systemd",
+            Self::NoSocketsBound(_) => "bind",
+        }
+    }
+
+    /// Formats the error as a single-line JSON object, for `--error-format json`. When no sockets could be bound, the
+    /// object has the first failed socket as its `addr`, and the reasons all of them failed as `failures`.
+    pub fn to_json(&self) -> String {
+        // The reasons sockets couldn't be bound go in `failures`, so they aren't repeated in the message.
+        let message = match self {
+            Self::NoSocketsBound(_) => String::from("No TCP nor UDP sockets could be bound"),
+            _ => utils::escape_json(&self.to_string()),
+        };
+        let mut json = format!("{{\"error\":\"{message}\",\"kind\":\"{}\"", self.kind());
+        if let Self::NoSocketsBound(failures) = self {
+            if let Some(first) = failures.first() {
+                let _ = fmt::Write::write_fmt(&mut json, format_args!(",\"addr\":\"{}\"", utils::escape_json(&first.socket)));
+            }
+
+            json.push_str(",\"failures\":[");
+            for (i, failure) in failures.iter().enumerate() {
+                if i != 0 {
+                    json.push(',');
+                }
+                let _ = fmt::Write::write_fmt(
+                    &mut json,
+                    format_args!(
+                        "{{\"addr\":\"{}\",\"error\":\"{}\"}}",
+                        utils::escape_json(&failure.socket),
+                        utils::escape_json(&failure.message)
+                    ),
+                );
+            }
+            json.push(']');
+        }
+
+        json.push('}');
+        json
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LogFile(path, error) => write!(f, "Failed to open log file {path}: {error}"),
+            Self::CsvLog(path, error) => write!(f, "Failed to create CSV log {path}: {error}"),
+            Self::Tls(error) => error.fmt(f),
+            Self::UnsupportedActivationSocket(fd) => {
+                write!(f, "File descriptor {fd} passed by socket activation is not a TCP nor UDP socket")
+            }
+            Self::NoSocketsBound(failures) => {
+                write!(f, "No TCP nor UDP sockets could be bound")?;
+                for failure in failures {
+                    write!(f, "\n  {}", failure.message)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Waits for a break signal, such as Ctrl+C, to be used as the `shutdown` future of [`run_server`].
+pub async fn break_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Received break signal, shutting down");
+}
+
+/// Binds the sockets and serves them until `shutdown` completes, or until a single request was served with `--once`,
+/// then shuts the servers down gracefully. Returns an error without serving anything if the servers couldn't start.
+pub async fn run_server(startup_args: StartupArguments, shutdown: impl Future<Output = ()>) -> Result<(), ServerError> {
+    serve(startup_args, None, shutdown).await
+}
+
+/// Runs the servers the same as [`run_server`], but with every response built by the given provider instead of in
+/// the format from the arguments.
+pub async fn run_server_with_provider(
+    startup_args: StartupArguments,
+    provider: Arc<dyn ResponseProvider>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    serve(startup_args, Some(provider), shutdown).await
+}
+
+async fn serve(
+    mut startup_args: StartupArguments,
+    provider: Option<Arc<dyn ResponseProvider>>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    // When started through systemd socket activation, the sockets passed in are served instead of the defaults.
+    #[cfg(unix)]
+    if startup_args.tcp_addresses_default && startup_args.udp_addresses_default {
+        use_activation_sockets(&mut startup_args)?;
+    }
+
+    let access_log = match &startup_args.log_file {
+        Some(path) => Some(AccessLog::open(path).map_err(|error| ServerError::LogFile(path.clone(), error))?),
+        None => None,
+    };
+
+    let csv_log = match &startup_args.csv_log {
+        Some(path) => Some(CsvLog::create(path).map_err(|error| ServerError::CsvLog(path.clone(), error))?),
+        None => None,
+    };
+
+    #[cfg(feature = "tls")]
+    let tls_acceptor = tls::load_acceptor(&startup_args).map_err(ServerError::Tls)?;
+
+    let mut failures = Vec::new();
+    let mut tcp_formats = HashMap::new();
+    let mut udp_formats = HashMap::new();
+    let tcp_listeners = bind_tcp_listeners(&startup_args, &mut failures, &mut tcp_formats);
+    let unix_listeners = bind_unix_listeners(&startup_args, &mut failures);
+    let udp_sockets = bind_udp_sockets(&startup_args, &mut failures, &mut udp_formats);
+
+    // If nothing could be bound, the failures are reported all at once in the returned error, which is printed in the
+    // requested error format. Otherwise they're just logged and the sockets that were bound are served.
+    if tcp_listeners.is_empty() && unix_listeners.is_empty() && udp_sockets.is_empty() {
+        return Err(ServerError::NoSocketsBound(failures));
+    }
+
+    for failure in &failures {
+        error!("{}", failure.message);
+    }
 
-    if tcp_listeners.is_empty() && udp_sockets.is_empty() {
-        eprintln!("ERROR! No TCP nor UDP sockets could be bound. Aborting.");
-        exit(1);
+    if (!startup_args.tcp_addresses.is_empty() || !startup_args.tcp_fds.is_empty()) && tcp_listeners.is_empty() {
+        warn!("WARNING! No TCP sockets were bound!");
     }
 
-    if !startup_args.tcp_addresses.is_empty() && tcp_listeners.is_empty() {
-        eprintln!("WARNING! No TCP sockets were bound!");
+    if !startup_args.unix_paths.is_empty() && unix_listeners.is_empty() {
+        warn!("WARNING! No Unix sockets were bound!");
     }
 
-    if !startup_args.udp_addresses.is_empty() && udp_sockets.is_empty() {
-        eprintln!("WARNING! No UDP sockets were bound!");
+    if (!startup_args.udp_addresses.is_empty() || !startup_args.udp_fds.is_empty()) && udp_sockets.is_empty() {
+        warn!("WARNING! No UDP sockets were bound!");
     }
 
-    let mut handles = Vec::with_capacity(tcp_listeners.len() + udp_sockets.len());
+    let startup_args = Arc::new(startup_args);
+    let shared = Arc::new(SharedState {
+        provider,
+        ..SharedState::new(
+            tcp_listeners.len() + unix_listeners.len() + udp_sockets.len(),
+            csv_log,
+            access_log,
+            tcp_formats,
+            udp_formats,
+            #[cfg(feature = "tls")]
+            tls_acceptor,
+            &startup_args,
+        )
+    });
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
 
-    handles.extend(tcp_listeners.into_iter().map(|listener| {
-        tokio::task::spawn_local(async move {
-            run_tcp_server(startup_args.verbose, startup_args.silent, listener).await;
+    let tcp_handles: Vec<_> = tcp_listeners
+        .into_iter()
+        .map(|listener| {
+            let startup_args = Arc::clone(&startup_args);
+            let shared = Arc::clone(&shared);
+            let shutdown_receiver = shutdown_receiver.clone();
+            tokio::spawn(async move {
+                run_tcp_server(startup_args, shared, listener, shutdown_receiver).await;
+            })
         })
-    }));
+        .collect();
 
-    handles.extend(udp_sockets.into_iter().map(|socket| {
-        tokio::task::spawn_local(async move {
-            run_udp_server(startup_args.verbose, startup_args.silent, socket).await;
+    let unix_handles: Vec<_> = unix_listeners
+        .into_iter()
+        .map(|(listener, path)| {
+            let startup_args = Arc::clone(&startup_args);
+            let shared = Arc::clone(&shared);
+            let shutdown_receiver = shutdown_receiver.clone();
+            tokio::spawn(async move {
+                run_unix_server(startup_args, shared, listener, path, shutdown_receiver).await;
+            })
         })
-    }));
+        .collect();
 
-    let _ = tokio::signal::ctrl_c().await;
-    printlnif!(!startup_args.silent, "Received break signal, shutting down");
-    for handle in handles {
+    let udp_handles: Vec<_> = udp_sockets
+        .into_iter()
+        .map(|socket| {
+            let startup_args = Arc::clone(&startup_args);
+            let shared = Arc::clone(&shared);
+            let shutdown_receiver = shutdown_receiver.clone();
+            tokio::spawn(async move {
+                run_udp_server(&startup_args, shared, socket, shutdown_receiver).await;
+            })
+        })
+        .collect();
+
+    let metrics_handle = match startup_args.metrics_address {
+        Some(addr) => bind_metrics_listener(addr).await.map(|listener| {
+            let shared = Arc::clone(&shared);
+            tokio::spawn(async move {
+                metrics::run_metrics_server(listener, || shared.metrics_snapshot()).await;
+            })
+        }),
+        None => None,
+    };
+
+    let stats_handle = startup_args.stats_interval.map(|interval| {
+        let shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            print_stats_periodically(interval, &shared).await;
+        })
+    });
+
+    tokio::select! {
+        _ = shutdown => {}
+        _ = shared.once_served.notified(), if startup_args.once => info!("Served a single request, shutting down"),
+    }
+    for handle in stats_handle.into_iter().chain(metrics_handle) {
         handle.abort();
     }
+
+    // The TCP listeners stop accepting new connections, while the ones already accepted are given some time to
+    // finish. The UDP servers only check for shutdown between packets, so any response being sent is completed first.
+    let deadline = Instant::now() + startup_args.shutdown_timeout;
+    let _ = shutdown_sender.send(true);
+    for handle in tcp_handles.into_iter().chain(unix_handles).chain(udp_handles) {
+        let _ = handle.await;
+    }
+
+    let active_connections = shared.active_connections.load(Ordering::SeqCst);
+    if active_connections != 0 {
+        info!("Waiting for {active_connections} TCP connections to finish");
+        if tokio::time::timeout_at(deadline, shared.wait_connections_drained()).await.is_err() {
+            // The remaining connection tasks stop, dropping their connections, as soon as they're told to.
+            let aborted = shared.active_connections.load(Ordering::SeqCst);
+            shared.terminate_connections();
+            warn!("WARNING! Forcibly terminated {aborted} TCP connections after the shutdown grace period");
+        }
+    }
+
+    Ok(())
+}
+
+/// Serves the sockets passed by systemd socket activation, if there are any, instead of the default addresses.
+#[cfg(unix)]
+fn use_activation_sockets(startup_args: &mut StartupArguments) -> Result<(), ServerError> {
+    let fds = std::mem::take(&mut startup_args.activation_fds);
+    if fds.is_empty() {
+        return Ok(());
+    }
+
+    for fd in fds {
+        match utils::socket_type(fd) {
+            Ok(libc::SOCK_STREAM) => startup_args.tcp_fds.push(fd),
+            Ok(libc::SOCK_DGRAM) => startup_args.udp_fds.push(fd),
+            _ => return Err(ServerError::UnsupportedActivationSocket(fd)),
+        }
+    }
+
+    startup_args.tcp_addresses.clear();
+    startup_args.udp_addresses.clear();
+    startup_args.tcp_addresses_default = false;
+    startup_args.udp_addresses_default = false;
+    Ok(())
+}
+
+/// Binds the listener for the metrics endpoint. If this fails the error is logged, and metrics aren't served.
+async fn bind_metrics_listener(addr: SocketAddr) -> Option<TcpListener> {
+    debug!("Binding metrics listener at {addr}");
+    match TcpListener::bind(addr).await {
+        Ok(listener) => {
+            info!("Serving metrics at http://{addr}/metrics");
+            Some(listener)
+        }
+        Err(error) => {
+            error!("Failed to bind metrics listener at {addr}: {error}");
+            None
+        }
+    }
 }
 
-fn bind_tcp_listeners(verbose: bool, addresses: &Vec<SocketAddr>) -> Vec<TcpListener> {
+/// Binds the TCP listeners, adding the format given to each address with an `=format` suffix to `formats` under the
+/// listener's local address.
+fn bind_tcp_listeners(
+    startup_args: &StartupArguments,
+    failures: &mut Vec<BindFailure>,
+    formats: &mut HashMap<SocketAddr, AddressFormat>,
+) -> Vec<TcpListener> {
     let mut tcp_listeners = Vec::new();
-    for addr in addresses {
-        printlnif!(verbose, "Binding TCP socket at {addr}");
+    for addr in &startup_args.tcp_addresses {
+        debug!("Binding TCP socket at {addr}");
 
-        let std_listener = match std::net::TcpListener::bind(addr) {
+        let std_listener = match bind_tcp_listener(startup_args, *addr) {
             Ok(l) => l,
+            Err(error) if startup_args.tcp_addresses_default && addr.is_ipv6() && utils::is_address_family_unsupported(&error) => {
+                info!("IPv6 is not available on this host, not listening for TCP on {addr}");
+                continue;
+            }
             Err(error) => {
-                eprintln!("Failed to bind TCP socket at {addr}: {error}");
+                failures.push(BindFailure::new(addr, format!("Failed to bind TCP socket at {addr}: {error}")));
                 continue;
             }
         };
 
         if let Err(error) = std_listener.set_nonblocking(true) {
-            eprintln!("Failed to set TCP socket {addr} as nonblocking: {error}");
+            failures.push(BindFailure::new(
+                addr,
+                format!("Failed to set TCP socket {addr} as nonblocking: {error}"),
+            ));
             continue;
         }
 
         let listener = match TcpListener::from_std(std_listener) {
             Ok(l) => l,
             Err(error) => {
-                eprintln!("Failed to convert `std::net::TcpListener` into `tokio::net::TcpListener`: {error}");
+                let message = format!("Failed to convert `std::net::TcpListener` into `tokio::net::TcpListener`: {error}");
+                failures.push(BindFailure::new(addr, message));
                 continue;
             }
         };
 
-        printlnif!(verbose, "Successfully bound TCP socket at {addr}");
+        debug!("Successfully bound TCP socket at {addr}");
+        if let (Some(format), Ok(local_address)) = (startup_args.tcp_address_formats.get(addr), listener.local_addr()) {
+            formats.insert(local_address, format.clone());
+        }
         tcp_listeners.push(listener)
     }
 
+    #[cfg(unix)]
+    for fd in &startup_args.tcp_fds {
+        debug!("Adopting TCP socket from file descriptor {fd}");
+        let result = utils::adopt_socket_fd(*fd, libc::SOCK_STREAM).and_then(|socket| {
+            socket.set_nonblocking(true)?;
+            TcpListener::from_std(socket.into())
+        });
+
+        match result {
+            Ok(listener) => {
+                debug!("Successfully adopted TCP socket from file descriptor {fd}");
+                tcp_listeners.push(listener);
+            }
+            Err(error) => failures.push(BindFailure::new(
+                format!("fd:{fd}"),
+                format!("Failed to adopt TCP socket from file descriptor {fd}: {error}"),
+            )),
+        }
+    }
+
     tcp_listeners
 }
 
-fn bind_udp_sockets(verbose: bool, addresses: &Vec<SocketAddr>) -> Vec<UdpSocket> {
+/// Binds the Unix domain socket listeners, first removing any stale socket file left at their paths by a previous run
+/// that didn't shut down cleanly.
+#[cfg(unix)]
+fn bind_unix_listeners(startup_args: &StartupArguments, failures: &mut Vec<BindFailure>) -> Vec<(tokio::net::UnixListener, PathBuf)> {
+    let mut unix_listeners = Vec::new();
+    for path in &startup_args.unix_paths {
+        debug!("Binding Unix socket at {}", path.display());
+        remove_stale_unix_socket(path);
+
+        match tokio::net::UnixListener::bind(path) {
+            Ok(listener) => {
+                debug!("Successfully bound Unix socket at {}", path.display());
+                unix_listeners.push((listener, path.clone()));
+            }
+            Err(error) => failures.push(BindFailure::new(
+                format!("unix:{}", path.display()),
+                format!("Failed to bind Unix socket at {}: {error}", path.display()),
+            )),
+        }
+    }
+
+    unix_listeners
+}
+
+#[cfg(not(unix))]
+fn bind_unix_listeners(_startup_args: &StartupArguments, _failures: &mut Vec<BindFailure>) -> Vec<(std::convert::Infallible, PathBuf)> {
+    Vec::new()
+}
+
+/// Removes the socket file at the given path if nothing is listening on it anymore. Files that aren't sockets, and
+/// sockets still in use, are left alone so binding fails instead.
+#[cfg(unix)]
+fn remove_stale_unix_socket(path: &std::path::Path) {
+    use std::os::unix::fs::FileTypeExt;
+
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+    if is_socket && std::os::unix::net::UnixStream::connect(path).is_err() {
+        debug!("Removing stale Unix socket file at {}", path.display());
+        if let Err(error) = std::fs::remove_file(path) {
+            error!("Failed to remove stale Unix socket file at {}: {error}", path.display());
+        }
+    }
+}
+
+/// Binds the UDP sockets, adding the format given to each address with an `=format` suffix to `formats` under the
+/// sockets' local address.
+fn bind_udp_sockets(
+    startup_args: &StartupArguments,
+    failures: &mut Vec<BindFailure>,
+    formats: &mut HashMap<SocketAddr, ResponseFormat>,
+) -> Vec<UdpSocket> {
     let mut udp_sockets = Vec::new();
-    for addr in addresses {
-        printlnif!(verbose, "Binding UDP socket at {addr}");
+    for addr in &startup_args.udp_addresses {
+        debug!("Binding UDP socket at {addr}");
 
-        let std_socket = match std::net::UdpSocket::bind(addr) {
+        let std_socket = match bind_udp_socket(startup_args, *addr) {
             Ok(s) => s,
+            Err(error) if startup_args.udp_addresses_default && addr.is_ipv6() && utils::is_address_family_unsupported(&error) => {
+                info!("IPv6 is not available on this host, not listening for UDP on {addr}");
+                continue;
+            }
             Err(error) => {
-                eprintln!("Failed to bind UDP socket at {addr}: {error}");
+                failures.push(BindFailure::new(addr, format!("Failed to bind UDP socket at {addr}: {error}")));
                 continue;
             }
         };
 
         if let Err(error) = std_socket.set_nonblocking(true) {
-            eprintln!("Failed to set UDP socket {addr} as nonblocking: {error}");
+            failures.push(BindFailure::new(
+                addr,
+                format!("Failed to set UDP socket {addr} as nonblocking: {error}"),
+            ));
             continue;
         }
 
+        if startup_args.udp_broadcast && addr.is_ipv4() {
+            if let Err(error) = std_socket.set_broadcast(true) {
+                failures.push(BindFailure::new(
+                    addr,
+                    format!("Failed to enable broadcast on UDP socket {addr}: {error}"),
+                ));
+                continue;
+            }
+        }
+
         let socket = match UdpSocket::from_std(std_socket) {
             Ok(s) => s,
             Err(error) => {
-                eprintln!("Failed to convert `std::net::UdpSocket` into `tokio::net::UdpSocket`: {error}");
+                let message = format!("Failed to convert `std::net::UdpSocket` into `tokio::net::UdpSocket`: {error}");
+                failures.push(BindFailure::new(addr, message));
                 continue;
             }
         };
 
-        printlnif!(verbose, "Successfully bound UDP socket at {addr}");
-        udp_sockets.push(socket)
+        debug!("Successfully bound UDP socket at {addr}");
+        // Bind the other shards to the address the first one got, in case it was bound with port 0.
+        let shard_addr = socket.local_addr().unwrap_or(*addr);
+        if let Some(format) = startup_args.udp_address_formats.get(addr) {
+            formats.insert(shard_addr, format.clone());
+        }
+        udp_sockets.push(socket);
+
+        for shard in 1..startup_args.udp_shards.get() {
+            let result = bind_udp_socket(startup_args, shard_addr).and_then(|std_socket| {
+                std_socket.set_nonblocking(true)?;
+                if startup_args.udp_broadcast && shard_addr.is_ipv4() {
+                    std_socket.set_broadcast(true)?;
+                }
+                UdpSocket::from_std(std_socket)
+            });
+
+            match result {
+                Ok(socket) => {
+                    debug!("Successfully bound UDP shard {shard} at {shard_addr}");
+                    udp_sockets.push(socket);
+                }
+                Err(error) => failures.push(BindFailure::new(
+                    shard_addr,
+                    format!("Failed to bind UDP shard {shard} at {shard_addr}: {error}"),
+                )),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    for fd in &startup_args.udp_fds {
+        debug!("Adopting UDP socket from file descriptor {fd}");
+        let result = utils::adopt_socket_fd(*fd, libc::SOCK_DGRAM).and_then(|socket| {
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket.into())
+        });
+
+        match result {
+            Ok(socket) => {
+                debug!("Successfully adopted UDP socket from file descriptor {fd}");
+                udp_sockets.push(socket);
+            }
+            Err(error) => failures.push(BindFailure::new(
+                format!("fd:{fd}"),
+                format!("Failed to adopt UDP socket from file descriptor {fd}: {error}"),
+            )),
+        }
     }
 
     udp_sockets
 }
 
-async fn run_tcp_server(verbose: bool, silent: bool, listener: TcpListener) {
-    let addr = listener.local_addr().unwrap();
+fn bind_tcp_listener(startup_args: &StartupArguments, addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Like `std::net::TcpListener::bind`, always set SO_REUSEADDR on Unix so restarting isn't blocked by connections
+    // left in TIME_WAIT. On Windows this would allow other processes to steal the port, so it's left opt-in there.
+    set_reuse_options(&socket, startup_args.reuse_addr || cfg!(unix), startup_args.reuse_port)?;
+    if startup_args.v6only && addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
 
-    let mut counter = 0u64;
-    let mut error_counter = 0;
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &startup_args.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
 
-    loop {
-        counter += 1;
-        let (mut stream, remote_address) = match listener.accept().await {
-            Ok(t) => t,
-            Err(error) => {
-                printlnif!(!silent, "Error while accepting from TCP socket {addr}: {error}");
-                error_counter += 1;
-                if error_counter >= 10 {
-                    break;
-                }
-                continue;
-            }
-        };
-        printlnif!(!silent, "TCP listener {addr} accepted connection from {remote_address}");
+    socket.bind(&addr.into())?;
+    socket.listen(TCP_BACKLOG)?;
+    Ok(socket.into())
+}
 
-        tokio::task::spawn_local(async move {
-            let mut buf = [0u8; 256];
-            let mut cursor = Cursor::new(buf.as_mut());
-            let _ = write!(cursor, "you: {remote_address} | connection_number: {counter}");
+fn bind_udp_socket(startup_args: &StartupArguments, addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    set_reuse_options(
+        &socket,
+        startup_args.reuse_addr || startup_args.udp_reuse_addr,
+        startup_args.reuse_port || startup_args.udp_shards.get() > 1,
+    )?;
+    if startup_args.v6only && addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
 
-            match stream.write_all(&buf).await {
-                Ok(()) => {
-                    printlnif!(
-                        verbose,
-                        "TCP socket {addr} responded to {remote_address} with connection number {counter}"
-                    )
-                }
-                Err(error) => {
-                    eprintln!("TCP socket {addr} failed to respond to {remote_address}: {error}");
-                }
-            }
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &startup_args.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
 
-            let _ = stream.shutdown().await;
-        });
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Sets `SO_REUSEADDR` and `SO_REUSEPORT` on a socket that is about to be bound, as requested.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn set_reuse_options(socket: &Socket, reuse_addr: bool, reuse_port: bool) -> io::Result<()> {
+    if reuse_addr {
+        socket.set_reuse_address(true)?;
     }
-    eprintln!("TCP socket {addr} closed due to too many consecutive errors.");
+
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+
+    Ok(())
 }
 
-async fn run_udp_server(verbose: bool, silent: bool, socket: UdpSocket) {
-    let addr = socket.local_addr().unwrap();
-    let mut buf = [0u8; UDP_BUF_SIZE];
+/// Binds the additional sockets, at the same IP address as a listening UDP socket, that responses are sent from when
+/// `--udp-response-ports` is specified.
+fn bind_response_sockets(startup_args: &StartupArguments, addr: SocketAddr) -> Vec<UdpSocket> {
+    let mut response_sockets = Vec::with_capacity(startup_args.udp_response_ports.len());
+    for port in &startup_args.udp_response_ports {
+        let response_addr = SocketAddr::new(addr.ip(), *port);
+        let result = bind_udp_socket(startup_args, response_addr).and_then(|std_socket| {
+            std_socket.set_nonblocking(true)?;
+            UdpSocket::from_std(std_socket)
+        });
+
+        match result {
+            Ok(socket) => {
+                debug!("Successfully bound UDP response socket at {response_addr}");
+                response_sockets.push(socket);
+            }
+            Err(error) => error!("Failed to bind UDP response socket at {response_addr}: {error}"),
+        }
+    }
+
+    response_sockets
+}
 
+async fn run_tcp_server(
+    startup_args: Arc<StartupArguments>,
+    shared: Arc<SharedState>,
+    listener: TcpListener,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        Err(error) => {
+            error!("Failed to get the local address of a TCP listener, closing it: {error}");
+            shared.remove_socket();
+            return;
+        }
+    };
     let mut counter = 0u64;
     let mut error_counter = 0;
+    let mut injected_errors = startup_args.inject_errors;
+    let mut last_activity = Instant::now();
+    let handler_pool = startup_args.prespawn.map(|size| TcpHandlerPool::new(size, &startup_args, &shared));
 
     loop {
-        counter += 1;
-        let (buf_len, remote_address) = match socket.recv_from(&mut buf).await {
+        let result = match take_injected_error(&mut injected_errors) {
+            Some(error) => Err(error),
+            None => loop {
+                tokio::select! {
+                    biased;
+                    _ = shutdown.changed() => {
+                        debug!("TCP listener {addr} shutting down");
+                        return;
+                    }
+                    result = listener.accept() => break result,
+                    _ = wait_idle(startup_args.unbind_idle, last_activity) => {
+                        if shared.try_unbind_idle_socket() {
+                            info!("TCP listener {addr} unbound after being idle for too long");
+                            return;
+                        }
+
+                        // This is the last socket being served, so check again after another full idle period.
+                        last_activity = Instant::now();
+                    }
+                }
+            },
+        };
+
+        let (stream, remote_address) = match result {
             Ok(t) => {
                 error_counter = 0;
+                counter += 1;
+                last_activity = Instant::now();
                 t
             }
             Err(error) => {
-                printlnif!(!silent, "Error while receiving from UDP socket {addr}: {error}");
+                info!("Error while accepting from TCP socket {addr}: {error}");
+                shared.record_error("tcp");
                 error_counter += 1;
                 if error_counter >= 10 {
                     break;
@@ -184,23 +1075,1533 @@ async fn run_udp_server(verbose: bool, silent: bool, socket: UdpSocket) {
                 continue;
             }
         };
+        info!("TCP listener {addr} accepted connection from {remote_address}");
+        if startup_args.warn_on_private && utils::is_private_ip(remote_address.ip()) {
+            warn!("WARNING! TCP listener {addr} accepted connection from private address {remote_address}");
+        }
+
+        shared.record_tcp_connection();
 
-        printlnif!(!silent, "UDP socket {addr} received {buf_len} bytes from {remote_address}");
-        let mut cursor = Cursor::new(buf.as_mut());
-        let _ = write!(cursor, "you: {remote_address} | bytes: {buf_len} | packet_number: {counter}");
-        let len = cursor.position() as usize;
+        // With --proxy-protocol, the connection's peer is the proxy, so the client is only checked once the PROXY
+        // header says who it is.
+        if !startup_args.proxy_protocol {
+            if !accept_tcp_client(&startup_args, &shared, addr, remote_address) {
+                continue;
+            }
 
-        match socket.send_to(&buf[..len], remote_address).await {
-            Ok(bytes_sent) if bytes_sent != len => {
-                eprintln!("UDP socket {addr} should have sent {len} bytes to {remote_address}, but {bytes_sent} were sent")
+            if !shared.claim_request() {
+                debug!("TCP listener {addr} closed connection from {remote_address}, a request was already served");
+                continue;
             }
-            Ok(_) => printlnif!(
-                verbose,
-                "UDP socket {addr} responded to {remote_address} with packet number {counter}"
-            ),
-            Err(error) => eprintln!("UDP socket {addr} failed to respond to {remote_address}: {error}"),
+        }
+
+        let permit = match &shared.connection_permits {
+            Some(permits) => match startup_args.connection_limit_policy {
+                ConnectionLimitPolicy::Reject => match Arc::clone(permits).try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        debug!("TCP listener {addr} closed connection from {remote_address}, too many connections");
+                        if let Some(message) = &startup_args.full_message {
+                            let message = match shared.serves_http(&startup_args, addr) {
+                                true => http::format_response(
+                                    "503 Service Unavailable",
+                                    "text/plain",
+                                    &[],
+                                    message.as_bytes(),
+                                    false,
+                                    true,
+                                    false,
+                                ),
+                                false => message.clone().into_bytes(),
+                            };
+                            send_full_message(stream, message);
+                        }
+                        continue;
+                    }
+                },
+                ConnectionLimitPolicy::Queue => tokio::select! {
+                    biased;
+                    _ = shutdown.changed() => {
+                        debug!("TCP listener {addr} shutting down");
+                        return;
+                    }
+                    permit = Arc::clone(permits).acquire_owned() => permit.ok(),
+                },
+            },
+            None => None,
         };
-    }
 
-    eprintln!("UDP socket {addr} closed due to too many consecutive errors.");
+        let mut connection = TcpConnection {
+            stream,
+            local_address: addr,
+            remote_address,
+            counter,
+            #[cfg(feature = "otlp")]
+            span: otlp::start_request_span("tcp", addr, remote_address),
+            guard: if startup_args.proxy_protocol {
+                ConnectionGuard::unclaimed(&shared)
+            } else {
+                ConnectionGuard::new(&shared)
+            },
+            permit,
+        };
+
+        if let Some(pool) = &handler_pool {
+            connection = match pool.try_dispatch(connection) {
+                Ok(()) => continue,
+                Err(connection) => connection,
+            };
+        }
+
+        let startup_args = Arc::clone(&startup_args);
+        let shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = handle_tcp_connection(&startup_args, &shared, connection) => {}
+                _ = shared.wait_terminated() => {}
+            }
+        });
+    }
+    shared.remove_socket();
+    error!("TCP socket {addr} closed due to too many consecutive errors.");
+}
+
+/// Serves connections to a Unix domain socket until shutdown, then removes its socket file.
+#[cfg(unix)]
+async fn run_unix_server(
+    startup_args: Arc<StartupArguments>,
+    shared: Arc<SharedState>,
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let name = path.display().to_string();
+    let mut counter = 0u64;
+    let mut error_counter = 0;
+    let mut last_activity = Instant::now();
+
+    loop {
+        let result = tokio::select! {
+            biased;
+            _ = shutdown.changed() => {
+                debug!("Unix listener {name} shutting down");
+                break;
+            }
+            result = listener.accept() => result,
+            _ = wait_idle(startup_args.unbind_idle, last_activity) => {
+                if shared.try_unbind_idle_socket() {
+                    info!("Unix listener {name} unbound after being idle for too long");
+                    break;
+                }
+
+                // This is the last socket being served, so check again after another full idle period.
+                last_activity = Instant::now();
+                continue;
+            }
+        };
+
+        let stream = match result {
+            Ok((stream, _)) => {
+                error_counter = 0;
+                counter += 1;
+                last_activity = Instant::now();
+                stream
+            }
+            Err(error) => {
+                info!("Error while accepting from Unix socket {name}: {error}");
+                shared.record_error("tcp");
+                error_counter += 1;
+                if error_counter >= 10 {
+                    shared.remove_socket();
+                    error!("Unix socket {name} closed due to too many consecutive errors.");
+                    break;
+                }
+                continue;
+            }
+        };
+        info!("Unix listener {name} accepted connection number {counter}");
+        shared.record_tcp_connection();
+        if !shared.claim_request() {
+            debug!("Unix listener {name} closed connection number {counter}, a request was already served");
+            continue;
+        }
+
+        let permit = match &shared.connection_permits {
+            Some(permits) => match startup_args.connection_limit_policy {
+                ConnectionLimitPolicy::Reject => match Arc::clone(permits).try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        debug!("Unix listener {name} closed connection number {counter}, too many connections");
+                        if let Some(message) = &startup_args.full_message {
+                            send_full_message(stream, message.clone().into_bytes());
+                        }
+                        continue;
+                    }
+                },
+                ConnectionLimitPolicy::Queue => tokio::select! {
+                    biased;
+                    _ = shutdown.changed() => {
+                        debug!("Unix listener {name} shutting down");
+                        break;
+                    }
+                    permit = Arc::clone(permits).acquire_owned() => permit.ok(),
+                },
+            },
+            None => None,
+        };
+
+        let guard = ConnectionGuard::new(&shared);
+        let startup_args = Arc::clone(&startup_args);
+        let shared = Arc::clone(&shared);
+        let name = name.clone();
+        tokio::spawn(async move {
+            let _guard = guard;
+            let _permit = permit;
+            let mut stream = stream;
+            let respond = async {
+                let response = build_unix_response(&startup_args, &shared, counter);
+                match stream.write_all(&response).await {
+                    Ok(()) => {
+                        debug!("Unix socket {name} responded to connection number {counter}");
+                        shared.record_unix_served(
+                            tcp_format_name(&startup_args, &startup_args.tcp_format),
+                            &name,
+                            counter,
+                            response.len(),
+                        );
+                        if let Some(delay) = startup_args.delay_close {
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                    Err(error) => {
+                        error!("Unix socket {name} failed to respond to connection number {counter}: {error}");
+                        shared.record_error("tcp");
+                    }
+                }
+                let _ = stream.shutdown().await;
+            };
+
+            tokio::select! {
+                _ = respond => {}
+                _ = shared.wait_terminated() => {}
+            }
+        });
+    }
+
+    drop(listener);
+    if let Err(error) = std::fs::remove_file(&path) {
+        error!("Failed to remove Unix socket file at {name}: {error}");
+    }
+}
+
+#[cfg(not(unix))]
+async fn run_unix_server(
+    _startup_args: Arc<StartupArguments>,
+    _shared: Arc<SharedState>,
+    listener: std::convert::Infallible,
+    _path: PathBuf,
+    _shutdown: watch::Receiver<bool>,
+) {
+    match listener {}
+}
+
+/// Builds the response for a connection to a Unix domain socket. These have no meaningful peer address, so the
+/// client is shown as "unix", with no IP address or port, and is told apart only by its connection number.
+#[cfg(unix)]
+fn build_unix_response(startup_args: &StartupArguments, shared: &SharedState, counter: u64) -> Vec<u8> {
+    let context = RequestContext {
+        protocol: "unix",
+        local_address: None,
+        remote_address: None,
+        client: ClientAddress {
+            address: "unix".into(),
+            ip: String::new(),
+            port: 0,
+        },
+        counter,
+        bytes: None,
+        ip_count: None,
+        hostname: None,
+        uptime: shared.started_at.elapsed(),
+        user_agent: None,
+        tls: None,
+    };
+
+    shared.response_builder(startup_args, &startup_args.tcp_format, context).build()
+}
+
+/// A TCP connection accepted by a listener, waiting to be handled.
+struct TcpConnection {
+    stream: TcpStream,
+    local_address: SocketAddr,
+    remote_address: SocketAddr,
+    counter: u64,
+    #[cfg(feature = "otlp")]
+    span: opentelemetry::global::BoxedSpan,
+    guard: ConnectionGuard,
+    /// The permit taken for this connection if `--max-connections` was specified, released when the connection is
+    /// dropped.
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+#[instrument(name = "tcp_connection", skip_all, fields(remote_address = %connection.remote_address))]
+async fn handle_tcp_connection(startup_args: &StartupArguments, shared: &SharedState, connection: TcpConnection) {
+    let TcpConnection {
+        mut stream,
+        local_address: addr,
+        remote_address,
+        counter,
+        #[cfg(feature = "otlp")]
+            span: _span,
+        mut guard,
+        permit: _permit,
+    } = connection;
+
+    let remote_address = if startup_args.proxy_protocol {
+        match tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(&mut stream)).await {
+            Ok(Ok(Some(client_address))) => {
+                debug!("TCP socket {addr} received connection from {remote_address} proxied for {client_address}");
+                client_address
+            }
+            Ok(Ok(None)) => remote_address,
+            Ok(Err(error)) => {
+                error!("TCP socket {addr} failed to read PROXY header from {remote_address}: {error}");
+                shared.record_error("tcp");
+                return;
+            }
+            Err(_) => {
+                error!("TCP socket {addr} timed out waiting for PROXY header from {remote_address}");
+                shared.record_error("tcp");
+                return;
+            }
+        }
+    } else {
+        remote_address
+    };
+
+    if startup_args.proxy_protocol {
+        if !accept_tcp_client(startup_args, shared, addr, remote_address) {
+            return;
+        }
+
+        if !guard.claim() {
+            debug!("TCP socket {addr} closed connection from {remote_address}, a request was already served");
+            return;
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = &shared.tls_acceptor {
+        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => {
+                if let Some(tls) = stream.tls_parameters() {
+                    debug!(
+                        "TCP socket {addr} completed TLS handshake with {remote_address} using {} and {}",
+                        tls.version, tls.cipher
+                    );
+                }
+                respond_tcp(startup_args, shared, stream, addr, remote_address, counter).await;
+            }
+            Ok(Err(error)) => {
+                error!("TCP socket {addr} failed TLS handshake with {remote_address}: {error}");
+                shared.record_error("tcp");
+            }
+            Err(_) => {
+                error!("TCP socket {addr} timed out waiting for TLS handshake with {remote_address}");
+                shared.record_error("tcp");
+            }
+        }
+        return;
+    }
+
+    respond_tcp(startup_args, shared, stream, addr, remote_address, counter).await;
+}
+
+/// Sends the `--full-message` to a client rejected by `--max-connections`, then closes the connection. This is done on
+/// a task of its own, so a client that doesn't read it can't hold up the listener.
+fn send_full_message<S: AsyncWrite + Unpin + Send + 'static>(mut stream: S, message: Vec<u8>) {
+    tokio::spawn(async move {
+        let _ = tokio::time::timeout(FULL_MESSAGE_TIMEOUT, stream.write_all(&message)).await;
+        let _ = stream.shutdown().await;
+    });
+}
+
+/// A stream that a TCP connection's response is written to, which gives access to the underlying TCP stream.
+pub(crate) trait AsTcpStream {
+    fn tcp_stream(&self) -> &TcpStream;
+
+    /// The parameters negotiated with the client, if the connection is served over TLS.
+    fn tls_parameters(&self) -> Option<TlsParameters> {
+        None
+    }
+}
+
+impl AsTcpStream for TcpStream {
+    fn tcp_stream(&self) -> &TcpStream {
+        self
+    }
+}
+
+/// Writes the response to a TCP client over the given stream, then closes it.
+async fn respond_tcp<S: AsyncRead + AsyncWrite + AsTcpStream + Unpin>(
+    startup_args: &StartupArguments,
+    shared: &SharedState,
+    mut stream: S,
+    addr: SocketAddr,
+    remote_address: SocketAddr,
+    counter: u64,
+) {
+    if shared.serves_http(startup_args, addr) {
+        respond_http(startup_args, shared, &mut stream, addr, remote_address, counter).await;
+        let _ = stream.shutdown().await;
+        return;
+    }
+
+    let response = build_tcp_response(startup_args, shared, &stream, addr, remote_address, counter, None).await;
+    if startup_args.length_framed {
+        respond_length_framed(startup_args, shared, &mut stream, &response, addr, remote_address, counter).await;
+        let _ = stream.shutdown().await;
+        return;
+    }
+
+    let sent_at = Instant::now();
+    match write_tcp_response(&mut stream, &response, startup_args.split_response).await {
+        Ok(()) => {
+            debug!("TCP socket {addr} responded to {remote_address} with connection number {counter}");
+            shared.record_served(
+                "tcp",
+                tcp_format_name(startup_args, shared.tcp_format(startup_args, addr)),
+                addr,
+                remote_address,
+                counter,
+                response.len(),
+            );
+
+            if startup_args.roundtrip {
+                measure_roundtrip(&mut stream, addr, remote_address, sent_at).await;
+            }
+
+            if let Some(delay) = startup_args.delay_close {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        Err(error) => {
+            error!("TCP socket {addr} failed to respond to {remote_address}: {error}");
+            shared.record_error("tcp");
+        }
+    }
+
+    let _ = stream.shutdown().await;
+}
+
+/// Builds the response for a TCP client in the requested format, recording the request for `--track-per-ip` and
+/// `--first-seen-message`.
+async fn build_tcp_response<S: AsTcpStream>(
+    startup_args: &StartupArguments,
+    shared: &SharedState,
+    stream: &S,
+    addr: SocketAddr,
+    remote_address: SocketAddr,
+    counter: u64,
+    user_agent: Option<String>,
+) -> Vec<u8> {
+    let context = RequestContext {
+        protocol: "tcp",
+        local_address: Some(addr),
+        remote_address: Some(remote_address),
+        client: client_address(startup_args, remote_address),
+        counter,
+        bytes: None,
+        ip_count: shared.record_ip(remote_address.ip()),
+        hostname: None,
+        uptime: shared.started_at.elapsed(),
+        user_agent,
+        tls: stream.tls_parameters(),
+    };
+
+    // The hostname and TCP info are only included in text responses, so they're only looked up for those.
+    let mut builder = shared.response_builder(startup_args, shared.tcp_format(startup_args, addr), context);
+    if builder.is_text() {
+        if startup_args.resolve_hostnames {
+            builder.context_mut().hostname = lookup_hostname(remote_address.ip()).await;
+        }
+        builder.first_seen(shared.record_first_seen(remote_address.ip()));
+        add_tcp_info_fields(startup_args, &mut builder, stream.tcp_stream(), remote_address);
+    }
+    builder.build()
+}
+
+/// Answers each request read from a TCP client with `--length-framed` with the response, until the client closes the
+/// connection. Requests and responses are both prefixed by their length as 4 bytes in network order.
+async fn respond_length_framed<S: AsyncRead + AsyncWrite + AsTcpStream + Unpin>(
+    startup_args: &StartupArguments,
+    shared: &SharedState,
+    stream: &mut S,
+    response: &[u8],
+    addr: SocketAddr,
+    remote_address: SocketAddr,
+    counter: u64,
+) {
+    let mut framed_response = Vec::with_capacity(4 + response.len());
+    framed_response.extend_from_slice(&(response.len() as u32).to_be_bytes());
+    framed_response.extend_from_slice(response);
+
+    let mut request = Vec::new();
+    loop {
+        let request_len = match stream.read_u32().await {
+            Ok(len) => len as usize,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return,
+            Err(error) => {
+                error!("TCP socket {addr} failed to read request from {remote_address}: {error}");
+                shared.record_error("tcp");
+                return;
+            }
+        };
+
+        if request_len > startup_args.max_request_size {
+            debug!(
+                "TCP socket {addr} closed connection from {remote_address} after a request of {request_len} bytes, over the maximum size"
+            );
+            return;
+        }
+
+        request.resize(request_len, 0);
+        if let Err(error) = stream.read_exact(&mut request).await {
+            error!("TCP socket {addr} failed to read request from {remote_address}: {error}");
+            shared.record_error("tcp");
+            return;
+        }
+
+        if let Err(error) = write_tcp_response(stream, &framed_response, startup_args.split_response).await {
+            error!("TCP socket {addr} failed to respond to {remote_address}: {error}");
+            shared.record_error("tcp");
+            return;
+        }
+
+        debug!("TCP socket {addr} responded to a request of {request_len} bytes from {remote_address} with connection number {counter}");
+        shared.record_served(
+            "tcp",
+            tcp_format_name(startup_args, shared.tcp_format(startup_args, addr)),
+            addr,
+            remote_address,
+            counter,
+            framed_response.len(),
+        );
+    }
+}
+
+/// Answers each HTTP request read from a TCP client with `--http` with the response as the body, until the client
+/// closes the connection or doesn't ask for it to be kept alive.
+async fn respond_http<S: AsyncRead + AsyncWrite + AsTcpStream + Unpin>(
+    startup_args: &StartupArguments,
+    shared: &SharedState,
+    stream: &mut S,
+    addr: SocketAddr,
+    remote_address: SocketAddr,
+    counter: u64,
+) {
+    let content_type = match (&startup_args.body_template, shared.tcp_format(startup_args, addr)) {
+        (Some(_), _) => startup_args.body_content_type,
+        (None, ResponseFormat::Json) => "application/json",
+        (None, ResponseFormat::IpBytes | ResponseFormat::IpPortBytes) => "application/octet-stream",
+        _ => "text/plain; charset=utf-8",
+    };
+
+    let mut buf = Vec::new();
+    loop {
+        let head = match tokio::time::timeout(HTTP_KEEP_ALIVE_TIMEOUT, http::read_request_head(stream, &mut buf)).await {
+            Ok(Ok(Some(head))) => head,
+            Ok(Ok(None)) => return,
+            Ok(Err(error)) => {
+                debug!("TCP socket {addr} failed to read HTTP request from {remote_address}: {error}");
+                if let Some(status) = error.status() {
+                    let _ = stream
+                        .write_all(&http::format_response(status, "text/plain", &[], &[], false, true, false))
+                        .await;
+                } else {
+                    shared.record_error("tcp");
+                }
+                return;
+            }
+            Err(_) => {
+                debug!("TCP socket {addr} closed idle HTTP connection from {remote_address}");
+                return;
+            }
+        };
+
+        let include_body = match head.method.as_str() {
+            "GET" => true,
+            "HEAD" => false,
+            _ => {
+                debug!("TCP socket {addr} rejected HTTP {} request from {remote_address}", head.method);
+                let _ = stream.write_all(http::METHOD_NOT_ALLOWED_RESPONSE).await;
+                return;
+            }
+        };
+
+        let client_address = if startup_args.trust_forwarded {
+            forwarded_client_address(startup_args, remote_address, &head.forwarded_for)
+        } else {
+            remote_address
+        };
+        let user_agent = head.user_agent.clone();
+        let response = build_tcp_response(startup_args, shared, stream, addr, client_address, counter, user_agent).await;
+        let headers = match startup_args.http_headers {
+            true => client_headers(startup_args, client_address),
+            false => Vec::new(),
+        };
+        let chunked = startup_args.http_chunked && head.accepts_chunked;
+        let http_response = http::format_response("200 OK", content_type, &headers, &response, head.keep_alive, include_body, chunked);
+        if let Err(error) = write_tcp_response(stream, &http_response, startup_args.split_response).await {
+            error!("TCP socket {addr} failed to respond to {remote_address}: {error}");
+            shared.record_error("tcp");
+            return;
+        }
+
+        debug!(
+            "TCP socket {addr} responded to HTTP {} request from {remote_address} with connection number {counter}, user agent: {}",
+            head.method,
+            head.user_agent.as_deref().unwrap_or("none")
+        );
+        shared.record_served(
+            "tcp",
+            tcp_format_name(startup_args, shared.tcp_format(startup_args, addr)),
+            addr,
+            remote_address,
+            counter,
+            http_response.len(),
+        );
+        if !head.keep_alive {
+            return;
+        }
+    }
+}
+
+/// Gets the headers that tell the client its address with `--http-headers`, the same way reverse proxies tell it to
+/// the servers behind them. With `--hash-ip`, these have the hashed IP address like the body does.
+fn client_headers(startup_args: &StartupArguments, remote_address: SocketAddr) -> Vec<(&'static str, String)> {
+    let client = client_address(startup_args, remote_address);
+    vec![
+        ("X-Client-IP", client.ip.clone()),
+        ("X-Client-Port", client.port.to_string()),
+        ("X-Real-IP", client.ip),
+    ]
+}
+
+/// Finds the address of the client behind the proxies an HTTP request went through with `--trust-forwarded`, given
+/// the addresses it was forwarded for in order. The proxies are walked back from the direct peer for as long as they
+/// are trusted, so a client can't pose as another by adding to the header itself. The header is ignored if the direct
+/// peer isn't a trusted proxy.
+fn forwarded_client_address(
+    startup_args: &StartupArguments,
+    remote_address: SocketAddr,
+    forwarded_for: &[Option<SocketAddr>],
+) -> SocketAddr {
+    let is_trusted = |address: SocketAddr| startup_args.trusted_proxies.iter().any(|network| network.contains(address.ip()));
+
+    let mut client_address = remote_address;
+    for address in forwarded_for.iter().rev() {
+        if !is_trusted(client_address) {
+            break;
+        }
+
+        match address {
+            Some(address) => client_address = *address,
+            None => break,
+        }
+    }
+
+    client_address
+}
+
+/// The name of the format TCP responses are sent in, as used for the `format` label in metrics.
+fn tcp_format_name(startup_args: &StartupArguments, format: &ResponseFormat) -> &'static str {
+    match startup_args.body_template {
+        Some(_) => "body",
+        None => format.name(),
+    }
+}
+
+/// A pool of pre-spawned tasks that handle TCP connections, so accepted connections can be handed off to an idle
+/// task instead of spawning a new one. When the pool is dropped, the tasks finish the connections they're handling
+/// and then exit.
+struct TcpHandlerPool {
+    sender: mpsc::UnboundedSender<TcpConnection>,
+    idle_handlers: Arc<AtomicUsize>,
+}
+
+impl TcpHandlerPool {
+    fn new(size: usize, startup_args: &Arc<StartupArguments>, shared: &Arc<SharedState>) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        let idle_handlers = Arc::new(AtomicUsize::new(size));
+
+        for _ in 0..size {
+            let startup_args = Arc::clone(startup_args);
+            let shared = Arc::clone(shared);
+            let receiver = Arc::clone(&receiver);
+            let idle_handlers = Arc::clone(&idle_handlers);
+            tokio::spawn(async move {
+                loop {
+                    let maybe_connection = receiver.lock().await.recv().await;
+                    let Some(connection) = maybe_connection else {
+                        return;
+                    };
+
+                    tokio::select! {
+                        _ = handle_tcp_connection(&startup_args, &shared, connection) => {}
+                        _ = shared.wait_terminated() => return,
+                    }
+                    idle_handlers.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+
+        TcpHandlerPool { sender, idle_handlers }
+    }
+
+    /// Hands off a connection to an idle handler task. If there are none, the connection is given back.
+    #[allow(clippy::result_large_err)] // Boxing the connection would add an allocation for every connection.
+    fn try_dispatch(&self, connection: TcpConnection) -> Result<(), TcpConnection> {
+        let claimed = self
+            .idle_handlers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |idle| idle.checked_sub(1));
+        if claimed.is_err() {
+            return Err(connection);
+        }
+
+        self.sender.send(connection).map_err(|error| {
+            self.idle_handlers.fetch_add(1, Ordering::SeqCst);
+            error.0
+        })
+    }
+}
+
+/// Looks up the client's hostname through reverse DNS on a blocking thread, giving up after [`REVERSE_DNS_TIMEOUT`].
+async fn lookup_hostname(ip: IpAddr) -> Option<String> {
+    lookup_hostname_with(ip, utils::reverse_lookup).await
+}
+
+/// Looks up the client's hostname with the given blocking resolver, like [`lookup_hostname`].
+async fn lookup_hostname_with<F>(ip: IpAddr, resolver: F) -> Option<String>
+where
+    F: FnOnce(IpAddr) -> Option<String> + Send + 'static,
+{
+    match tokio::time::timeout(REVERSE_DNS_TIMEOUT, tokio::task::spawn_blocking(move || resolver(ip))).await {
+        Ok(Ok(Some(hostname))) => Some(hostname),
+        Ok(Ok(None)) => {
+            debug!("Reverse DNS lookup found no hostname for {ip}");
+            None
+        }
+        Ok(Err(error)) => {
+            error!("Reverse DNS lookup for {ip} failed: {error}");
+            None
+        }
+        Err(_) => {
+            debug!("Reverse DNS lookup for {ip} timed out");
+            None
+        }
+    }
+}
+
+/// Adds the TCP info fields requested to a text response for a TCP connection, logging them as well.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn add_tcp_info_fields(startup_args: &StartupArguments, builder: &mut ResponseBuilder, stream: &TcpStream, remote_address: SocketAddr) {
+    #[cfg(target_os = "linux")]
+    if startup_args.tcp_info {
+        match utils::get_tcp_info(stream) {
+            Ok(info) => {
+                info!("TCP connection from {remote_address} has a smoothed RTT of {}us", info.tcpi_rtt);
+                builder.text_field("rtt", format_args!("{}us", info.tcpi_rtt));
+            }
+            Err(error) => error!("Failed to get TCP info for connection from {remote_address}: {error}"),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if startup_args.report_mss {
+        match utils::get_tcp_mss(stream) {
+            Ok(mss) => {
+                info!("TCP connection from {remote_address} has an MSS of {mss} bytes");
+                builder.text_field("mss", mss);
+            }
+            Err(error) => error!("Failed to get MSS for connection from {remote_address}: {error}"),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if startup_args.tcp_timestamps {
+        match utils::get_tcp_info(stream) {
+            Ok(info) if info.tcpi_options & utils::TCPI_OPT_TIMESTAMPS != 0 => {
+                info!(
+                    "TCP connection from {remote_address} negotiated timestamps, with a timestamp-derived RTT of {}us",
+                    info.tcpi_rtt
+                );
+                builder.text_field("timestamps", "on");
+            }
+            Ok(_) => {
+                info!("TCP connection from {remote_address} did not negotiate timestamps");
+                builder.text_field("timestamps", "off");
+            }
+            Err(error) => error!("Failed to get TCP info for connection from {remote_address}: {error}"),
+        }
+    }
+}
+
+/// Writes a response to a TCP stream. If `split` is set, the response is written in that many separate writes with
+/// Nagle's algorithm disabled, yielding between them, so it is likely sent across several segments.
+async fn write_tcp_response<S: AsyncWrite + AsTcpStream + Unpin>(
+    stream: &mut S,
+    response: &[u8],
+    split: Option<NonZeroUsize>,
+) -> io::Result<()> {
+    let split = match split {
+        Some(split) if split.get() > 1 && response.len() > 1 => split.get(),
+        _ => return stream.write_all(response).await,
+    };
+
+    stream.tcp_stream().set_nodelay(true)?;
+    for chunk in response.chunks(response.len().div_ceil(split)) {
+        stream.write_all(chunk).await?;
+        stream.flush().await?;
+        tokio::task::yield_now().await;
+    }
+
+    Ok(())
+}
+
+/// Waits for a client to echo back a byte, or close the connection, after being sent its response, then logs the
+/// round-trip time measured since `sent_at`.
+async fn measure_roundtrip<S: AsyncRead + Unpin>(stream: &mut S, addr: SocketAddr, remote_address: SocketAddr, sent_at: Instant) {
+    let mut byte = [0u8; 1];
+    match tokio::time::timeout(ROUNDTRIP_TIMEOUT, stream.read(&mut byte)).await {
+        Ok(Ok(_)) => info!(
+            "TCP socket {addr} measured a round-trip time of {:?} to {remote_address}",
+            sent_at.elapsed()
+        ),
+        Ok(Err(error)) => error!("TCP socket {addr} failed to read echo from {remote_address}: {error}"),
+        Err(_) => error!("TCP socket {addr} timed out waiting for echo from {remote_address}"),
+    }
+}
+
+async fn run_udp_server(startup_args: &StartupArguments, shared: Arc<SharedState>, socket: UdpSocket, mut shutdown: watch::Receiver<bool>) {
+    let addr = match socket.local_addr() {
+        Ok(addr) => addr,
+        Err(error) => {
+            error!("Failed to get the local address of a UDP socket, closing it: {error}");
+            shared.remove_socket();
+            return;
+        }
+    };
+    let socket = Arc::new(socket);
+    let mut buf = [0u8; UDP_BUF_SIZE];
+
+    let mut counter = 0u64;
+    let mut error_counter = 0;
+    let mut injected_errors = startup_args.inject_errors;
+    let mut last_activity = Instant::now();
+    let format = shared.udp_format(startup_args, addr);
+    let mut responder = UdpResponder::new(startup_args, &shared, Arc::clone(&socket), addr);
+    let mut coalescer = startup_args.udp_coalesce.map(UdpCoalescer::new);
+    let mut log_batch = match startup_args.udp_log_batch {
+        Some(size) if !startup_args.verbose && !startup_args.silent => Some(UdpLogBatch::new(size)),
+        _ => None,
+    };
+    let mut rng = match startup_args.random_seed {
+        Some(seed) => Rng::new(seed),
+        None => Rng::from_time(),
+    };
+
+    loop {
+        let result = match take_injected_error(&mut injected_errors) {
+            Some(error) => Err(error),
+            None => loop {
+                tokio::select! {
+                    biased;
+                    _ = shutdown.changed() => {
+                        debug!("UDP socket {addr} shutting down");
+                        if let Some(batch) = &mut log_batch {
+                            batch.flush(addr);
+                        }
+                        if let Some(coalescer) = &mut coalescer {
+                            for response in coalescer.take_all() {
+                                responder.send(response, &mut rng).await;
+                            }
+                        }
+                        responder.finish().await;
+                        return;
+                    }
+                    _ = wait_until(coalescer.as_ref().and_then(UdpCoalescer::next_deadline)) => {
+                        if let Some(coalescer) = &mut coalescer {
+                            for response in coalescer.take_due(Instant::now()) {
+                                responder.send(response, &mut rng).await;
+                            }
+                        }
+                    }
+                    result = socket.recv_from(&mut buf) => break result,
+                    _ = wait_idle(startup_args.unbind_idle, last_activity) => {
+                        if shared.try_unbind_idle_socket() {
+                            info!("UDP socket {addr} unbound after being idle for too long");
+                            if let Some(coalescer) = &mut coalescer {
+                                for response in coalescer.take_all() {
+                                    responder.send(response, &mut rng).await;
+                                }
+                            }
+                            responder.finish().await;
+                            return;
+                        }
+
+                        // This is the last socket being served, so check again after another full idle period.
+                        last_activity = Instant::now();
+                    }
+                }
+            },
+        };
+
+        let (buf_len, remote_address) = match result {
+            Ok(t) => {
+                error_counter = 0;
+                counter += 1;
+                last_activity = Instant::now();
+                t
+            }
+            Err(error) => {
+                info!("Error while receiving from UDP socket {addr}: {error}");
+                shared.record_error("udp");
+                error_counter += 1;
+                if error_counter >= 10 {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        match &mut log_batch {
+            Some(batch) => batch.record(addr, buf_len, remote_address),
+            None => info!("UDP socket {addr} received {buf_len} bytes from {remote_address}"),
+        }
+        shared.record_udp_packet();
+        #[cfg(feature = "otlp")]
+        let _span = otlp::start_request_span("udp", addr, remote_address);
+
+        if startup_args.warn_on_private && utils::is_private_ip(remote_address.ip()) {
+            warn!("WARNING! UDP socket {addr} received packet from private address {remote_address}");
+        }
+
+        if !is_ip_allowed(startup_args, remote_address.ip()) {
+            debug!("UDP socket {addr} dropped packet from disallowed client {remote_address}");
+            continue;
+        }
+
+        if buf_len == 0 && startup_args.empty_udp == EmptyUdpPolicy::Drop {
+            debug!("UDP socket {addr} dropped empty packet from {remote_address}");
+            continue;
+        }
+
+        if startup_args.udp_require_size.is_some_and(|size| buf_len != size) {
+            debug!("UDP socket {addr} dropped packet of unexpected size {buf_len} from {remote_address}");
+            continue;
+        }
+
+        if !shared.try_acquire_udp(remote_address.ip()) {
+            debug!("UDP socket {addr} dropped packet from rate limited client {remote_address}");
+            continue;
+        }
+
+        let context = RequestContext {
+            protocol: "udp",
+            local_address: Some(addr),
+            remote_address: Some(remote_address),
+            client: client_address(startup_args, remote_address),
+            counter,
+            bytes: Some(buf_len),
+            ip_count: shared.record_ip(remote_address.ip()),
+            hostname: None,
+            uptime: shared.started_at.elapsed(),
+            user_agent: None,
+            tls: None,
+        };
+        let mut builder = shared.response_builder(startup_args, format, context);
+        if builder.is_text() {
+            builder.first_seen(shared.record_first_seen(remote_address.ip()));
+        }
+        let mut response = builder.build();
+
+        // A long template or --json-extra can make a response that doesn't fit in a packet. A JSON object can't be cut
+        // short without becoming invalid, so it's dropped instead.
+        if response.len() > UDP_BUF_SIZE {
+            if *format == ResponseFormat::Json {
+                debug!("UDP socket {addr} dropped response to {remote_address}, it's longer than {UDP_BUF_SIZE} bytes");
+                continue;
+            }
+
+            truncate_utf8(&mut response, UDP_BUF_SIZE);
+            debug!("UDP socket {addr} truncated the response to {remote_address} to {UDP_BUF_SIZE} bytes");
+        }
+
+        match startup_args.echo {
+            Some(EchoMode::Append) => {
+                let truncated = append_echo(&mut response, &buf[..buf_len]);
+                if truncated {
+                    debug!("UDP socket {addr} truncated the payload echoed to {remote_address}");
+                }
+            }
+            Some(EchoMode::Replace) => response = buf[..buf_len].to_vec(),
+            None => {}
+        }
+
+        let Some(response_port) = remote_address.port().checked_add(startup_args.udp_response_port_offset) else {
+            debug!("UDP socket {addr} dropped response to {remote_address}, the port offset overflows its port");
+            continue;
+        };
+        let mut response_address = remote_address;
+        response_address.set_port(response_port);
+
+        if startup_args.drop_probability > 0.0 && rng.next_f64() < startup_args.drop_probability {
+            debug!("UDP socket {addr} deliberately dropped response to {response_address}");
+            continue;
+        }
+
+        // The request is only claimed once it's certain to be answered, so `--once` doesn't exit without responding.
+        if !shared.claim_request() {
+            debug!("UDP socket {addr} dropped packet from {remote_address}, a request was already served");
+            continue;
+        }
+
+        let response = UdpResponse {
+            response,
+            response_address,
+            remote_address,
+            counter,
+        };
+
+        let response = match &mut coalescer {
+            Some(coalescer) => match coalescer.hold(response) {
+                Ok(()) => continue,
+                Err(response) => response,
+            },
+            None => response,
+        };
+
+        responder.send(response, &mut rng).await;
+    }
+
+    responder.finish().await;
+    shared.remove_socket();
+    error!("UDP socket {addr} closed due to too many consecutive errors.");
+}
+
+/// Appends the payload of a UDP packet to its text response for `--echo`, cut short if needed so the response fits in
+/// `UDP_BUF_SIZE`, in which case it's noted that it was. If even the note doesn't fit, the response itself is cut
+/// short to make room for it. Returns whether the payload was cut.
+fn append_echo(response: &mut Vec<u8>, payload: &[u8]) -> bool {
+    const PREFIX: &[u8] = b" | echo: ";
+    const TRUNCATED_PREFIX: &[u8] = b" | echo (truncated): ";
+
+    let truncated = response.len() + PREFIX.len() + payload.len() > UDP_BUF_SIZE;
+    let prefix = if truncated { TRUNCATED_PREFIX } else { PREFIX };
+    truncate_utf8(response, UDP_BUF_SIZE - prefix.len());
+    let len = payload.len().min(UDP_BUF_SIZE - response.len() - prefix.len());
+    response.extend_from_slice(prefix);
+    response.extend_from_slice(&payload[..len]);
+    truncated
+}
+
+/// Cuts a UTF-8 response short to at most `max_len` bytes, without splitting a character.
+fn truncate_utf8(response: &mut Vec<u8>, max_len: usize) {
+    let mut len = max_len.min(response.len());
+    // Continuation bytes start with 0b10, so back off until the cut falls before the first byte of a character.
+    while len > 0 && len < response.len() && response[len] & 0xC0 == 0x80 {
+        len -= 1;
+    }
+    response.truncate(len);
+}
+
+/// A UDP response ready to be sent.
+struct UdpResponse {
+    response: Vec<u8>,
+    response_address: SocketAddr,
+    remote_address: SocketAddr,
+    counter: u64,
+}
+
+/// Sends the responses of a UDP server, choosing the socket each one is sent from.
+struct UdpResponder<'a> {
+    startup_args: &'a StartupArguments,
+    shared: Arc<SharedState>,
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    response_sockets: Vec<Arc<UdpSocket>>,
+    response_counter: usize,
+    fresh_source_throttle: Throttle,
+    /// The tasks sending the responses delayed by `--udp-jitter`.
+    jitter_tasks: JoinSet<()>,
+}
+
+impl<'a> UdpResponder<'a> {
+    fn new(startup_args: &'a StartupArguments, shared: &Arc<SharedState>, socket: Arc<UdpSocket>, addr: SocketAddr) -> Self {
+        UdpResponder {
+            startup_args,
+            shared: Arc::clone(shared),
+            socket,
+            addr,
+            response_sockets: bind_response_sockets(startup_args, addr).into_iter().map(Arc::new).collect(),
+            response_counter: 0,
+            fresh_source_throttle: Throttle::new(FRESH_SOURCE_MAX_RATE),
+            jitter_tasks: JoinSet::new(),
+        }
+    }
+
+    /// Waits for the responses delayed by `--udp-jitter` to be sent, which takes at most the jitter's duration.
+    async fn finish(mut self) {
+        while self.jitter_tasks.join_next().await.is_some() {}
+    }
+
+    /// Sends a response, after a random delay if `--udp-jitter` was specified. With `--once`, the server shuts down
+    /// once this returns, as the response was either sent or dropped.
+    async fn send(&mut self, response: UdpResponse, rng: &mut Rng) {
+        let UdpResponse {
+            response,
+            response_address,
+            remote_address,
+            counter,
+        } = response;
+
+        let Some(response_socket) = self.next_socket(response_address) else {
+            self.shared.finish_once();
+            return;
+        };
+
+        // The request is only recorded as served once its response was sent.
+        let format = self.shared.udp_format(self.startup_args, self.addr).name();
+        match self.startup_args.udp_jitter {
+            Some(jitter) => {
+                let delay = jitter.mul_f64(rng.next_f64());
+                // Reap the tasks that already sent their response, so they don't pile up.
+                while self.jitter_tasks.try_join_next().is_some() {}
+
+                let shared = Arc::clone(&self.shared);
+                let addr = self.addr;
+                self.jitter_tasks.spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    send_udp_response(&shared, addr, &response_socket, &response, response_address, counter).await;
+                    shared.record_served("udp", format, addr, remote_address, counter, response.len());
+                });
+            }
+            None => {
+                send_udp_response(&self.shared, self.addr, &response_socket, &response, response_address, counter).await;
+                self.shared
+                    .record_served("udp", format, self.addr, remote_address, counter, response.len());
+            }
+        }
+
+        // Shutdown is only checked between packets, so the server only stops after this response was handled.
+        self.shared.finish_once();
+    }
+
+    /// Gets the socket to send the next response from, or `None` if the response should be dropped.
+    fn next_socket(&mut self, response_address: SocketAddr) -> Option<Arc<UdpSocket>> {
+        let addr = self.addr;
+        if self.startup_args.udp_fresh_source {
+            if !self.fresh_source_throttle.try_acquire() {
+                debug!("UDP socket {addr} dropped response to {response_address}, too many fresh sockets");
+                return None;
+            }
+
+            return match bind_fresh_source_socket(addr) {
+                Ok(socket) => Some(Arc::new(socket)),
+                Err(error) => {
+                    error!("UDP socket {addr} failed to bind a fresh socket to respond to {response_address}: {error}");
+                    self.shared.record_error("udp");
+                    None
+                }
+            };
+        }
+
+        // Rotate between the listening socket and the response sockets, if any, to send the response from.
+        let response_socket = match self.response_counter % (self.response_sockets.len() + 1) {
+            0 => &self.socket,
+            i => &self.response_sockets[i - 1],
+        };
+        self.response_counter = self.response_counter.wrapping_add(1);
+        Some(Arc::clone(response_socket))
+    }
+}
+
+/// Holds back the responses of a UDP server for `--udp-coalesce`, so a burst of packets from the same client within
+/// the window gets a single response, the one for its latest packet.
+///
+/// At most `MAX_COALESCED_CLIENTS` clients may have a response held back at once. Responses to any other clients are
+/// sent right away.
+struct UdpCoalescer {
+    window: Duration,
+    pending: HashMap<SocketAddr, UdpResponse>,
+    /// The clients with a response held back, in the order their windows end.
+    deadlines: VecDeque<(Instant, SocketAddr)>,
+}
+
+impl UdpCoalescer {
+    fn new(window: Duration) -> Self {
+        UdpCoalescer {
+            window,
+            pending: HashMap::new(),
+            deadlines: VecDeque::new(),
+        }
+    }
+
+    /// Holds back a response until the window of its client ends, replacing any response already held back for that
+    /// client. If too many clients have responses held back, the response is given back.
+    fn hold(&mut self, response: UdpResponse) -> Result<(), UdpResponse> {
+        let remote_address = response.remote_address;
+        if let Some(pending) = self.pending.get_mut(&remote_address) {
+            *pending = response;
+            return Ok(());
+        }
+
+        if self.pending.len() >= MAX_COALESCED_CLIENTS {
+            return Err(response);
+        }
+
+        self.pending.insert(remote_address, response);
+        self.deadlines.push_back((Instant::now() + self.window, remote_address));
+        Ok(())
+    }
+
+    /// Gets when the next window ends, if any response is held back.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.front().map(|(deadline, _)| *deadline)
+    }
+
+    /// Takes the responses whose windows have ended.
+    fn take_due(&mut self, now: Instant) -> Vec<UdpResponse> {
+        let mut due = Vec::new();
+        while let Some((_, remote_address)) = self.deadlines.front().filter(|(deadline, _)| *deadline <= now) {
+            let remote_address = *remote_address;
+            self.deadlines.pop_front();
+            due.extend(self.pending.remove(&remote_address));
+        }
+
+        due
+    }
+
+    /// Takes all the responses held back, regardless of their windows.
+    fn take_all(&mut self) -> Vec<UdpResponse> {
+        self.deadlines.clear();
+        self.pending.drain().map(|(_, response)| response).collect()
+    }
+}
+
+/// Accumulates the packets received by a UDP socket for `--udp-log-batch`, so a single summary line is logged for each
+/// batch of packets instead of a line per packet.
+struct UdpLogBatch {
+    size: usize,
+    packets: usize,
+    bytes: usize,
+    sources: HashSet<SocketAddr>,
+}
+
+impl UdpLogBatch {
+    fn new(size: NonZeroUsize) -> Self {
+        UdpLogBatch {
+            size: size.get(),
+            packets: 0,
+            bytes: 0,
+            sources: HashSet::new(),
+        }
+    }
+
+    /// Records a received packet, logging a summary of the batch if it is now full.
+    fn record(&mut self, addr: SocketAddr, len: usize, remote_address: SocketAddr) {
+        self.packets += 1;
+        self.bytes += len;
+        self.sources.insert(remote_address);
+        if self.packets >= self.size {
+            self.flush(addr);
+        }
+    }
+
+    /// Logs a summary of the packets recorded so far, if any, and starts a new batch.
+    fn flush(&mut self, addr: SocketAddr) {
+        if self.packets != 0 {
+            info!(
+                "UDP socket {addr} received {} packets totalling {} bytes from {} distinct sources",
+                self.packets,
+                self.bytes,
+                self.sources.len()
+            );
+        }
+
+        self.packets = 0;
+        self.bytes = 0;
+        self.sources.clear();
+    }
+}
+
+/// Binds a new UDP socket on an ephemeral port of the same IP address as `addr`, to send a single response from.
+fn bind_fresh_source_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(addr.ip(), 0))?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// Sends a response from `socket` for the UDP server listening on `addr`.
+async fn send_udp_response(
+    shared: &SharedState,
+    addr: SocketAddr,
+    socket: &UdpSocket,
+    response: &[u8],
+    response_address: SocketAddr,
+    counter: u64,
+) {
+    let len = response.len();
+    match socket.send_to(response, response_address).await {
+        Ok(bytes_sent) if bytes_sent != len => {
+            error!("UDP socket {addr} should have sent {len} bytes to {response_address}, but {bytes_sent} were sent")
+        }
+        Ok(_) => debug!("UDP socket {addr} responded to {response_address} with packet number {counter}"),
+        Err(error) => {
+            error!("UDP socket {addr} failed to respond to {response_address}: {error}");
+            shared.record_error("udp");
+        }
+    };
+}
+
+/// Checks a TCP client against `--allow`, `--deny` and the rate limit, returning whether its connection should be
+/// served.
+fn accept_tcp_client(startup_args: &StartupArguments, shared: &SharedState, addr: SocketAddr, remote_address: SocketAddr) -> bool {
+    if !is_ip_allowed(startup_args, remote_address.ip()) {
+        debug!("TCP socket {addr} closed connection from disallowed client {remote_address}");
+        return false;
+    }
+
+    if !shared.try_acquire_tcp(remote_address.ip()) {
+        debug!("TCP socket {addr} closed connection from rate limited client {remote_address}");
+        return false;
+    }
+
+    true
+}
+
+/// Returns whether a client's IP address passes the `--allow` and `--deny` rules. Deny rules take precedence, and if
+/// there are any allow rules, the address must match one of them.
+fn is_ip_allowed(startup_args: &StartupArguments, ip: IpAddr) -> bool {
+    if startup_args.deny.iter().any(|network| network.contains(ip)) {
+        return false;
+    }
+
+    startup_args.allow.is_empty() || startup_args.allow.iter().any(|network| network.contains(ip))
+}
+
+/// Gets the client's address as shown in responses, which has its IP address hashed if `--hash-ip` was specified.
+fn client_address(startup_args: &StartupArguments, remote_address: SocketAddr) -> ClientAddress {
+    ClientAddress::new(remote_address, startup_args.ip_salt.as_deref())
+}
+
+/// Consumes one of the remaining injected errors, if any, returning a simulated I/O error in its place. This is used
+/// for exercising the error handling paths of the servers without needing real socket failures.
+fn take_injected_error(remaining: &mut u32) -> Option<io::Error> {
+    if *remaining == 0 {
+        return None;
+    }
+
+    *remaining -= 1;
+    Some(io::Error::other("injected error"))
+}
+
+/// Waits until a socket whose last activity was at `last_activity` has been idle for the `--unbind-idle` duration.
+/// If no such duration was specified, or it's zero, this never completes.
+async fn wait_idle(unbind_idle: Option<Duration>, last_activity: Instant) {
+    match unbind_idle {
+        Some(duration) if !duration.is_zero() => tokio::time::sleep_until(last_activity + duration).await,
+        _ => future::pending().await,
+    }
+}
+
+/// Waits until the given instant. If there is none, this never completes.
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+/// Prints a line with aggregate statistics about the served requests every `interval`.
+async fn print_stats_periodically(interval: Duration, shared: &SharedState) {
+    let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+    let mut last_requests = 0;
+
+    loop {
+        ticker.tick().await;
+        let tcp_connections = shared.tcp_connections.load(Ordering::Relaxed);
+        let udp_packets = shared.udp_packets.load(Ordering::Relaxed);
+        let requests = tcp_connections + udp_packets;
+        let rate = (requests - last_requests) as f64 / interval.as_secs_f64();
+        last_requests = requests;
+
+        info!(
+            "Stats: {tcp_connections} TCP connections, {udp_packets} UDP packets, {rate:.2} requests/s, {} errors",
+            shared.errors.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lookup_hostname_returns_resolved_name() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let hostname = lookup_hostname_with(ip, |_| Some("client.example".into())).await;
+        assert_eq!(hostname.as_deref(), Some("client.example"));
+    }
+
+    #[tokio::test]
+    async fn lookup_hostname_without_name_returns_none() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(lookup_hostname_with(ip, |_| None).await, None);
+    }
+
+    #[tokio::test]
+    async fn lookup_hostname_gives_up_after_timeout() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let hostname = lookup_hostname_with(ip, |_| {
+            std::thread::sleep(REVERSE_DNS_TIMEOUT * 2);
+            Some("slow.example".into())
+        })
+        .await;
+        assert_eq!(hostname, None);
+    }
+
+    #[test]
+    fn no_sockets_bound_json_lists_every_failure() {
+        let error = ServerError::NoSocketsBound(vec![
+            BindFailure::new("192.0.2.1:6969", String::from("Failed to bind TCP socket at 192.0.2.1:6969: nope")),
+            BindFailure::new("fd:3", String::from("Failed to adopt UDP socket from file descriptor 3: \"bad\"")),
+        ]);
+
+        assert_eq!(
+            error.to_json(),
+            concat!(
+                r#"{"error":"No TCP nor UDP sockets could be bound","kind":"bind","addr":"192.0.2.1:6969","failures":["#,
+                r#"{"addr":"192.0.2.1:6969","error":"Failed to bind TCP socket at 192.0.2.1:6969: nope"},"#,
+                r#"{"addr":"fd:3","error":"Failed to adopt UDP socket from file descriptor 3: \"bad\""}]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn forwarded_client_address_stops_at_untrusted_proxy() {
+        let mut startup_args = StartupArguments::empty();
+        startup_args.trusted_proxies = vec!["127.0.0.0/8".parse().unwrap()];
+        let peer: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let forwarded_for = [Some("192.0.2.1:80".parse().unwrap()), Some("198.51.100.1:80".parse().unwrap())];
+
+        assert_eq!(
+            forwarded_client_address(&startup_args, peer, &forwarded_for),
+            forwarded_for[1].unwrap()
+        );
+
+        let untrusted_peer: SocketAddr = "203.0.113.1:1234".parse().unwrap();
+        assert_eq!(
+            forwarded_client_address(&startup_args, untrusted_peer, &forwarded_for),
+            untrusted_peer
+        );
+    }
+
+    #[test]
+    fn truncate_utf8_does_not_split_characters() {
+        let mut response = "aé".repeat(3).into_bytes();
+        truncate_utf8(&mut response, 5);
+        assert_eq!(response, "aéa".as_bytes());
+
+        let mut response = "aé".repeat(3).into_bytes();
+        truncate_utf8(&mut response, 6);
+        assert_eq!(response, "aéaé".as_bytes());
+
+        let mut response = b"short".to_vec();
+        truncate_utf8(&mut response, UDP_BUF_SIZE);
+        assert_eq!(response, b"short");
+    }
+
+    #[test]
+    fn append_echo_keeps_responses_within_the_buffer() {
+        let mut response = b"you".to_vec();
+        assert!(!append_echo(&mut response, b"hello"));
+        assert_eq!(response, b"you | echo: hello");
+
+        let mut response = b"you".to_vec();
+        assert!(append_echo(&mut response, &[b'x'; UDP_BUF_SIZE]));
+        assert_eq!(response.len(), UDP_BUF_SIZE);
+        assert!(response.starts_with(b"you | echo (truncated): xxx"));
+
+        // A response that already fills the buffer is cut short to make room for the note.
+        let mut response = vec![b'y'; UDP_BUF_SIZE];
+        assert!(append_echo(&mut response, b"hello"));
+        assert_eq!(response.len(), UDP_BUF_SIZE);
+        assert!(response.ends_with(b"y | echo (truncated): "));
+    }
+
+    #[test]
+    fn udp_reuse_addr_lets_a_port_be_rebound_right_away() {
+        let mut startup_args = StartupArguments::empty();
+        startup_args.udp_reuse_addr = true;
+        let socket = bind_udp_socket(&startup_args, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        // With SO_REUSEADDR set on both, the port can even be bound again while the first socket is still open.
+        let second = bind_udp_socket(&startup_args, addr).unwrap();
+        drop((socket, second));
+        bind_udp_socket(&startup_args, addr).unwrap();
+    }
+
+    #[test]
+    fn udp_ports_are_exclusive_without_reuse_addr() {
+        let startup_args = StartupArguments::empty();
+        let socket = bind_udp_socket(&startup_args, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let error = bind_udp_socket(&startup_args, addr).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        drop(socket);
+        bind_udp_socket(&startup_args, addr).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn link_local_addresses_bind_with_the_default_scope() {
+        // Find a link-local address and its interface, as listed by the kernel. Hosts without one can't run this.
+        let Ok(addresses) = std::fs::read_to_string("/proc/net/if_inet6") else {
+            return;
+        };
+        let Some((ip, interface)) = addresses.lines().find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let ip = std::net::Ipv6Addr::from(u128::from_str_radix(fields[0], 16).ok()?);
+            utils::is_link_local_v6(ip).then(|| (ip, fields[5].to_string()))
+        }) else {
+            return;
+        };
+
+        let args = ["--default-scope", &interface, "-t", &format!("[{ip}]:0"), "-u", "-"];
+        let startup_args = match crate::args::parse_args_from(&args) {
+            Ok(crate::args::ArgumentsRequest::Run(startup_args)) => startup_args,
+            other => panic!("expected to run the server, got {other:?}"),
+        };
+        let listener = bind_tcp_listener(&startup_args, startup_args.tcp_addresses[0]).unwrap();
+        match listener.local_addr().unwrap() {
+            SocketAddr::V6(addr) => assert_eq!(addr.scope_id(), utils::interface_index(&interface).unwrap()),
+            addr => panic!("expected an IPv6 address, got {addr}"),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "binding sockets to an interface needs CAP_NET_RAW"]
+    fn interface_is_applied_to_bound_sockets() {
+        let mut startup_args = StartupArguments::empty();
+        startup_args.interface = Some("lo".into());
+
+        let listener = bind_tcp_listener(&startup_args, "127.0.0.1:0".parse().unwrap()).unwrap();
+        assert_eq!(socket2::SockRef::from(&listener).device().unwrap().as_deref(), Some(&b"lo"[..]));
+        let socket = bind_udp_socket(&startup_args, "127.0.0.1:0".parse().unwrap()).unwrap();
+        assert_eq!(socket2::SockRef::from(&socket).device().unwrap().as_deref(), Some(&b"lo"[..]));
+    }
 }
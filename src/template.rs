@@ -0,0 +1,294 @@
+use std::{
+    fmt::Write,
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    args::UptimeFormat,
+    response::{text_value, RequestContext},
+    utils,
+};
+
+/// A value that can be substituted into a response template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    Addr,
+    Ip,
+    Port,
+    Count,
+    Proto,
+    Time,
+    IpCount,
+    Hextets,
+    Uptime,
+    Pid,
+    Worker,
+    UserAgent,
+    TlsVersion,
+    Cipher,
+}
+
+impl FromStr for Placeholder {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "addr" => Ok(Self::Addr),
+            "ip" => Ok(Self::Ip),
+            "port" => Ok(Self::Port),
+            "count" => Ok(Self::Count),
+            "proto" => Ok(Self::Proto),
+            "time" => Ok(Self::Time),
+            "ip_count" => Ok(Self::IpCount),
+            "hextets" => Ok(Self::Hextets),
+            "uptime" => Ok(Self::Uptime),
+            "pid" => Ok(Self::Pid),
+            "worker" => Ok(Self::Worker),
+            "user_agent" => Ok(Self::UserAgent),
+            "tls_version" => Ok(Self::TlsVersion),
+            "cipher" => Ok(Self::Cipher),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// A response template, parsed ahead of time so rendering it for each request is cheap and can't fail.
+///
+/// Placeholders are written as a name between braces, such as `{addr}`. Braces that don't enclose a name, like
+/// those in a JSON object, are kept as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+impl ResponseTemplate {
+    /// Parses a template, returning the name of the first unknown placeholder if there is one.
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            rest = &rest[(start + 1)..];
+
+            let name_len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            if name_len == 0 || !rest[name_len..].starts_with('}') {
+                literal.push('{');
+                continue;
+            }
+
+            let name = &rest[..name_len];
+            let placeholder = name.parse().map_err(|_| name.to_string())?;
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(TemplatePart::Placeholder(placeholder));
+            rest = &rest[(name_len + 1)..];
+        }
+
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        Ok(ResponseTemplate { parts })
+    }
+
+    /// Returns whether the template contains the given placeholder.
+    pub fn uses(&self, placeholder: Placeholder) -> bool {
+        self.parts.contains(&TemplatePart::Placeholder(placeholder))
+    }
+
+    /// Renders the template for a request. Line breaks and other control characters are removed from the values
+    /// substituted in, so only the template itself can span several lines.
+    pub fn render(&self, context: &RequestContext, uptime_format: UptimeFormat) -> String {
+        let mut result = String::new();
+        for part in &self.parts {
+            let _ = match part {
+                TemplatePart::Literal(literal) => result.write_str(literal),
+                TemplatePart::Placeholder(Placeholder::Addr) => result.write_str(&text_value(&context.client.address)),
+                TemplatePart::Placeholder(Placeholder::Ip) => result.write_str(&text_value(&context.client.ip)),
+                TemplatePart::Placeholder(Placeholder::Port) => write!(result, "{}", context.client.port),
+                TemplatePart::Placeholder(Placeholder::Count) => write!(result, "{}", context.counter),
+                TemplatePart::Placeholder(Placeholder::Proto) => result.write_str(context.protocol),
+                TemplatePart::Placeholder(Placeholder::Time) => {
+                    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                    write!(result, "{}", time.as_secs())
+                }
+                TemplatePart::Placeholder(Placeholder::IpCount) => write!(result, "{}", context.ip_count.unwrap_or_default()),
+                TemplatePart::Placeholder(Placeholder::Hextets) => write_hextets(&mut result, &context.client.ip),
+                TemplatePart::Placeholder(Placeholder::Uptime) => write_uptime(&mut result, context.uptime, uptime_format),
+                TemplatePart::Placeholder(Placeholder::Pid) => write!(result, "{}", std::process::id()),
+                TemplatePart::Placeholder(Placeholder::Worker) => write!(result, "{}", utils::worker_id()),
+                TemplatePart::Placeholder(Placeholder::UserAgent) => {
+                    result.write_str(&text_value(context.user_agent.as_deref().unwrap_or_default()))
+                }
+                TemplatePart::Placeholder(Placeholder::TlsVersion) => {
+                    result.write_str(context.tls.map(|tls| tls.version).unwrap_or_default())
+                }
+                TemplatePart::Placeholder(Placeholder::Cipher) => result.write_str(context.tls.map(|tls| tls.cipher).unwrap_or_default()),
+            };
+        }
+
+        result
+    }
+}
+
+/// Writes an IPv6 address in full, as eight groups of four hexadecimal digits with no zeros left out. IPv4 addresses,
+/// and IPs hashed with `--hash-ip`, are written as they are.
+fn write_hextets(result: &mut String, ip: &str) -> std::fmt::Result {
+    match ip.parse() {
+        Ok(IpAddr::V6(ip)) => {
+            for (i, segment) in ip.segments().iter().enumerate() {
+                if i != 0 {
+                    result.push(':');
+                }
+                write!(result, "{segment:04x}")?;
+            }
+            Ok(())
+        }
+        _ => result.write_str(&text_value(ip)),
+    }
+}
+
+fn write_uptime(result: &mut String, uptime: Duration, uptime_format: UptimeFormat) -> std::fmt::Result {
+    if uptime_format == UptimeFormat::Seconds {
+        return write!(result, "{}.{:03}", uptime.as_secs(), uptime.subsec_millis());
+    }
+
+    let secs = uptime.as_secs();
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days != 0 {
+        write!(result, "{days}d ")?;
+    }
+    if days != 0 || hours != 0 {
+        write!(result, "{hours}h ")?;
+    }
+    if secs >= 60 {
+        write!(result, "{minutes}m ")?;
+    }
+    write!(result, "{}s", secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{response::TlsParameters, utils::ClientAddress};
+
+    fn context(protocol: &'static str, counter: u64, ip_count: Option<u64>) -> RequestContext {
+        RequestContext {
+            protocol,
+            local_address: None,
+            remote_address: Some("192.0.2.1:5678".parse().unwrap()),
+            client: ClientAddress {
+                address: "192.0.2.1:5678".into(),
+                ip: "192.0.2.1".into(),
+                port: 5678,
+            },
+            counter,
+            bytes: None,
+            ip_count,
+            hostname: None,
+            uptime: Duration::from_millis(93_784_250),
+            user_agent: None,
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let template = ResponseTemplate::parse("{proto} {addr} {ip}:{port} #{count} ({ip_count})").unwrap();
+        assert_eq!(
+            template.render(&context("udp", 7, Some(3)), UptimeFormat::Seconds),
+            "udp 192.0.2.1:5678 192.0.2.1:5678 #7 (3)"
+        );
+        assert!(template.uses(Placeholder::IpCount));
+        assert!(!template.uses(Placeholder::Time));
+    }
+
+    #[test]
+    fn keeps_braces_that_are_not_placeholders() {
+        let template = ResponseTemplate::parse("{\"ip\": \"{ip}\", \"x\": { }, {}").unwrap();
+        assert_eq!(
+            template.render(&context("tcp", 1, None), UptimeFormat::Seconds),
+            "{\"ip\": \"192.0.2.1\", \"x\": { }, {}"
+        );
+
+        let template = ResponseTemplate::parse("trailing {").unwrap();
+        assert_eq!(template.render(&context("tcp", 1, None), UptimeFormat::Seconds), "trailing {");
+    }
+
+    #[test]
+    fn renders_time_as_unix_seconds() {
+        let template = ResponseTemplate::parse("{time}").unwrap();
+        let time: u64 = template.render(&context("tcp", 1, None), UptimeFormat::Seconds).parse().unwrap();
+        assert!(time > 1_600_000_000);
+    }
+
+    #[test]
+    fn renders_hextets_in_full() {
+        let template = ResponseTemplate::parse("{hextets}").unwrap();
+        let mut context = context("tcp", 1, None);
+        assert_eq!(template.render(&context, UptimeFormat::Seconds), "192.0.2.1");
+
+        context.client = ClientAddress::new("[2001:db8::1]:5678".parse().unwrap(), None);
+        assert_eq!(
+            template.render(&context, UptimeFormat::Seconds),
+            "2001:0db8:0000:0000:0000:0000:0000:0001"
+        );
+
+        context.client = ClientAddress::new("[::ffff:0:1]:5678".parse().unwrap(), None);
+        assert_eq!(
+            template.render(&context, UptimeFormat::Seconds),
+            "0000:0000:0000:0000:0000:ffff:0000:0001"
+        );
+
+        context.client.ip = "3f2a9c".into();
+        assert_eq!(template.render(&context, UptimeFormat::Seconds), "3f2a9c");
+    }
+
+    #[test]
+    fn renders_uptime_in_either_format() {
+        let template = ResponseTemplate::parse("{uptime}").unwrap();
+        let mut context = context("tcp", 1, None);
+        assert_eq!(template.render(&context, UptimeFormat::Seconds), "93784.250");
+        assert_eq!(template.render(&context, UptimeFormat::Human), "1d 2h 3m 4s");
+
+        context.uptime = Duration::from_secs(3605);
+        assert_eq!(template.render(&context, UptimeFormat::Human), "1h 0m 5s");
+        context.uptime = Duration::from_millis(999);
+        assert_eq!(template.render(&context, UptimeFormat::Human), "0s");
+        assert_eq!(template.render(&context, UptimeFormat::Seconds), "0.999");
+    }
+
+    #[test]
+    fn renders_tls_parameters_when_served_over_tls() {
+        let template = ResponseTemplate::parse("[{tls_version}] [{cipher}]").unwrap();
+        let mut context = context("tcp", 1, None);
+        assert_eq!(template.render(&context, UptimeFormat::Seconds), "[] []");
+
+        context.tls = Some(TlsParameters {
+            version: "TLSv1.3",
+            cipher: "TLS13_AES_256_GCM_SHA384",
+        });
+        assert_eq!(
+            template.render(&context, UptimeFormat::Seconds),
+            "[TLSv1.3] [TLS13_AES_256_GCM_SHA384]"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert_eq!(ResponseTemplate::parse("{ip} {nope}"), Err("nope".to_string()));
+    }
+}
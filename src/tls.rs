@@ -0,0 +1,188 @@
+use std::sync::Arc;
+
+use p12_keystore::KeyStore;
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        version, ProtocolVersion, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use tracing::info;
+
+use crate::{
+    args::{StartupArguments, TlsVersion},
+    response::TlsParameters,
+    server::AsTcpStream,
+};
+
+/// Builds the TLS acceptor for TCP connections from the identity given with either `--tls-cert` and `--tls-key` or
+/// `--tls-pkcs12`, or returns `None` if TLS wasn't enabled.
+pub fn load_acceptor(startup_args: &StartupArguments) -> Result<Option<TlsAcceptor>, String> {
+    let (certs, key) = match (&startup_args.tls_cert, &startup_args.tls_key, &startup_args.tls_pkcs12) {
+        (Some(cert_path), Some(key_path), _) => load_pem_identity(cert_path, key_path)?,
+        (_, _, Some(path)) => load_pkcs12_identity(path, startup_args.tls_pkcs12_password.as_deref().unwrap_or_default())?,
+        _ => return Ok(None),
+    };
+
+    let mut provider = ring::default_provider();
+    if !startup_args.tls_cipher_suites.is_empty() {
+        provider.cipher_suites = select_cipher_suites(&startup_args.tls_cipher_suites)?;
+    }
+
+    // Without --min-tls-version, this is the same as the versions rustls enables by default.
+    let versions: &[&SupportedProtocolVersion] = match startup_args.min_tls_version {
+        Some(TlsVersion::Tls13) => &[&version::TLS13],
+        Some(TlsVersion::Tls12) | None => &[&version::TLS13, &version::TLS12],
+    };
+    info!(
+        "Serving TCP clients over TLS with a minimum version of {}",
+        if versions.contains(&&version::TLS12) {
+            "TLSv1.2"
+        } else {
+            "TLSv1.3"
+        }
+    );
+
+    let config = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(versions)
+        .map_err(|error| format!("Invalid TLS configuration: {error}"))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|error| format!("Invalid TLS certificate or private key: {error}"))?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Picks the cipher suites named with `--tls-cipher-suites` out of the ones supported, in the order they were given.
+fn select_cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>, String> {
+    let supported = ring::ALL_CIPHER_SUITES;
+    names
+        .iter()
+        .map(|name| {
+            supported
+                .iter()
+                .find(|suite| {
+                    suite
+                        .suite()
+                        .as_str()
+                        .is_some_and(|suite_name| suite_name.eq_ignore_ascii_case(name))
+                })
+                .copied()
+                .ok_or_else(|| {
+                    let names: Vec<_> = supported.iter().filter_map(|suite| suite.suite().as_str()).collect();
+                    format!("Unknown TLS cipher suite {name}, the supported ones are {}", names.join(", "))
+                })
+        })
+        .collect()
+}
+
+/// Reads a PEM certificate chain file and a PEM private key file.
+fn load_pem_identity(cert_path: &str, key_path: &str) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| format!("Failed to read TLS certificates from {cert_path}: {error}"))?;
+    if certs.is_empty() {
+        return Err(format!("No TLS certificates found in {cert_path}"));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|error| format!("Failed to read TLS private key from {key_path}: {error}"))?;
+    Ok((certs, key))
+}
+
+/// Reads the private key and its certificate chain from a PKCS#12 bundle. If the bundle holds several keys, the first
+/// one is used.
+fn load_pkcs12_identity(path: &str, password: &str) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+    let data = std::fs::read(path).map_err(|error| format!("Failed to read TLS PKCS#12 bundle from {path}: {error}"))?;
+    let keystore = KeyStore::from_pkcs12(&data, password).map_err(|error| match error {
+        p12_keystore::error::Error::MacError(_) => format!("Failed to decrypt TLS PKCS#12 bundle {path}: wrong password"),
+        error => format!("Failed to decode TLS PKCS#12 bundle {path}: {error}"),
+    })?;
+
+    let (_, chain) = keystore
+        .private_key_chain()
+        .ok_or_else(|| format!("No private key found in TLS PKCS#12 bundle {path}"))?;
+    if chain.chain().is_empty() {
+        return Err(format!("No TLS certificates found in PKCS#12 bundle {path}"));
+    }
+
+    let certs = chain
+        .chain()
+        .iter()
+        .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+        .collect();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(chain.key().to_vec()));
+    Ok((certs, key))
+}
+
+impl AsTcpStream for TlsStream<TcpStream> {
+    fn tcp_stream(&self) -> &TcpStream {
+        self.get_ref().0
+    }
+
+    fn tls_parameters(&self) -> Option<TlsParameters> {
+        let connection = self.get_ref().1;
+        let version = match connection.protocol_version()? {
+            ProtocolVersion::TLSv1_2 => "TLSv1.2",
+            ProtocolVersion::TLSv1_3 => "TLSv1.3",
+            version => version.as_str().unwrap_or("unknown"),
+        };
+        let cipher = connection.negotiated_cipher_suite()?.suite().as_str().unwrap_or("unknown");
+        Some(TlsParameters { version, cipher })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_rustls::rustls::CipherSuite;
+
+    const DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+    #[test]
+    fn loads_pem_identity() {
+        let (certs, _) = load_pem_identity(&format!("{DATA}/cert.pem"), &format!("{DATA}/key.pem")).unwrap();
+        assert_eq!(certs.len(), 1);
+
+        let error = load_pem_identity(&format!("{DATA}/key.pem"), &format!("{DATA}/key.pem")).unwrap_err();
+        assert!(error.starts_with("No TLS certificates found"), "{error}");
+    }
+
+    #[test]
+    fn loads_pkcs12_identity() {
+        let (certs, _) = load_pkcs12_identity(&format!("{DATA}/identity.p12"), "whereyoufrom").unwrap();
+        assert_eq!(certs.len(), 2);
+        let (pem_certs, _) = load_pem_identity(&format!("{DATA}/cert.pem"), &format!("{DATA}/key.pem")).unwrap();
+        assert_eq!(certs[0], pem_certs[0]);
+    }
+
+    #[test]
+    fn selects_cipher_suites_by_name() {
+        let names = ["TLS13_CHACHA20_POLY1305_SHA256".to_string(), "tls13_aes_128_gcm_sha256".to_string()];
+        let suites: Vec<_> = select_cipher_suites(&names).unwrap().iter().map(|suite| suite.suite()).collect();
+        assert_eq!(
+            suites,
+            [CipherSuite::TLS13_CHACHA20_POLY1305_SHA256, CipherSuite::TLS13_AES_128_GCM_SHA256]
+        );
+
+        let error = select_cipher_suites(&["TLS_RSA_WITH_RC4_128_MD5".to_string()]).unwrap_err();
+        assert!(error.starts_with("Unknown TLS cipher suite TLS_RSA_WITH_RC4_128_MD5"), "{error}");
+    }
+
+    #[test]
+    fn reports_pkcs12_errors() {
+        let error = load_pkcs12_identity(&format!("{DATA}/identity.p12"), "nope").unwrap_err();
+        assert!(error.ends_with("wrong password"), "{error}");
+
+        let error = load_pkcs12_identity(&format!("{DATA}/cert.pem"), "whereyoufrom").unwrap_err();
+        assert!(error.starts_with("Failed to decode TLS PKCS#12 bundle"), "{error}");
+
+        let error = load_pkcs12_identity(&format!("{DATA}/missing.p12"), "whereyoufrom").unwrap_err();
+        assert!(error.starts_with("Failed to read TLS PKCS#12 bundle"), "{error}");
+    }
+}
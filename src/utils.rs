@@ -1,15 +1,522 @@
-/// The same as the `println!` macro, but takes as first parameter a condition on whether to print.
-/// If true, the contents will be printed. Otherwise, nothing will happen.
-#[macro_export]
-macro_rules! printlnif {
-    ($condition:expr) => {
-        if $condition {
-            std::println!();
+use std::{
+    fmt::Write,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::args::ChecksumType;
+
+/// Returns whether an IP address belongs to a private (RFC 1918), unique local (ULA) or link-local range.
+/// IPv4-mapped IPv6 addresses are classified by the IPv4 address they map to.
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_private() || v4.is_link_local(),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_private_ip(IpAddr::V4(v4)),
+            None => (v6.segments()[0] & 0xfe00) == 0xfc00 || is_link_local_v6(v6),
+        },
+    }
+}
+
+/// Returns whether an IPv6 address is a unicast link-local address (fe80::/10), which needs a scope to be bound.
+pub fn is_link_local_v6(ip: Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// Gets the index of a network interface, to use as the scope of IPv6 addresses, from either its name or the index
+/// itself. Returns `None` if there is no such interface.
+pub fn interface_index(interface: &str) -> Option<u32> {
+    if let Ok(index) = interface.parse() {
+        return Some(index);
+    }
+
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(interface).ok()?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => None,
+            index => Some(index),
+        }
+    }
+
+    #[cfg(not(unix))]
+    None
+}
+
+/// Looks up the hostname an IP address points back to through reverse DNS. This blocks until the system's resolver
+/// answers, and returns `None` if the address has no hostname or the lookup fails.
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    #[cfg(unix)]
+    {
+        let address = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+        let mut host: [libc::c_char; libc::NI_MAXHOST as usize] = [0; libc::NI_MAXHOST as usize];
+        let result = unsafe {
+            libc::getnameinfo(
+                address.as_ptr(),
+                address.len(),
+                host.as_mut_ptr(),
+                host.len() as libc::socklen_t,
+                std::ptr::null_mut(),
+                0,
+                libc::NI_NAMEREQD,
+            )
+        };
+
+        if result != 0 {
+            return None;
+        }
+
+        let host = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+        Some(host.to_string_lossy().into_owned())
+    }
+
+    #[cfg(not(unix))]
+    None
+}
+
+/// Computes a checksum of the given data, formatted as a lowercase hexadecimal string.
+pub fn compute_checksum(checksum_type: ChecksumType, data: &[u8]) -> String {
+    match checksum_type {
+        ChecksumType::Crc32 => format!("{:08x}", crc32fast::hash(data)),
+        ChecksumType::Sha256 => Sha256::digest(data).iter().fold(String::with_capacity(64), |mut s, b| {
+            let _ = write!(s, "{b:02x}");
+            s
+        }),
+    }
+}
+
+/// Escapes a string so it can be placed between quotes as a JSON string.
+pub fn escape_json(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(result, "\\u{:04x}", c as u32);
+            }
+            c => result.push(c),
         }
+    }
+
+    result
+}
+
+/// A client's address as shown in responses. With `--hash-ip`, the IP address is replaced by a salted hash of it.
+#[derive(Debug, Clone)]
+pub struct ClientAddress {
+    pub address: String,
+    pub ip: String,
+    pub port: u16,
+}
+
+impl ClientAddress {
+    pub fn new(remote_address: SocketAddr, ip_salt: Option<&str>) -> Self {
+        let port = remote_address.port();
+        match ip_salt {
+            Some(salt) => {
+                let ip = hash_ip(salt, remote_address.ip());
+                ClientAddress {
+                    address: format!("{ip}:{port}"),
+                    ip,
+                    port,
+                }
+            }
+            None => ClientAddress {
+                address: remote_address.to_string(),
+                ip: remote_address.ip().to_string(),
+                port,
+            },
+        }
+    }
+}
+
+/// Generates a random salt for `--hash-ip` from the operating system's secure random number generator, formatted as a
+/// lowercase hexadecimal string.
+pub fn random_salt() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes)?;
+    Ok(bytes.iter().fold(String::with_capacity(32), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    }))
+}
+
+/// Hashes an IP address with a salt using SHA-256, truncated to 64 bits and formatted as a lowercase hexadecimal
+/// string. IPv4-mapped IPv6 addresses are hashed as IPv4, so a client gets the same hash through any socket.
+pub fn hash_ip(salt: &str, ip: IpAddr) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+
+    hasher.finalize()[..8].iter().fold(String::with_capacity(16), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+/// The names of the fields in a `json` format response.
+pub const JSON_RESPONSE_FIELDS: [&str; 5] = ["address", "ip", "port", "protocol", "count"];
+
+/// Formats a response as a single-line JSON object describing the client, for the `json` response format, followed by
+/// the already serialized `extra` fields if there are any.
+/// Formats the json response object. The port is written as a number, or as a string if `numeric_port` is false.
+pub fn format_json_response(protocol: &str, client: &ClientAddress, counter: u64, numeric_port: bool, extra: Option<&str>) -> String {
+    let port_quote = if numeric_port { "" } else { "\"" };
+    let mut result = format!(
+        "{{\"address\":\"{}\",\"ip\":\"{}\",\"port\":{port_quote}{}{port_quote},\"protocol\":\"{}\",\"count\":{counter}",
+        escape_json(&client.address),
+        escape_json(&client.ip),
+        client.port,
+        escape_json(protocol),
+    );
+
+    if let Some(extra) = extra {
+        result.push(',');
+        result.push_str(extra);
+    }
+
+    result.push('}');
+    result
+}
+
+/// Prints a fatal error to stderr as a single-line JSON object, with an optional associated socket address.
+pub fn print_json_error(kind: &str, addr: Option<SocketAddr>, message: &str) {
+    let message = escape_json(message);
+    match addr {
+        Some(addr) => eprintln!("{{\"error\":\"{message}\",\"kind\":\"{kind}\",\"addr\":\"{addr}\"}}"),
+        None => eprintln!("{{\"error\":\"{message}\",\"kind\":\"{kind}\"}}"),
+    }
+}
+
+/// Returns whether an I/O error indicates the address family is not supported, such as when binding an IPv6
+/// address on a host without IPv6 support.
+pub fn is_address_family_unsupported(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    let code = libc::EAFNOSUPPORT;
+    #[cfg(windows)]
+    let code = 10047; // WSAEAFNOSUPPORT
+
+    error.raw_os_error() == Some(code)
+}
+
+/// Takes ownership of an inherited file descriptor as a socket, after checking with `SO_TYPE` that it is a socket of
+/// the expected type, such as `libc::SOCK_STREAM` or `libc::SOCK_DGRAM`, and that it's an IPv4 or IPv6 socket rather
+/// than, for example, a Unix domain socket.
+///
+/// The descriptor must not be owned by anything else in the process, as it is closed when the socket is dropped.
+#[cfg(unix)]
+pub fn adopt_socket_fd(fd: std::os::fd::RawFd, expected_type: libc::c_int) -> io::Result<socket2::Socket> {
+    use std::os::fd::FromRawFd;
+
+    if socket_type(fd)? != expected_type {
+        return Err(io::Error::other(format!(
+            "file descriptor {fd} is not a socket of the expected type"
+        )));
+    }
+
+    if !matches!(socket_family(fd)?, libc::AF_INET | libc::AF_INET6) {
+        return Err(io::Error::other(format!("file descriptor {fd} is not an IPv4 or IPv6 socket")));
+    }
+
+    Ok(unsafe { socket2::Socket::from_raw_fd(fd) })
+}
+
+/// Gets the address family of the socket behind a file descriptor with `getsockname`, such as `libc::AF_INET`.
+#[cfg(unix)]
+pub fn socket_family(fd: std::os::fd::RawFd) -> io::Result<libc::c_int> {
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe { libc::getsockname(fd, &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut len) };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(address.ss_family as libc::c_int)
+}
+
+/// Gets the type of the socket behind a file descriptor with `SO_TYPE`, such as `libc::SOCK_STREAM`.
+#[cfg(unix)]
+pub fn socket_type(fd: std::os::fd::RawFd) -> io::Result<libc::c_int> {
+    let mut socket_type: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut socket_type as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket_type)
+}
+
+/// The first file descriptor passed to a process by systemd socket activation.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+/// Takes the file descriptors passed to this process by systemd socket activation, as told by the `LISTEN_PID` and
+/// `LISTEN_FDS` environment variables. Returns none if the process wasn't started this way.
+///
+/// Like `sd_listen_fds`, the environment variables are then removed, so the descriptors aren't taken twice nor
+/// announced to child processes. Modifying the environment isn't thread-safe, so this must be called before the
+/// process starts any other threads, such as those of a multi-threaded runtime.
+#[cfg(unix)]
+pub fn take_systemd_listen_fds() -> Vec<std::os::fd::RawFd> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+
+    parse_systemd_listen_fds(listen_pid.as_deref(), listen_fds.as_deref(), std::process::id())
+}
+
+/// Gets the file descriptors passed by systemd socket activation from the values of the `LISTEN_PID` and `LISTEN_FDS`
+/// environment variables. Returns none unless `LISTEN_PID` names the process with the given `pid`.
+#[cfg(unix)]
+pub fn parse_systemd_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Vec<std::os::fd::RawFd> {
+    let listen_pid = listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok());
+    let listen_fds = listen_fds.and_then(|count| count.parse::<std::os::fd::RawFd>().ok());
+
+    match listen_fds {
+        Some(count) if count > 0 && listen_pid == Some(pid) => (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(count)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The flag set in `tcp_info::tcpi_options` when TCP timestamps were negotiated for a connection.
+#[cfg(target_os = "linux")]
+pub const TCPI_OPT_TIMESTAMPS: u8 = 1;
+
+/// Reads the kernel's `TCP_INFO` statistics for a connected TCP socket.
+#[cfg(target_os = "linux")]
+pub fn get_tcp_info(socket: &impl std::os::fd::AsRawFd) -> io::Result<libc::tcp_info> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    match result {
+        0 => Ok(info),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Reads the maximum segment size (`TCP_MAXSEG`) of a connected TCP socket.
+#[cfg(target_os = "linux")]
+pub fn get_tcp_mss(socket: &impl std::os::fd::AsRawFd) -> io::Result<u32> {
+    let mut mss: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &mut mss as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
     };
-    ($condition:expr, $($arg:tt)*) => {{
-        if $condition {
-            std::println!($($arg)*);
+
+    match result {
+        0 => Ok(mss as u32),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Gets an identifier for the current thread, numbered from 1 in the order threads first ask for one. With
+/// `--workers`, this tells apart the runtime's worker threads.
+pub fn worker_id() -> usize {
+    static NEXT_WORKER_ID: AtomicUsize = AtomicUsize::new(1);
+    thread_local! {
+        static WORKER_ID: usize = NEXT_WORKER_ID.fetch_add(1, Ordering::Relaxed);
+    }
+
+    WORKER_ID.with(|id| *id)
+}
+
+/// A small, seedable pseudo-random number generator based on SplitMix64. This is meant for simulating network
+/// conditions reproducibly, and is not suitable for anything security-related.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    /// Creates a generator seeded from the current system time.
+    pub fn from_time() -> Self {
+        Rng(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a uniformly distributed number in the range [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_private_addresses() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.105",
+            "169.254.0.1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(is_private_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "127.0.0.1", "2001:db8::1", "::1", "::ffff:8.8.8.8"] {
+            assert!(!is_private_ip(ip.parse().unwrap()), "{ip}");
         }
-    }};
+    }
+
+    #[test]
+    fn random_salts_differ() {
+        let first = random_salt().unwrap();
+        let second = random_salt().unwrap();
+        assert_eq!(first.len(), 32);
+        assert!(first.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn hashes_ips_consistently() {
+        let ip = "192.0.2.1".parse().unwrap();
+        let hash = hash_ip("salt", ip);
+        assert_eq!(hash.len(), 16);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hash_ip("salt", ip), hash);
+        assert_eq!(hash_ip("salt", "::ffff:192.0.2.1".parse().unwrap()), hash);
+
+        assert_ne!(hash_ip("salt", "192.0.2.2".parse().unwrap()), hash);
+        assert_ne!(hash_ip("pepper", ip), hash);
+
+        let client = ClientAddress::new("192.0.2.1:1234".parse().unwrap(), Some("salt"));
+        assert_eq!(client.ip, hash);
+        assert_eq!(client.address, format!("{hash}:1234"));
+    }
+
+    #[test]
+    fn worker_ids_are_per_thread() {
+        let id = worker_id();
+        assert_eq!(worker_id(), id);
+        let other = std::thread::spawn(worker_id).join().unwrap();
+        assert_ne!(other, id);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parses_systemd_listen_fds() {
+        assert_eq!(parse_systemd_listen_fds(Some("42"), Some("2"), 42), vec![3, 4]);
+        assert!(parse_systemd_listen_fds(Some("1"), Some("2"), 42).is_empty());
+        assert!(parse_systemd_listen_fds(None, Some("2"), 42).is_empty());
+        assert!(parse_systemd_listen_fds(Some("42"), None, 42).is_empty());
+        assert!(parse_systemd_listen_fds(Some("42"), Some("0"), 42).is_empty());
+        assert!(parse_systemd_listen_fds(Some("42"), Some("-1"), 42).is_empty());
+        assert!(parse_systemd_listen_fds(Some("nope"), Some("2"), 42).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn adopt_socket_fd_rejects_unix_sockets() {
+        use std::os::fd::IntoRawFd;
+
+        let (socket, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let fd = socket.into_raw_fd();
+        assert!(adopt_socket_fd(fd, libc::SOCK_STREAM).is_err());
+        unsafe { libc::close(fd) };
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn adopt_socket_fd_accepts_tcp_listeners() {
+        use std::os::fd::IntoRawFd;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let socket = adopt_socket_fd(listener.into_raw_fd(), libc::SOCK_STREAM).unwrap();
+        assert_eq!(socket.local_addr().unwrap().as_socket(), Some(address));
+    }
+
+    /// Connects a TCP client to a listener on loopback, returning the server's end of the connection.
+    #[cfg(target_os = "linux")]
+    fn loopback_connection() -> (std::net::TcpStream, std::net::TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (server, client)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reads_tcp_info_of_loopback_connection() {
+        // The TCP_ESTABLISHED and TCP_LISTEN states from the kernel's tcp_states.h, which libc doesn't define.
+        let (server, _client) = loopback_connection();
+        assert_eq!(get_tcp_info(&server).unwrap().tcpi_state, 1);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_eq!(get_tcp_info(&listener).unwrap().tcpi_state, 10);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reads_timestamps_option_of_loopback_connection() {
+        // Timestamps are negotiated unless the net.ipv4.tcp_timestamps sysctl is 0, which disables them.
+        let Ok(sysctl) = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_timestamps") else {
+            return;
+        };
+        let (server, _client) = loopback_connection();
+        let info = get_tcp_info(&server).unwrap();
+        assert_eq!(info.tcpi_options & TCPI_OPT_TIMESTAMPS != 0, sysctl.trim() != "0");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reads_mss_of_loopback_connection() {
+        // Loopback's MTU is 65536 bytes, so its MSS is only limited by the 40 bytes of IP and TCP headers and options.
+        let (server, _client) = loopback_connection();
+        let mss = get_tcp_mss(&server).unwrap();
+        assert!(mss > 0 && mss <= 65535 - 40, "{mss}");
+    }
 }
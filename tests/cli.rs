@@ -0,0 +1,38 @@
+//! Runs the built binary for the options that print something and exit without serving anything.
+
+use std::{net::SocketAddr, process::Command};
+
+fn run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_whereyoufrom")).args(args).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn capabilities_reflect_the_build() {
+    let output = run(&["--capabilities"]);
+    let capabilities: Vec<&str> = output.lines().collect();
+
+    assert!(capabilities
+        .iter()
+        .all(|capability| !capability.is_empty() && !capability.contains(char::is_whitespace)));
+    assert_eq!(&capabilities[..2], ["tcp", "udp"]);
+    assert_eq!(capabilities.contains(&"unix"), cfg!(unix));
+    assert_eq!(capabilities.contains(&"interface"), cfg!(target_os = "linux"));
+    assert_eq!(capabilities.contains(&"otlp"), cfg!(feature = "otlp"));
+    assert_eq!(capabilities.contains(&"tls"), cfg!(feature = "tls"));
+}
+
+#[test]
+fn resolve_only_prints_each_resolved_address() {
+    // localhost may or may not resolve to ::1 as well, depending on the system's hosts file.
+    let output = run(&["--resolve-only", "localhost:1234"]);
+    let addresses: Vec<SocketAddr> = output.lines().map(|line| line.parse().unwrap()).collect();
+    assert!(addresses.contains(&"127.0.0.1:1234".parse().unwrap()), "{output}");
+    assert!(
+        addresses.iter().all(|addr| addr.ip().is_loopback() && addr.port() == 1234),
+        "{output}"
+    );
+
+    assert_eq!(run(&["--default-port", "80", "--resolve-only", "[::1]"]), "[::1]:80\n");
+}
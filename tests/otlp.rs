@@ -0,0 +1,96 @@
+//! Exports telemetry to a stub OTLP/HTTP collector. The providers are installed globally, so this runs in a test
+//! binary of its own.
+#![cfg(all(unix, feature = "otlp"))]
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, UdpSocket},
+    os::fd::IntoRawFd,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use whereyoufrom::{otlp, run_server, StartupArguments};
+
+/// How long to wait for a response or an export before failing a test.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request received by the stub collector.
+struct ExportRequest {
+    path: String,
+    body: Vec<u8>,
+}
+
+/// Starts a collector that accepts every export, sending each request it receives through the returned channel.
+fn start_collector() -> (String, mpsc::Receiver<ExportRequest>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-protobuf\r\nContent-Length: 0\r\n\r\n");
+            let _ = sender.send(ExportRequest { path, body });
+        }
+    });
+
+    (endpoint, receiver)
+}
+
+#[test]
+fn exports_request_spans_to_the_collector() {
+    let (endpoint, exports) = start_collector();
+    let providers = otlp::init(&endpoint).unwrap();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_fds.push(socket.into_raw_fd());
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+        let (shutdown, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(run_server(startup_args, async {
+            let _ = shutdown_receiver.await;
+        }));
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"hello", addr).await.unwrap();
+        let mut buf = [0u8; 2048];
+        tokio::time::timeout(TIMEOUT, client.recv_from(&mut buf)).await.unwrap().unwrap();
+
+        let _ = shutdown.send(());
+        tokio::time::timeout(TIMEOUT, server).await.unwrap().unwrap().unwrap();
+    });
+
+    // Shutting down flushes the batched spans to the collector.
+    providers.shutdown();
+
+    let spans = std::iter::from_fn(|| exports.recv_timeout(TIMEOUT).ok())
+        .find(|export| export.path == "/v1/traces")
+        .expect("the collector received no spans");
+    assert!(spans.body.windows(b"udp request".len()).any(|window| window == b"udp request"));
+}
@@ -0,0 +1,1400 @@
+//! Runs the servers through `run_server` on sockets bound to 127.0.0.1:0. The sockets are bound here and handed to the
+//! servers as inherited file descriptors, the same as with `--fd-tcp` and `--fd-udp`, so the tests know their ports.
+#![cfg(unix)]
+
+use std::{
+    io::Write,
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    os::fd::IntoRawFd,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use sha2::Digest;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    sync::oneshot,
+    task::JoinHandle,
+};
+use whereyoufrom::{
+    args::{AddressFormat, ChecksumType, ConnectionLimitPolicy, EchoMode, EmptyUdpPolicy, ResponseFormat},
+    response::{RequestContext, ResponseProvider},
+    run_server, run_server_with_provider,
+    template::ResponseTemplate,
+    utils::Rng,
+    ServerError, StartupArguments,
+};
+
+/// How long to wait for a response before failing a test.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running in the background until it's told to shut down.
+struct TestServer {
+    shutdown: Option<oneshot::Sender<()>>,
+    handle: JoinHandle<Result<(), ServerError>>,
+}
+
+impl TestServer {
+    fn start(startup_args: StartupArguments) -> Self {
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let handle = tokio::spawn(run_server(startup_args, async {
+            let _ = shutdown_receiver.await;
+        }));
+
+        TestServer {
+            shutdown: Some(shutdown),
+            handle,
+        }
+    }
+
+    /// Starts a server that builds its responses with the given provider.
+    fn start_with_provider(startup_args: StartupArguments, provider: Arc<dyn ResponseProvider>) -> Self {
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let handle = tokio::spawn(run_server_with_provider(startup_args, provider, async {
+            let _ = shutdown_receiver.await;
+        }));
+
+        TestServer {
+            shutdown: Some(shutdown),
+            handle,
+        }
+    }
+
+    /// Tells the server to shut down, without waiting for it.
+    fn trigger_shutdown(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+
+    /// Waits for the server to finish on its own, without telling it to shut down.
+    async fn wait(self) {
+        let result = tokio::time::timeout(TIMEOUT, self.handle).await.expect("server didn't finish");
+        result.unwrap().unwrap();
+    }
+
+    /// Shuts the server down, waiting for it to finish.
+    async fn stop(mut self) {
+        self.trigger_shutdown();
+        let result = tokio::time::timeout(TIMEOUT, self.handle).await.expect("server didn't shut down");
+        result.unwrap().unwrap();
+    }
+}
+
+/// Binds a TCP listener to 127.0.0.1:0 for the server to serve, returning its address.
+fn bind_tcp(startup_args: &mut StartupArguments) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    startup_args.tcp_fds.push(listener.into_raw_fd());
+    addr
+}
+
+/// Binds a UDP socket to 127.0.0.1:0 for the server to serve, returning its address.
+fn bind_udp(startup_args: &mut StartupArguments) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    startup_args.udp_fds.push(socket.into_raw_fd());
+    addr
+}
+
+/// Collects the events logged on the current thread, which with the single-threaded test runtime includes the server's.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    /// Starts capturing debug events and above, until the returned guard is dropped.
+    fn start() -> (Self, tracing::subscriber::DefaultGuard) {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn read_tcp_response(addr: SocketAddr) -> (SocketAddr, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let local_addr = stream.local_addr().unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .expect("timed out waiting for a TCP response")
+        .unwrap();
+    (local_addr, response)
+}
+
+async fn read_udp_response(addr: SocketAddr, payload: &[u8]) -> (SocketAddr, String) {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(payload, addr).await.unwrap();
+    let mut buf = [0u8; 2048];
+    let (len, _) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf))
+        .await
+        .expect("timed out waiting for a UDP response")
+        .unwrap();
+    (socket.local_addr().unwrap(), String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[tokio::test]
+async fn tcp_responds_with_client_address() {
+    let mut startup_args = StartupArguments::empty();
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    for counter in 1..=2 {
+        let (client_addr, response) = read_tcp_response(addr).await;
+        assert_eq!(response, format!("you: {client_addr} | connection_number: {counter}"));
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn tcp_responds_over_http() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.http = true;
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+    assert!(head.contains("\r\nConnection: close"), "{head}");
+    assert_eq!(body, format!("you: {client_addr} | connection_number: 1"));
+
+    server.stop().await;
+}
+
+/// Reads an HTTP response with a Content-Length from a stream, returning its head and body.
+async fn read_http_response(stream: &mut TcpStream) -> (String, String) {
+    let mut response = Vec::new();
+    loop {
+        let mut chunk = [0u8; 1024];
+        let len = tokio::time::timeout(TIMEOUT, stream.read(&mut chunk))
+            .await
+            .expect("timed out waiting for an HTTP response")
+            .unwrap();
+        assert_ne!(len, 0, "connection closed before the response was complete");
+        response.extend_from_slice(&chunk[..len]);
+
+        let text = String::from_utf8_lossy(&response);
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let content_length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            if body.len() >= content_length {
+                return (head.to_string(), body.to_string());
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_connections_being_handled() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.http = true;
+    let addr = bind_tcp(&mut startup_args);
+    let mut server = TestServer::start(startup_args);
+
+    // The first request makes sure the connection was accepted before shutting down.
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    read_http_response(&mut stream).await;
+
+    // The client only sends its next request well after the server was told to shut down.
+    server.trigger_shutdown();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap();
+    let (head, body) = read_http_response(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+    assert_eq!(body, format!("you: {client_addr} | connection_number: 1"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn shutdown_terminates_connections_after_grace_period() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.http = true;
+    startup_args.shutdown_timeout = Duration::from_millis(200);
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    read_http_response(&mut stream).await;
+
+    // The client never sends another request, so the server cuts it off once the grace period is over.
+    server.stop().await;
+    let mut buf = [0u8; 16];
+    let len = tokio::time::timeout(TIMEOUT, stream.read(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(len, 0);
+}
+
+#[tokio::test]
+async fn udp_responds_with_client_address() {
+    let mut startup_args = StartupArguments::empty();
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (client_addr, response) = read_udp_response(addr, b"hello").await;
+    assert_eq!(response, format!("you: {client_addr} | bytes: 5 | packet_number: 1"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn unix_responds_to_connections() {
+    let path = std::env::temp_dir().join(format!("whereyoufrom-test-{}.sock", std::process::id()));
+    let mut startup_args = StartupArguments::empty();
+    startup_args.unix_paths.push(path.clone());
+    let server = TestServer::start(startup_args);
+
+    let mut stream = connect_unix(&path).await;
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(response, "you: unix | connection_number: 1");
+
+    server.stop().await;
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn servers_keep_running_after_injected_errors() {
+    // Sockets are closed after 10 consecutive errors, so 9 are the most they can recover from.
+    let mut startup_args = StartupArguments::empty();
+    startup_args.inject_errors = 9;
+    let tcp_addr = bind_tcp(&mut startup_args);
+    let udp_addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (client_addr, response) = read_tcp_response(tcp_addr).await;
+    assert_eq!(response, format!("you: {client_addr} | connection_number: 1"));
+
+    let (client_addr, response) = read_udp_response(udp_addr, b"x").await;
+    assert_eq!(response, format!("you: {client_addr} | bytes: 1 | packet_number: 1"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn too_many_injected_errors_close_the_sockets() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.inject_errors = 10;
+    let tcp_addr = bind_tcp(&mut startup_args);
+    let udp_addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    // The sockets give up and are closed, so connections are refused and packets go unanswered.
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    while TcpStream::connect(tcp_addr).await.is_ok() {
+        assert!(tokio::time::Instant::now() < deadline, "TCP listener wasn't closed");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(b"x", udp_addr).await.unwrap();
+    let mut buf = [0u8; 2048];
+    let result = tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await;
+    assert!(result.is_err(), "UDP socket answered after being closed");
+
+    server.stop().await;
+}
+
+/// Connects to a Unix domain socket, waiting for the server to bind it.
+async fn connect_unix(path: &Path) -> UnixStream {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    loop {
+        match UnixStream::connect(path).await {
+            Ok(stream) => return stream,
+            Err(error) if tokio::time::Instant::now() >= deadline => panic!("failed to connect to {}: {error}", path.display()),
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn fails_when_no_sockets_are_bound() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.tcp_addresses.push("192.0.2.1:0".parse().unwrap());
+
+    let result = run_server(startup_args, std::future::pending()).await;
+    match result {
+        Err(ServerError::NoSocketsBound(failures)) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].socket, "192.0.2.1:0");
+        }
+        other => panic!("expected NoSocketsBound, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn udp_responds_to_port_offset() {
+    // The client listens for the response on a second socket, offset from the one the packet is sent from.
+    let first = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let second = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (sender, receiver) = match first.local_addr().unwrap().port() < second.local_addr().unwrap().port() {
+        true => (first, second),
+        false => (second, first),
+    };
+    let offset = receiver.local_addr().unwrap().port() - sender.local_addr().unwrap().port();
+
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_response_port_offset = offset;
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    sender.send_to(b"hi", addr).await.unwrap();
+    let mut buf = [0u8; 2048];
+    let (len, _) = tokio::time::timeout(TIMEOUT, receiver.recv_from(&mut buf)).await.unwrap().unwrap();
+    let sender_addr = sender.local_addr().unwrap();
+    assert_eq!(&buf[..len], format!("you: {sender_addr} | bytes: 2 | packet_number: 1").as_bytes());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn udp_jitter_delays_responses_and_records_them_once_sent() {
+    // With a fixed seed, the first response is delayed by the first number the generator gives, scaled to the jitter.
+    let jitter = Duration::from_secs(1);
+    let delay = jitter.mul_f64(Rng::new(1).next_f64());
+    assert!(
+        delay > Duration::from_millis(200),
+        "the seed should give a noticeable delay, not {delay:?}"
+    );
+
+    let path = std::env::temp_dir().join(format!("whereyoufrom-jitter-test-{}.csv", std::process::id()));
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_jitter = Some(jitter);
+    startup_args.random_seed = Some(1);
+    startup_args.csv_log = Some(path.to_str().unwrap().to_string());
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let sent_at = tokio::time::Instant::now();
+    socket.send_to(b"x", addr).await.unwrap();
+
+    // While the response is being held back, the request isn't recorded as served yet.
+    tokio::time::sleep(delay / 2).await;
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+    let mut buf = [0u8; 2048];
+    tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await.unwrap().unwrap();
+    let elapsed = sent_at.elapsed();
+    assert!(
+        elapsed >= delay && elapsed <= jitter + Duration::from_millis(500),
+        "responded after {elapsed:?}"
+    );
+
+    server.stop().await;
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn udp_echoes_the_payload() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.echo = Some(EchoMode::Append);
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (client_addr, response) = read_udp_response(addr, b"hello").await;
+    assert_eq!(response, format!("you: {client_addr} | bytes: 5 | packet_number: 1 | echo: hello"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn udp_once_exits_after_answering() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.once = true;
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (client_addr, response) = read_udp_response(addr, b"x").await;
+    assert_eq!(response, format!("you: {client_addr} | bytes: 1 | packet_number: 1"));
+    server.wait().await;
+}
+
+#[tokio::test]
+async fn udp_once_answers_coalesced_responses_before_exiting() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.once = true;
+    startup_args.udp_coalesce = Some(Duration::from_millis(100));
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (client_addr, response) = read_udp_response(addr, b"x").await;
+    assert_eq!(response, format!("you: {client_addr} | bytes: 1 | packet_number: 1"));
+    server.wait().await;
+}
+
+#[tokio::test]
+async fn udp_once_keeps_running_when_the_response_is_dropped() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.once = true;
+    startup_args.drop_probability = 1.0;
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(b"x", addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!server.handle.is_finished(), "server exited without answering");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn serves_activation_sockets_instead_of_default_addresses() {
+    let mut startup_args = StartupArguments::empty();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    startup_args.activation_fds.push(listener.into_raw_fd());
+    startup_args.tcp_addresses_default = true;
+    startup_args.udp_addresses_default = true;
+    let server = TestServer::start(startup_args);
+
+    let (client_addr, response) = read_tcp_response(addr).await;
+    assert_eq!(response, format!("you: {client_addr} | connection_number: 1"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn unbind_idle_unbinds_unix_sockets() {
+    let path = std::env::temp_dir().join(format!("whereyoufrom-idle-test-{}.sock", std::process::id()));
+    let mut startup_args = StartupArguments::empty();
+    startup_args.unbind_idle = Some(Duration::from_secs(1));
+    startup_args.unix_paths.push(path.clone());
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    // The Unix socket is unbound and its file removed once idle, while the TCP listener is kept busy meanwhile.
+    connect_unix(&path).await;
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    while path.exists() {
+        assert!(tokio::time::Instant::now() < deadline, "Unix socket wasn't unbound");
+        let (client_addr, response) = read_tcp_response(addr).await;
+        assert!(response.starts_with(&format!("you: {client_addr} | ")), "{response}");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn uptime_increases_between_responses() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.tcp_format = ResponseFormat::Template(ResponseTemplate::parse("{uptime}").unwrap());
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (_, first) = read_tcp_response(addr).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (_, second) = read_tcp_response(addr).await;
+
+    let first: f64 = first.parse().unwrap();
+    let second: f64 = second.parse().unwrap();
+    assert!(second >= first + 0.05, "{first} {second}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn responds_with_process_and_worker_ids() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_format = ResponseFormat::Template(ResponseTemplate::parse("{pid} {worker}").unwrap());
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (_, response) = read_udp_response(addr, b"hi").await;
+    let (pid, worker) = response.split_once(' ').unwrap();
+    assert_eq!(pid.parse::<u32>().unwrap(), std::process::id());
+    assert!(worker.parse::<usize>().unwrap() >= 1, "{response}");
+
+    server.stop().await;
+}
+
+/// Finds a free port on 127.0.0.1 for a server to bind itself, for tests that need it to bind an address rather than
+/// inherit a socket.
+fn free_tcp_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// Connects to a TCP address, waiting for the server to bind it.
+async fn connect_tcp(addr: SocketAddr) -> TcpStream {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return stream,
+            Err(error) if tokio::time::Instant::now() >= deadline => panic!("failed to connect to {addr}: {error}"),
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn tcp_addresses_respond_in_their_own_formats() {
+    let json_addr = free_tcp_address();
+    let http_addr = free_tcp_address();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.tcp_addresses = vec![json_addr, http_addr];
+    startup_args
+        .tcp_address_formats
+        .insert(json_addr, AddressFormat::Format(ResponseFormat::Json));
+    startup_args.tcp_address_formats.insert(http_addr, AddressFormat::Http);
+    let text_addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    // The addresses are all bound together, so once one accepts connections the others do too.
+    let mut stream = connect_tcp(json_addr).await;
+    let client_addr = stream.local_addr().unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    let value: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(value["address"], client_addr.to_string());
+
+    let mut stream = TcpStream::connect(http_addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap();
+    let (head, body) = read_http_response(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+    assert_eq!(body, format!("you: {client_addr} | connection_number: 1"));
+
+    let (client_addr, response) = read_tcp_response(text_addr).await;
+    assert_eq!(response, format!("you: {client_addr} | connection_number: 1"));
+
+    server.stop().await;
+}
+
+/// A provider that responds with a canned response naming the protocol and counter.
+struct CannedProvider;
+
+impl ResponseProvider for CannedProvider {
+    fn build(&self, context: &RequestContext) -> Vec<u8> {
+        format!("canned {} #{}", context.protocol, context.counter).into_bytes()
+    }
+}
+
+#[tokio::test]
+async fn provider_builds_the_responses() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.tcp_format = ResponseFormat::Json;
+    let tcp_addr = bind_tcp(&mut startup_args);
+    let udp_addr = bind_udp(&mut startup_args);
+    let server = TestServer::start_with_provider(startup_args, Arc::new(CannedProvider));
+
+    let (_, response) = read_tcp_response(tcp_addr).await;
+    assert_eq!(response, "canned tcp #1");
+    let (_, response) = read_udp_response(udp_addr, b"hi").await;
+    assert_eq!(response, "canned udp #1");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn http_headers_tell_the_client_its_address() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.http = true;
+    startup_args.http_headers = true;
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap();
+    let (head, _) = read_http_response(&mut stream).await;
+    assert!(head.contains("\r\nX-Client-IP: 127.0.0.1\r\n"), "{head}");
+    assert!(head.contains(&format!("\r\nX-Client-Port: {}\r\n", client_addr.port())), "{head}");
+    assert!(head.contains("\r\nX-Real-IP: 127.0.0.1\r\n"), "{head}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn http_reflects_the_user_agent() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.http = true;
+    startup_args.tcp_format = ResponseFormat::Template(ResponseTemplate::parse("[{user_agent}]").unwrap());
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nUser-Agent: test-client/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    let (_, body) = read_http_response(&mut stream).await;
+    assert_eq!(body, "[test-client/1.0]");
+    stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap();
+    let (_, body) = read_http_response(&mut stream).await;
+    assert_eq!(body, "[]");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn http_chunked_sends_a_well_formed_chunked_body() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.http = true;
+    startup_args.http_chunked = true;
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let (head, mut chunked) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"), "{head}");
+    let mut body = String::new();
+    loop {
+        let (size, rest) = chunked.split_once("\r\n").unwrap();
+        let size = usize::from_str_radix(size, 16).unwrap();
+        body.push_str(&rest[..size]);
+        assert_eq!(&rest[size..(size + 2)], "\r\n");
+        chunked = &rest[(size + 2)..];
+        if size == 0 {
+            break;
+        }
+    }
+    assert!(chunked.is_empty(), "{chunked:?}");
+    assert_eq!(body, format!("you: {client_addr} | connection_number: 1"));
+
+    server.stop().await;
+}
+
+/// Opens a length-framed connection and exchanges a request on it. The connection then stays open until the client
+/// closes it, holding one of the `--max-connections` permits.
+async fn hold_length_framed_connection(addr: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_u32(0).await.unwrap();
+    let len = tokio::time::timeout(TIMEOUT, stream.read_u32()).await.unwrap().unwrap();
+    let mut response = vec![0; len as usize];
+    stream.read_exact(&mut response).await.unwrap();
+    stream
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_rejected_or_queued() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.length_framed = true;
+    startup_args.max_connections = Some(2.try_into().unwrap());
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let held = [hold_length_framed_connection(addr).await, hold_length_framed_connection(addr).await];
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut response = Vec::new();
+    let result = tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut response)).await.unwrap();
+    assert!(result.is_err() || response.is_empty(), "{response:?}");
+    drop(held);
+    server.stop().await;
+
+    let mut startup_args = StartupArguments::empty();
+    startup_args.length_framed = true;
+    startup_args.max_connections = Some(1.try_into().unwrap());
+    startup_args.connection_limit_policy = ConnectionLimitPolicy::Queue;
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let held = hold_length_framed_connection(addr).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_u32(0).await.unwrap();
+    let result = tokio::time::timeout(Duration::from_millis(200), stream.read_u32()).await;
+    assert!(result.is_err(), "queued connection was served while the limit was reached");
+
+    drop(held);
+    let len = tokio::time::timeout(TIMEOUT, stream.read_u32()).await.unwrap().unwrap();
+    let mut response = vec![0; len as usize];
+    stream.read_exact(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.ends_with("| connection_number: 2"), "{response}");
+
+    drop(stream);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_sent_the_full_message() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.length_framed = true;
+    startup_args.max_connections = Some(1.try_into().unwrap());
+    startup_args.full_message = Some("busy, try again later".into());
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let first = hold_length_framed_connection(addr).await;
+
+    let (_, response) = read_tcp_response(addr).await;
+    assert_eq!(response, "busy, try again later");
+
+    drop(first);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn checksums_match_the_response() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.checksum = Some(ChecksumType::Crc32);
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (_, response) = read_tcp_response(addr).await;
+    let (message, checksum) = response.rsplit_once(" | checksum: ").unwrap();
+    assert_eq!(checksum, format!("{:08x}", crc32fast::hash(message.as_bytes())));
+    server.stop().await;
+
+    let mut startup_args = StartupArguments::empty();
+    startup_args.checksum = Some(ChecksumType::Sha256);
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (_, response) = read_udp_response(addr, b"hello").await;
+    let (message, checksum) = response.rsplit_once(" | checksum: ").unwrap();
+    let expected: String = sha2::Sha256::digest(message.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    assert_eq!(checksum, expected);
+
+    server.stop().await;
+}
+
+/// Sends each payload in turn from the same socket, returning the responses received until none arrives for a while.
+async fn exchange_udp(addr: SocketAddr, payloads: &[&[u8]]) -> Vec<String> {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for payload in payloads {
+        socket.send_to(payload, addr).await.unwrap();
+    }
+
+    let mut responses = Vec::new();
+    let mut buf = [0u8; 2048];
+    while let Ok(result) = tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await {
+        let (len, _) = result.unwrap();
+        responses.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+    }
+    responses
+}
+
+#[tokio::test]
+async fn empty_udp_packets_follow_the_policy() {
+    let mut startup_args = StartupArguments::empty();
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let responses = exchange_udp(addr, &[b"", b"x"]).await;
+    assert_eq!(responses.len(), 2, "{responses:?}");
+    assert!(responses[0].contains("| bytes: 0 |"), "{responses:?}");
+    server.stop().await;
+
+    let mut startup_args = StartupArguments::empty();
+    startup_args.empty_udp = EmptyUdpPolicy::Drop;
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let responses = exchange_udp(addr, &[b"", b"x"]).await;
+    assert_eq!(responses.len(), 1, "{responses:?}");
+    assert!(responses[0].contains("| bytes: 1 |"), "{responses:?}");
+    server.stop().await;
+}
+
+/// Loopback broadcasts to 127.255.255.255 reach sockets bound to 0.0.0.0 on Linux.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn udp_broadcast_packets_are_answered() {
+    let port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_broadcast = true;
+    startup_args.udp_addresses.push(SocketAddr::from(([0, 0, 0, 0], port)));
+    let server = TestServer::start(startup_args);
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.set_broadcast(true).unwrap();
+    let client_addr = socket.local_addr().unwrap();
+    let mut buf = [0u8; 2048];
+
+    // The server binds its socket in the background, so keep broadcasting until it answers.
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    let response = loop {
+        assert!(tokio::time::Instant::now() < deadline, "timed out waiting for a broadcast response");
+        socket.send_to(b"discover", ("127.255.255.255", port)).await.unwrap();
+        if let Ok(result) = tokio::time::timeout(Duration::from_millis(100), socket.recv_from(&mut buf)).await {
+            let (len, _) = result.unwrap();
+            break String::from_utf8_lossy(&buf[..len]).into_owned();
+        }
+    };
+    assert!(response.starts_with(&format!("you: {client_addr} | bytes: 8")), "{response}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn unbind_idle_unbinds_only_the_idle_listener() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.unbind_idle = Some(Duration::from_secs(1));
+    let busy = bind_tcp(&mut startup_args);
+    let idle = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    // Connecting to the idle listener would count as activity, so it's only tried once it should have been unbound.
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(1500);
+    while tokio::time::Instant::now() < deadline {
+        let (client_addr, response) = read_tcp_response(busy).await;
+        assert!(response.starts_with(&format!("you: {client_addr} | ")), "{response}");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let error = TcpStream::connect(idle).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+    let (client_addr, response) = read_tcp_response(busy).await;
+    assert!(response.starts_with(&format!("you: {client_addr} | ")), "{response}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn stats_are_logged_every_interval() {
+    let (logs, _guard) = CapturedLogs::start();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.stats_interval = Some(Duration::from_millis(100));
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    read_udp_response(addr, b"hello").await;
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    server.stop().await;
+
+    let logs = logs.contents();
+    let stats: Vec<&str> = logs.lines().filter(|line| line.contains("Stats: ")).collect();
+    assert!(!stats.is_empty(), "{logs}");
+    assert!(stats.last().unwrap().contains("Stats: 0 TCP connections, 1 UDP packets"), "{logs}");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn tcp_info_reports_the_rtt() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.tcp_info = true;
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (_, response) = read_tcp_response(addr).await;
+    let rtt = response.split(" | rtt: ").nth(1).and_then(|rest| rest.split("us").next()).unwrap();
+    rtt.parse::<u32>().unwrap();
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn udp_responses_rotate_between_response_ports() {
+    let response_ports: Vec<u16> = (0..2)
+        .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port())
+        .collect();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_response_ports = response_ports.clone();
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut buf = [0u8; 2048];
+    let mut source_ports = Vec::new();
+    for _ in 0..4 {
+        socket.send_to(b"hello", addr).await.unwrap();
+        let (_, source) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await.unwrap().unwrap();
+        source_ports.push(source.port());
+    }
+    assert_eq!(source_ports, [addr.port(), response_ports[0], response_ports[1], addr.port()]);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn roundtrip_logs_the_time_until_the_client_echoes() {
+    let (logs, _guard) = CapturedLogs::start();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.roundtrip = true;
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    // The connection is kept open until the client echoes the response back.
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut response = vec![0u8; 2048];
+    let len = tokio::time::timeout(TIMEOUT, stream.read(&mut response)).await.unwrap().unwrap();
+    stream.write_all(&response[..len]).await.unwrap();
+    let mut rest = Vec::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut rest)).await.unwrap().unwrap();
+    server.stop().await;
+
+    let logs = logs.contents();
+    assert!(
+        logs.contains(&format!("TCP socket {addr} measured a round-trip time of ")),
+        "{logs}"
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn report_mss_includes_the_mss() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.report_mss = true;
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (_, response) = read_tcp_response(addr).await;
+    let mss = response.split(" | mss: ").nth(1).and_then(|rest| rest.split(' ').next()).unwrap();
+    assert!(mss.parse::<u32>().unwrap() > 0, "{response}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn prespawned_tasks_serve_connections() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.prespawn = Some(2);
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    // More connections at once than there are idle tasks, so some are handed off and others served as usual.
+    let mut clients = tokio::task::JoinSet::new();
+    for _ in 0..5 {
+        clients.spawn(read_tcp_response(addr));
+    }
+    let mut counters = Vec::new();
+    while let Some(result) = clients.join_next().await {
+        let (client_addr, response) = result.unwrap();
+        let counter = response.strip_prefix(&format!("you: {client_addr} | connection_number: ")).unwrap();
+        counters.push(counter.parse::<u64>().unwrap());
+    }
+    counters.sort_unstable();
+    assert_eq!(counters, [1, 2, 3, 4, 5]);
+
+    let (client_addr, response) = read_tcp_response(addr).await;
+    assert_eq!(response, format!("you: {client_addr} | connection_number: 6"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn split_responses_arrive_intact() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.split_response = Some(4.try_into().unwrap());
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    // The kernel may still coalesce the segments, so only the content is checked and not how it was split up.
+    let (client_addr, response) = read_tcp_response(addr).await;
+    assert_eq!(response, format!("you: {client_addr} | connection_number: 1"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn byte_formats_decode_back_to_the_client_address() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_format = ResponseFormat::IpBytes;
+    startup_args.tcp_format = ResponseFormat::IpPortBytes;
+    let udp_addr = bind_udp(&mut startup_args);
+    let tcp_addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(b"hi", udp_addr).await.unwrap();
+    let mut buf = [0u8; 64];
+    let (len, _) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await.unwrap().unwrap();
+    let octets: [u8; 4] = buf[..len].try_into().unwrap();
+    assert_eq!(IpAddr::from(octets), socket.local_addr().unwrap().ip());
+
+    let mut stream = TcpStream::connect(tcp_addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    let (ip, port) = response.split_at(4);
+    let octets: [u8; 4] = ip.try_into().unwrap();
+    let port = u16::from_be_bytes(port.try_into().unwrap());
+    assert_eq!(SocketAddr::new(IpAddr::from(octets), port), client_addr);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn udp_fresh_source_responds_from_distinct_ports() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_fresh_source = true;
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut buf = [0u8; 2048];
+    let mut sources = Vec::new();
+    for _ in 0..3 {
+        socket.send_to(b"hello", addr).await.unwrap();
+        let (len, source) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&buf[..len]).starts_with("you: "));
+        assert_eq!(source.ip(), addr.ip());
+        assert_ne!(source.port(), addr.port());
+        sources.push(source.port());
+    }
+    sources.sort_unstable();
+    sources.dedup();
+    assert_eq!(sources.len(), 3, "responses were sent from the same port: {sources:?}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn tcp_responses_end_at_the_message() {
+    let mut startup_args = StartupArguments::empty();
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!response.contains(&0), "{response:?}");
+    assert_eq!(response, format!("you: {client_addr} | connection_number: 1").into_bytes());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn failed_accepts_do_not_use_up_counter_values() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.inject_errors = 3;
+    let tcp_addr = bind_tcp(&mut startup_args);
+    let udp_addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    for counter in 1..=2 {
+        let (client_addr, response) = read_tcp_response(tcp_addr).await;
+        assert_eq!(response, format!("you: {client_addr} | connection_number: {counter}"));
+    }
+
+    let responses = exchange_udp(udp_addr, &[b"x", b"y"]).await;
+    assert!(responses[0].ends_with("| bytes: 1 | packet_number: 1"), "{responses:?}");
+    assert!(responses[1].ends_with("| bytes: 1 | packet_number: 2"), "{responses:?}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn udp_log_batch_logs_a_summary_per_batch() {
+    let (logs, _guard) = CapturedLogs::start();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_log_batch = Some(3.try_into().unwrap());
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let responses = exchange_udp(addr, &[b"a", b"b", b"c", b"d", b"e", b"f"]).await;
+    assert_eq!(responses.len(), 6);
+    server.stop().await;
+
+    let logs = logs.contents();
+    let summary = format!("UDP socket {addr} received 3 packets totalling 3 bytes from 1 distinct sources");
+    assert_eq!(logs.matches(&summary).count(), 2, "{logs}");
+    assert!(!logs.contains(&format!("UDP socket {addr} received 1 bytes")), "{logs}");
+}
+
+#[tokio::test]
+async fn delay_close_holds_the_connection_open_after_the_response() {
+    let delay = Duration::from_millis(300);
+    let mut startup_args = StartupArguments::empty();
+    startup_args.delay_close = Some(delay);
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let expected = format!("you: {} | connection_number: 1", stream.local_addr().unwrap());
+    let mut response = vec![0u8; expected.len()];
+    tokio::time::timeout(TIMEOUT, stream.read_exact(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    let received_at = tokio::time::Instant::now();
+    assert_eq!(response, expected.as_bytes());
+
+    // The delay starts once the response is written, a moment before it is received, so allow for some slack.
+    let len = tokio::time::timeout(TIMEOUT, stream.read(&mut [0u8; 16])).await.unwrap().unwrap();
+    assert_eq!(len, 0);
+    assert!(
+        received_at.elapsed() >= delay - Duration::from_millis(20),
+        "{:?}",
+        received_at.elapsed()
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn v6only_listeners_refuse_ipv4_clients() {
+    let port = free_tcp_address().port();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.v6only = true;
+    startup_args.tcp_addresses = vec![SocketAddr::new("::".parse().unwrap(), port)];
+    let server = TestServer::start(startup_args);
+
+    let mut stream = connect_tcp(SocketAddr::new("::1".parse().unwrap(), port)).await;
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(response.starts_with("you: [::1]:"), "{response}");
+
+    let error = TcpStream::connect(SocketAddr::new("127.0.0.1".parse().unwrap(), port))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn udp_require_size_answers_only_datagrams_of_that_size() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_require_size = Some(4);
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let responses = exchange_udp(addr, &[b"abc", b"abcd", b"abcde"]).await;
+    assert_eq!(responses.len(), 1, "{responses:?}");
+    assert!(responses[0].contains("| bytes: 4 |"), "{responses:?}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn connection_events_carry_the_remote_address() {
+    let (logs, _guard) = CapturedLogs::start();
+    let mut startup_args = StartupArguments::empty();
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (client_addr, _) = read_tcp_response(addr).await;
+    server.stop().await;
+
+    let logs = logs.contents();
+    let line = logs
+        .lines()
+        .find(|line| line.contains(&format!("TCP socket {addr} responded to {client_addr}")))
+        .unwrap_or_else(|| panic!("{logs}"));
+    assert!(line.contains(" DEBUG "), "{line}");
+    assert!(line.contains(&format!("tcp_connection{{remote_address={client_addr}}}")), "{line}");
+}
+
+/// Scrapes the metrics endpoint at the given address, waiting for the server to bind it, and returns the body.
+async fn scrape_metrics(addr: SocketAddr) -> String {
+    let mut stream = connect_tcp(addr).await;
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let (head, body) = read_http_response(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+    body
+}
+
+#[tokio::test]
+async fn metrics_count_requests() {
+    let metrics_addr = free_tcp_address();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.metrics_address = Some(metrics_addr);
+    let tcp_addr = bind_tcp(&mut startup_args);
+    let udp_addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let metrics = scrape_metrics(metrics_addr).await;
+    assert!(metrics.contains("\nwhereyoufrom_tcp_connections_total 0\n"), "{metrics}");
+    assert!(metrics.contains("\nwhereyoufrom_udp_packets_total 0\n"), "{metrics}");
+
+    read_tcp_response(tcp_addr).await;
+    read_udp_response(udp_addr, b"hello").await;
+    read_udp_response(udp_addr, b"hello").await;
+
+    let metrics = scrape_metrics(metrics_addr).await;
+    assert!(metrics.contains("\nwhereyoufrom_tcp_connections_total 1\n"), "{metrics}");
+    assert!(metrics.contains("\nwhereyoufrom_udp_packets_total 2\n"), "{metrics}");
+    assert!(metrics.contains("\nwhereyoufrom_errors_total 0\n"), "{metrics}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn metrics_label_responses_by_protocol_and_format() {
+    let metrics_addr = free_tcp_address();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.metrics_address = Some(metrics_addr);
+    startup_args.tcp_format = ResponseFormat::Json;
+    let tcp_addr = bind_tcp(&mut startup_args);
+    let udp_addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    read_tcp_response(tcp_addr).await;
+    exchange_udp(udp_addr, &[b"a", b"b"]).await;
+
+    // Responses are counted once sent, which may be a moment after the client receives them.
+    let expected = [
+        "whereyoufrom_responses_total{proto=\"tcp\",format=\"json\"} 1",
+        "whereyoufrom_responses_total{proto=\"udp\",format=\"text\"} 2",
+    ];
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    loop {
+        let metrics = scrape_metrics(metrics_addr).await;
+        let series: Vec<&str> = metrics
+            .lines()
+            .filter(|line| line.starts_with("whereyoufrom_responses_total{"))
+            .collect();
+        if series == expected {
+            break;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{metrics}");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn udp_coalesce_answers_a_burst_once() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_coalesce = Some(Duration::from_millis(200));
+    let addr = bind_udp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for payload in [&b"a"[..], b"bb", b"ccc", b"dddd", b"eeeee"] {
+        socket.send_to(payload, addr).await.unwrap();
+    }
+
+    let mut buf = [0u8; 2048];
+    let (len, _) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await.unwrap().unwrap();
+    let client_addr = socket.local_addr().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&buf[..len]),
+        format!("you: {client_addr} | bytes: 5 | packet_number: 5")
+    );
+
+    let result = tokio::time::timeout(Duration::from_millis(300), socket.recv_from(&mut buf)).await;
+    assert!(result.is_err(), "the burst was answered more than once");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn length_framed_connections_answer_each_request() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.length_framed = true;
+    startup_args.max_request_size = 8;
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let expected = format!("you: {} | connection_number: 1", stream.local_addr().unwrap());
+    for request in [&b"hello"[..], b"world!"] {
+        stream.write_u32(request.len() as u32).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let len = tokio::time::timeout(TIMEOUT, stream.read_u32()).await.unwrap().unwrap();
+        let mut response = vec![0; len as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), expected);
+    }
+
+    // A request over --max-request-size closes the connection instead.
+    stream.write_u32(9).await.unwrap();
+    stream.write_all(b"too long!").await.unwrap();
+    let mut rest = Vec::new();
+    let result = tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut rest)).await.unwrap();
+    assert!(result.is_err() || rest.is_empty(), "{rest:?}");
+
+    server.stop().await;
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn udp_shards_answer_every_datagram() {
+    let (logs, _guard) = CapturedLogs::start();
+    let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut startup_args = StartupArguments::empty();
+    startup_args.udp_addresses = vec![addr];
+    startup_args.udp_shards = 2.try_into().unwrap();
+    let server = TestServer::start(startup_args);
+
+    // Each datagram comes from its own port, so the kernel may hand them to either shard.
+    let mut packet_numbers = Vec::new();
+    for _ in 0..8 {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 2048];
+        let response = loop {
+            socket.send_to(b"hello", addr).await.unwrap();
+            match tokio::time::timeout(Duration::from_millis(100), socket.recv_from(&mut buf)).await {
+                Ok(result) => break String::from_utf8_lossy(&buf[..result.unwrap().0]).into_owned(),
+                Err(_) => assert!(tokio::time::Instant::now() < deadline, "timed out waiting for a UDP response"),
+            }
+        };
+        let client_addr = socket.local_addr().unwrap();
+        let packet_number = response
+            .strip_prefix(&format!("you: {client_addr} | bytes: 5 | packet_number: "))
+            .unwrap();
+        packet_numbers.push(packet_number.parse::<usize>().unwrap());
+    }
+    server.stop().await;
+
+    let logs = logs.contents();
+    assert!(logs.contains(&format!("Successfully bound UDP shard 1 at {addr}")), "{logs}");
+
+    // Each shard counts its own packets, so the numbers are 1 up to however many packets each shard got.
+    let count = |n: usize| packet_numbers.iter().filter(|&&number| number == n).count();
+    assert!((1..=2).contains(&count(1)), "{packet_numbers:?}");
+    assert!((2..=8).all(|n| count(n) <= count(n - 1)), "{packet_numbers:?}");
+}
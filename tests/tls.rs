@@ -0,0 +1,196 @@
+//! Runs the server over TLS with the test identity in tests/data, a certificate for localhost and 127.0.0.1 signed by
+//! the CA in ca.pem. The key and certificates are also bundled in identity.p12, encrypted with the password
+//! "whereyoufrom".
+#![cfg(all(unix, feature = "tls"))]
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    os::fd::IntoRawFd,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{io::AsyncReadExt, net::TcpStream, sync::oneshot, task::JoinHandle};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        version, ClientConfig, RootCertStore, SupportedProtocolVersion,
+    },
+    TlsConnector,
+};
+use whereyoufrom::{
+    args::{ResponseFormat, TlsVersion},
+    run_server,
+    template::ResponseTemplate,
+    ServerError, StartupArguments,
+};
+
+/// How long to wait for a response before failing a test.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+/// A server running in the background until it's told to shut down.
+struct TestServer {
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<Result<(), ServerError>>,
+}
+
+impl TestServer {
+    fn start(startup_args: StartupArguments) -> Self {
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let handle = tokio::spawn(run_server(startup_args, async {
+            let _ = shutdown_receiver.await;
+        }));
+
+        TestServer { shutdown, handle }
+    }
+
+    /// Shuts the server down, waiting for it to finish.
+    async fn stop(self) {
+        let _ = self.shutdown.send(());
+        let result = tokio::time::timeout(TIMEOUT, self.handle).await.expect("server didn't shut down");
+        result.unwrap().unwrap();
+    }
+}
+
+/// Binds a TCP listener to 127.0.0.1:0 for the server to serve, returning its address.
+fn bind_tcp(startup_args: &mut StartupArguments) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    startup_args.tcp_fds.push(listener.into_raw_fd());
+    addr
+}
+
+/// Startup arguments serving TLS with the identity in identity.p12.
+fn pkcs12_args() -> StartupArguments {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.tls_pkcs12 = Some(format!("{DATA}/identity.p12"));
+    startup_args.tls_pkcs12_password = Some("whereyoufrom".into());
+    startup_args
+}
+
+/// A client configuration trusting only the test CA.
+fn client_config() -> ClientConfig {
+    client_config_with_versions(&[&version::TLS13, &version::TLS12])
+}
+
+/// A client configuration trusting only the test CA, that only supports the given TLS versions.
+fn client_config_with_versions(versions: &[&'static SupportedProtocolVersion]) -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(format!("{DATA}/ca.pem")).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+
+    ClientConfig::builder_with_protocol_versions(versions)
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+async fn connect_tls(addr: SocketAddr, config: ClientConfig) -> std::io::Result<TlsStream<TcpStream>> {
+    let stream = TcpStream::connect(addr).await?;
+    let connector = TlsConnector::from(Arc::new(config));
+    tokio::time::timeout(TIMEOUT, connector.connect(ServerName::try_from("localhost").unwrap(), stream))
+        .await
+        .expect("timed out waiting for the TLS handshake")
+}
+
+async fn read_tls_response(addr: SocketAddr, config: ClientConfig) -> (SocketAddr, String) {
+    let mut stream = connect_tls(addr, config).await.unwrap();
+    let local_addr = stream.get_ref().0.local_addr().unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .expect("timed out waiting for a TLS response")
+        .unwrap();
+    (local_addr, response)
+}
+
+#[tokio::test]
+async fn pem_identity_serves_decrypted_responses() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.tls_cert = Some(format!("{DATA}/cert.pem"));
+    startup_args.tls_key = Some(format!("{DATA}/key.pem"));
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    for counter in 1..=2 {
+        let (client_addr, response) = read_tls_response(addr, client_config()).await;
+        assert_eq!(response, format!("you: {client_addr} | connection_number: {counter}"));
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn missing_key_fails_at_startup() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.tls_cert = Some(format!("{DATA}/cert.pem"));
+    startup_args.tls_key = Some(format!("{DATA}/missing.pem"));
+    bind_tcp(&mut startup_args);
+
+    let result = run_server(startup_args, std::future::pending()).await;
+    assert!(matches!(result, Err(ServerError::Tls(error)) if error.contains("missing.pem")));
+}
+
+#[tokio::test]
+async fn pkcs12_identity_completes_a_handshake() {
+    let mut startup_args = pkcs12_args();
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (client_addr, response) = read_tls_response(addr, client_config()).await;
+    assert_eq!(response, format!("you: {client_addr} | connection_number: 1"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn wrong_pkcs12_password_fails_at_startup() {
+    let mut startup_args = StartupArguments::empty();
+    startup_args.tls_pkcs12 = Some(format!("{DATA}/identity.p12"));
+    startup_args.tls_pkcs12_password = Some("nope".into());
+    bind_tcp(&mut startup_args);
+
+    let result = run_server(startup_args, std::future::pending()).await;
+    assert!(matches!(result, Err(ServerError::Tls(error)) if error.ends_with("wrong password")));
+}
+
+#[tokio::test]
+async fn responds_with_the_negotiated_parameters() {
+    let mut startup_args = pkcs12_args();
+    startup_args.tls_cipher_suites = vec!["TLS13_CHACHA20_POLY1305_SHA256".into()];
+    startup_args.tcp_format = ResponseFormat::Template(ResponseTemplate::parse("{tls_version} {cipher}").unwrap());
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (_, response) = read_tls_response(addr, client_config()).await;
+    assert_eq!(response, "TLSv1.3 TLS13_CHACHA20_POLY1305_SHA256");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn min_tls_version_rejects_older_clients() {
+    let mut startup_args = pkcs12_args();
+    startup_args.tcp_format = ResponseFormat::Template(ResponseTemplate::parse("{tls_version}").unwrap());
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    let (_, response) = read_tls_response(addr, client_config_with_versions(&[&version::TLS12])).await;
+    assert_eq!(response, "TLSv1.2");
+    server.stop().await;
+
+    let mut startup_args = pkcs12_args();
+    startup_args.tcp_format = ResponseFormat::Template(ResponseTemplate::parse("{tls_version}").unwrap());
+    startup_args.min_tls_version = Some(TlsVersion::Tls13);
+    let addr = bind_tcp(&mut startup_args);
+    let server = TestServer::start(startup_args);
+
+    assert!(connect_tls(addr, client_config_with_versions(&[&version::TLS12])).await.is_err());
+    let (_, response) = read_tls_response(addr, client_config()).await;
+    assert_eq!(response, "TLSv1.3");
+
+    server.stop().await;
+}